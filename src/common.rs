@@ -1,4 +1,26 @@
+#[path = "common/cache.rs"]
+pub mod cache;
+#[path = "common/config.rs"]
+pub mod config;
+#[path = "common/detail.rs"]
+pub mod detail;
+#[path = "common/ignore_rules.rs"]
+pub mod ignore_rules;
+#[path = "common/links.rs"]
+pub mod links;
+#[path = "common/markdown.rs"]
+pub mod markdown;
+#[path = "common/metadata.rs"]
+pub mod metadata;
 #[path = "common/node.rs"]
 pub mod node;
+#[path = "common/parser.rs"]
+pub mod parser;
+#[path = "common/selector.rs"]
+pub mod selector;
+#[path = "common/traverse.rs"]
+pub mod traverse;
 #[path = "common/tree.rs"]
 pub mod tree;
+#[path = "common/vpath.rs"]
+pub mod vpath;
@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::common::parser::ParserDescription;
+use crate::common::tree::Tree;
+
+/// What a document looked like when it was parsed. A cached tree is only
+/// used while the file still matches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub modified: SystemTime,
+    pub size: u64,
+    /// The BLAKE3 hash of the file's contents, in hex.
+    pub hash: String,
+}
+
+impl Fingerprint {
+    pub fn of(path: &str) -> io::Result<Fingerprint> {
+        let metadata = fs::metadata(path)?;
+        let contents = fs::read(path)?;
+        return Ok(
+            Fingerprint {
+                modified: metadata.modified()?,
+                size: metadata.len(),
+                hash: blake3::hash(&contents).to_hex().to_string(),
+            },
+        );
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    /// The parser which produced the tree, see `parser_key`.
+    parser: String,
+    fingerprint: Fingerprint,
+    tree: Tree,
+}
+
+/// Parsed documents stored on disk between runs, so hydrating a tree only
+/// reparses the documents which changed. There's one file per document,
+/// named after the hash of its canonical path.
+pub struct ParseCache {
+    pub dir: PathBuf,
+    parser: String,
+}
+
+impl ParseCache {
+    /// A cache for trees produced by `parser` when run with `args`. Entries
+    /// written by any other parser, another version of it or with other
+    /// arguments are ignored and overwritten.
+    pub fn new(dir: PathBuf, parser: &ParserDescription, args: &[String]) -> ParseCache {
+        return ParseCache {
+            dir,
+            parser: Self::parser_key(
+                parser, args,
+            ),
+        };
+    }
+
+    /// `$XDG_CACHE_HOME/zenkat` or the platform's equivalent.
+    pub fn default_dir() -> Option<PathBuf> {
+        return dirs::cache_dir().map(|dir| dir.join("zenkat"));
+    }
+
+    fn parser_key(parser: &ParserDescription, args: &[String]) -> String {
+        let key = format!(
+            "{} {} (protocol {})",
+            parser.name, parser.version, parser.protocol_version
+        );
+        if args.is_empty() {
+            // so entries from before parsers took arguments stay valid
+            return key;
+        }
+        return format!(
+            "{} {}",
+            key,
+            args.join(" ")
+        );
+    }
+
+    fn entry_path(&self, path: &str) -> PathBuf {
+        let canonical = fs::canonicalize(path).unwrap_or(PathBuf::from(path));
+        let key = blake3::hash(canonical.to_string_lossy().as_bytes());
+        return self.dir.join(format!(
+            "{}.json",
+            key.to_hex()
+        ));
+    }
+
+    /// Returns the cached tree for the document at `path` if it was parsed
+    /// by the same parser and hasn't changed since. The size and mtime are
+    /// checked first; the contents are only hashed when the mtime differs,
+    /// e.g. after a checkout.
+    pub fn get(&self, path: &str) -> Option<Tree> {
+        let entry_path = self.entry_path(path);
+        let raw = fs::read(&entry_path).ok()?;
+        let mut entry: CacheEntry = serde_json::from_slice(&raw).ok()?;
+        if entry.parser != self.parser {
+            return None;
+        }
+        let metadata = fs::metadata(path).ok()?;
+        if metadata.len() != entry.fingerprint.size {
+            return None;
+        }
+        if metadata.modified().ok()? == entry.fingerprint.modified {
+            return Some(entry.tree);
+        }
+        let fingerprint = Fingerprint::of(path).ok()?;
+        if fingerprint.hash != entry.fingerprint.hash {
+            return None;
+        }
+        // remember the new mtime so the next lookup doesn't hash again
+        entry.fingerprint = fingerprint;
+        if let Err(e) = self.write(
+            &entry_path,
+            &entry,
+        ) {
+            eprintln!(
+                "Failed to update cache entry for {}: {}",
+                path, e
+            );
+        }
+        return Some(entry.tree);
+    }
+
+    /// Stores the tree parsed from the document at `path`, which looked
+    /// like `fingerprint` before it was parsed.
+    pub fn put(&self, path: &str, fingerprint: Fingerprint, tree: Tree) {
+        let entry = CacheEntry {
+            parser: self.parser.clone(),
+            fingerprint,
+            tree,
+        };
+        if let Err(e) = self.write(
+            &self.entry_path(path),
+            &entry,
+        ) {
+            eprintln!(
+                "Failed to cache {}: {}",
+                path, e
+            );
+        }
+    }
+
+    fn write(&self, entry_path: &Path, entry: &CacheEntry) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // write then rename, so a crash never leaves half an entry behind
+        let temp_path = entry_path.with_extension(format!(
+            "{}.tmp",
+            std::process::id()
+        ));
+        fs::write(
+            &temp_path,
+            serde_json::to_vec(entry)?,
+        )?;
+        return fs::rename(
+            temp_path, entry_path,
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Settings shared by `zenkat` and `zk-cmd`, read from a TOML file. Every
+/// field is optional, and command line flags take precedence over them.
+/// See docs/configuration.md for an example.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// `builtin`, or the parser executable to run for trees without their own.
+    pub parser: Option<String>,
+    /// Passed to the parser before the protocol's own arguments.
+    pub parser_args: Vec<String>,
+    /// The Markdown dialect documents are written in. The parser must list
+    /// it among the `dialects` it reports from `--describe`.
+    pub dialect: Option<String>,
+    pub processes: Option<usize>,
+    /// Seconds a parser may spend on one document before it's killed.
+    pub parse_timeout: Option<u64>,
+    pub cache_dir: Option<PathBuf>,
+    pub no_cache: bool,
+    pub snapshot: Option<PathBuf>,
+    pub follow_symlinks: bool,
+    pub document_extensions: Option<Vec<String>>,
+    pub ignore: IgnoreConfig,
+    pub server: ServerConfig,
+    #[serde(rename = "tree")]
+    pub trees: Vec<TreeConfig>,
+}
+
+/// The `[ignore]` table, applying to every tree.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IgnoreConfig {
+    pub hidden: bool,
+    pub no_ignore_files: bool,
+    /// Patterns in `.gitignore` syntax, relative to each tree's root.
+    pub exclude: Vec<String>,
+}
+
+/// The `[server]` table: where the server listens, and so where clients
+/// connect to.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub interface: Option<String>,
+    pub port: Option<u16>,
+    /// Listen on a Unix socket at this path instead of over TCP, unless
+    /// an interface or port is given as a flag. Read on every platform so
+    /// that the error where there are no Unix sockets is a clear one.
+    pub socket: Option<PathBuf>,
+    /// The permissions of the socket, e.g. `0o660` to let the group in too.
+    pub socket_mode: Option<u32>,
+}
+
+/// A `[[tree]]` entry. Its settings take precedence over the global ones.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TreeConfig {
+    pub name: String,
+    pub path: PathBuf,
+    pub parser: Option<String>,
+    pub parser_args: Option<Vec<String>>,
+    pub dialect: Option<String>,
+    /// Added to the global `[ignore]` patterns for this tree only.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/zenkat/config.toml` or the platform's equivalent.
+    pub fn default_path() -> Option<PathBuf> {
+        return dirs::config_dir().map(|dir| dir.join("zenkat").join("config.toml"));
+    }
+
+    /// Reads the config at `path`, or at `default_path` if there's none.
+    /// A missing default config is the same as an empty one, but a
+    /// missing `path` is an error since it was asked for.
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Self::default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+        let raw = fs::read_to_string(&path).map_err(|e| {
+            format!(
+                "Failed to read {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        let mut config: Config = toml::from_str(&raw).map_err(|e| {
+            format!(
+                "Invalid config {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        config.validate().map_err(|e| {
+            format!(
+                "Invalid config {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        config.resolve_paths(
+            path.parent().unwrap_or(Path::new(
+                ".",
+            )),
+        );
+        return Ok(config);
+    }
+
+    /// Catches settings which parse but can't mean what they seem to.
+    fn validate(&self) -> Result<(), String> {
+        for tree in self.trees.iter() {
+            // they'd otherwise be passed to the global parser, which may
+            // not take them
+            if tree.parser_args.is_some() && tree.parser.is_none() {
+                return Err(format!(
+                    "tree {} has parser_args but no parser of its own",
+                    tree.name
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Makes paths in the file relative to the directory it's in rather
+    /// than wherever the program was started, and expands a leading `~`.
+    /// Parsers given as a bare name are left to be found on the `PATH`.
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &Path| resolve_path(base, path);
+        let resolve_parser = |parser: &mut Option<String>| {
+            if let Some(command) = parser {
+                if command.contains('/') || command.starts_with('~') {
+                    *command = resolve(Path::new(
+                        command,
+                    ))
+                    .to_string_lossy()
+                    .into();
+                }
+            }
+        };
+        resolve_parser(&mut self.parser);
+        self.cache_dir = self.cache_dir.as_deref().map(resolve);
+        self.snapshot = self.snapshot.as_deref().map(resolve);
+        self.server.socket = self.server.socket.as_deref().map(resolve);
+        for tree in self.trees.iter_mut() {
+            tree.path = resolve(&tree.path);
+            resolve_parser(&mut tree.parser);
+        }
+    }
+
+    pub fn tree(&self, name: &str) -> Option<&TreeConfig> {
+        return self.trees.iter().find(|tree| tree.name == name);
+    }
+}
+
+fn resolve_path(base: &Path, path: &Path) -> PathBuf {
+    if let Ok(rest) = path.strip_prefix("~") {
+        if let Some(home) = dirs::home_dir() {
+            return home.join(rest);
+        }
+    }
+    return base.join(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn load(raw: &str) -> Result<Config, String> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, raw).unwrap();
+        return Config::load(Some(
+            &path,
+        ));
+    }
+
+    #[test]
+    fn tree_parser_args_need_a_tree_parser() {
+        let error =
+            load("[[tree]]\nname = \"wiki\"\npath = \"wiki\"\nparser_args = [\"--tables\"]\n")
+                .unwrap_err();
+        assert!(
+            error.ends_with("tree wiki has parser_args but no parser of its own"),
+            "{}",
+            error
+        );
+        let config = load(
+            "[[tree]]\nname = \"wiki\"\npath = \"wiki\"\nparser = \"builtin\"\nparser_args = [\"--tables\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.tree("wiki").unwrap().parser_args,
+            Some(vec![
+                "--tables".to_string()
+            ])
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::common::node::{Node, NodeType};
+use crate::common::traverse::{Visitor, Walk};
+use crate::common::tree::Tree;
+
+/// The lowest kind of node to include when returning part of a tree, from
+/// the coarsest to the finest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LevelOfDetail {
+    DIRECTORY,
+    /// Documents and attachments.
+    DOCUMENT,
+    /// Nodes directly inside a document, e.g. headers and lists.
+    BLOCK,
+    /// Nodes inside blocks, e.g. list items.
+    INLINE,
+    /// Everything, including any kinds of node added below inline.
+    FULL,
+}
+
+impl LevelOfDetail {
+    /// The level a node belongs to.
+    pub fn of(tree: &Tree, node: &Node) -> LevelOfDetail {
+        return match node.node_type {
+            NodeType::DIRECTORY => LevelOfDetail::DIRECTORY,
+            NodeType::DOCUMENT | NodeType::ATTACHMENT => LevelOfDetail::DOCUMENT,
+            _ => match tree.get_parent(&node.id) {
+                Some(parent) if parent.node_type == NodeType::DOCUMENT => LevelOfDetail::BLOCK,
+                _ => LevelOfDetail::INLINE,
+            },
+        };
+    }
+}
+
+/// Collects the nodes of a subtree down to a level and depth.
+struct Pruner<'a> {
+    detail: LevelOfDetail,
+    max_depth: Option<usize>,
+    kept: Vec<&'a Node>,
+}
+
+impl<'a> Visitor<'a> for Pruner<'a> {
+    fn enter(&mut self, tree: &'a Tree, node: &'a Node, depth: usize) -> Walk {
+        // the root is always kept, whatever its level
+        if depth > 0 {
+            let too_deep = self.max_depth.is_some_and(|max_depth| depth > max_depth);
+            if too_deep || LevelOfDetail::of(tree, node) > self.detail {
+                // levels only get finer further down, so nothing below is wanted either
+                return Walk::SkipChildren;
+            }
+        }
+        self.kept.push(node);
+        return Walk::Continue;
+    }
+}
+
+impl Tree {
+    /// A copy of the subtree under `root_id`, without nodes finer than
+    /// `detail` or more than `max_depth` levels below the root. Children
+    /// which were left out are removed from their parents' lists, so every
+    /// id in the copy has a node. Returns `None` if there's no such node.
+    pub fn subtree(
+        &self,
+        root_id: &str,
+        detail: LevelOfDetail,
+        max_depth: Option<usize>,
+    ) -> Option<Tree> {
+        self.nodes.get(root_id)?;
+        let mut pruner = Pruner {
+            detail,
+            max_depth,
+            kept: vec![],
+        };
+        self.walk(
+            root_id,
+            &mut pruner,
+        );
+        let kept: HashSet<&str> = pruner.kept.iter().map(|node| node.id.as_str()).collect();
+
+        let mut subtree = Tree::new(Node::new(
+            NodeType::None,
+        ));
+        subtree.nodes.clear();
+        for node in pruner.kept {
+            let mut node = node.clone();
+            node.children.retain(|child| kept.contains(child.as_str()));
+            subtree.nodes.insert(
+                node.id.clone(),
+                node,
+            );
+        }
+        subtree.schema_version = self.schema_version;
+        subtree.name = self.name.clone();
+        subtree.state = self.state;
+        subtree.root_node = root_id.into();
+        subtree.failures = self
+            .failures
+            .iter()
+            .filter(|(node_id, _)| kept.contains(node_id.as_str()))
+            .map(
+                |(node_id, failure)| {
+                    (
+                        node_id.clone(),
+                        failure.clone(),
+                    )
+                },
+            )
+            .collect();
+        subtree.rebuild_parents();
+        return Some(subtree);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::parser::{BuiltinParser, ParseError};
+    use crate::common::tree::{DocumentFailure, HydrateOptions, LoadOptions};
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    /// root
+    /// ├── notes
+    /// │   └── todo.md  # Todo, then a paragraph holding an inline node
+    /// └── top.md       # Top
+    async fn hydrated_tree() -> (
+        TempDir,
+        Tree,
+    ) {
+        let root = TempDir::new().unwrap();
+        fs::create_dir(root.path().join("notes")).unwrap();
+        fs::write(
+            root.path().join("notes/todo.md"),
+            "# Todo\n\n- one\n- two\n",
+        )
+        .unwrap();
+        fs::write(
+            root.path().join("top.md"),
+            "# Top\n",
+        )
+        .unwrap();
+        let mut tree = Tree::load(
+            "test".into(),
+            root.path().to_str().unwrap().into(),
+            &LoadOptions::default(),
+        )
+        .await
+        .unwrap();
+        tree.load_all_unloaded_docs(
+            &HydrateOptions {
+                parser: Arc::new(BuiltinParser),
+                processes: 1,
+                timeout: Duration::from_secs(30),
+                cache: None,
+            },
+            |_, _, _| {},
+        )
+        .await;
+        // the built-in grammar only has blocks, so the inline node is added
+        // by hand
+        let todo = tree.find_node("notes/todo.md").unwrap();
+        let paragraph = todo.children[1].clone();
+        let inline = Node::new(NodeType::LIST_ITEM);
+        tree.nodes
+            .get_mut(&paragraph)
+            .unwrap()
+            .children
+            .push(inline.id.clone());
+        tree.nodes.insert(
+            inline.id.clone(),
+            inline,
+        );
+        tree.rebuild_parents();
+        return (root, tree);
+    }
+
+    /// Checks every id in `subtree` has a node, and returns how many nodes
+    /// of each level it holds, from directories to full.
+    fn levels(tree: &Tree, subtree: &Tree) -> [usize; 5] {
+        let mut levels = [0; 5];
+        for node in subtree.nodes.values() {
+            assert!(node
+                .children
+                .iter()
+                .all(|child| subtree.nodes.contains_key(child)));
+            levels[LevelOfDetail::of(tree, node) as usize] += 1;
+        }
+        return levels;
+    }
+
+    #[tokio::test]
+    async fn prunes_nodes_finer_than_the_level() {
+        let (_root, tree) = hydrated_tree().await;
+        let subtree = |detail| {
+            return tree
+                .subtree(
+                    &tree.root_node,
+                    detail,
+                    None,
+                )
+                .unwrap();
+        };
+        assert_eq!(
+            levels(
+                &tree,
+                &subtree(LevelOfDetail::DIRECTORY)
+            ),
+            [2, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            levels(
+                &tree,
+                &subtree(LevelOfDetail::DOCUMENT)
+            ),
+            [2, 2, 0, 0, 0]
+        );
+        assert_eq!(
+            levels(
+                &tree,
+                &subtree(LevelOfDetail::BLOCK)
+            ),
+            [2, 2, 3, 0, 0]
+        );
+        assert_eq!(
+            levels(
+                &tree,
+                &subtree(LevelOfDetail::INLINE)
+            ),
+            [2, 2, 3, 1, 0]
+        );
+        let full = subtree(LevelOfDetail::FULL);
+        assert_eq!(
+            full.nodes,
+            tree.nodes
+        );
+    }
+
+    #[tokio::test]
+    async fn prunes_nodes_deeper_than_the_depth() {
+        let (_root, tree) = hydrated_tree().await;
+        let subtree = tree
+            .subtree(
+                &tree.root_node,
+                LevelOfDetail::FULL,
+                Some(1),
+            )
+            .unwrap();
+        assert!(subtree.find_node("notes").is_some());
+        assert!(subtree.find_node("top.md").is_some());
+        assert!(subtree.find_node("notes/todo.md").is_none());
+        assert_eq!(
+            levels(&tree, &subtree),
+            [2, 1, 0, 0, 0]
+        );
+        // the root is kept at any depth or level
+        let top = tree.find_node("top.md").unwrap();
+        let subtree = tree
+            .subtree(
+                &top.id,
+                LevelOfDetail::DIRECTORY,
+                Some(0),
+            )
+            .unwrap();
+        assert_eq!(
+            subtree.nodes.keys().collect::<Vec<_>>(),
+            [&top.id]
+        );
+        assert!(subtree.nodes[&top.id].children.is_empty());
+    }
+
+    #[tokio::test]
+    async fn starts_from_the_root_given() {
+        let (_root, mut tree) = hydrated_tree().await;
+        let notes = tree.find_node("notes").unwrap().id.clone();
+        let todo = tree.find_node("notes/todo.md").unwrap().id.clone();
+        let top = tree.find_node("top.md").unwrap().id.clone();
+        for id in [&todo, &top] {
+            tree.failures.insert(
+                id.clone(),
+                DocumentFailure {
+                    path: String::new(),
+                    error: ParseError::Reported {
+                        message: "failed".into(),
+                    },
+                },
+            );
+        }
+
+        let subtree = tree
+            .subtree(
+                &notes,
+                LevelOfDetail::DOCUMENT,
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            subtree.root_node,
+            notes
+        );
+        assert_eq!(
+            subtree.nodes[&notes].children,
+            [todo.as_str()]
+        );
+        assert!(subtree.get_parent(&notes).is_none());
+        assert_eq!(
+            subtree.failures.keys().collect::<Vec<_>>(),
+            [&todo]
+        );
+        assert!(tree
+            .subtree(
+                "no-such-node",
+                LevelOfDetail::FULL,
+                None
+            )
+            .is_none());
+    }
+}
@@ -0,0 +1,415 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Files in a directory whose patterns apply to it and everything below.
+pub const IGNORE_FILES: [&str; 2] = [".gitignore", ".zkignore"];
+
+/// Controls which files `Tree::load` skips while walking.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreOptions {
+    /// Walk hidden files and directories, i.e. those starting with `.`.
+    pub hidden: bool,
+    /// Don't read `.gitignore` or `.zkignore` files.
+    pub no_ignore_files: bool,
+    /// Extra patterns in `.gitignore` syntax, relative to the tree root.
+    pub exclude: Vec<String>,
+}
+
+/// The ignore rules in effect for one directory: the `--exclude` patterns
+/// plus any ignore files from the tree root down to the directory.
+#[derive(Clone)]
+pub struct IgnoreRules {
+    hidden: bool,
+    no_ignore_files: bool,
+    exclude: Arc<Gitignore>,
+    /// Outermost directory first, so later matchers take precedence.
+    files: Vec<Arc<Gitignore>>,
+}
+
+impl IgnoreRules {
+    pub fn new(root: &Path, options: &IgnoreOptions) -> Result<IgnoreRules, String> {
+        let mut exclude = GitignoreBuilder::new(root);
+        for pattern in options.exclude.iter() {
+            exclude
+                .add_line(
+                    None, pattern,
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        return Ok(
+            IgnoreRules {
+                hidden: options.hidden,
+                no_ignore_files: options.no_ignore_files,
+                exclude: Arc::new(exclude.build().map_err(|e| e.to_string())?),
+                files: vec![],
+            },
+        );
+    }
+
+    /// The rules for `dir`, a directory directly inside the one these rules
+    /// are for (or the root), adding the ignore files it contains.
+    pub fn for_directory(&self, dir: &Path) -> IgnoreRules {
+        let mut rules = self.clone();
+        if self.no_ignore_files {
+            return rules;
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in IGNORE_FILES {
+            let path = dir.join(name);
+            if path.is_file() {
+                found = true;
+                if let Some(e) = builder.add(&path) {
+                    // broken lines are skipped, the rest of the file still applies
+                    eprintln!(
+                        "Error in {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+        if found {
+            match builder.build() {
+                Ok(matcher) => rules.files.push(Arc::new(
+                    matcher,
+                )),
+                Err(e) => eprintln!(
+                    "Ignoring the ignore files in {}: {}",
+                    dir.display(),
+                    e
+                ),
+            }
+        }
+        return rules;
+    }
+
+    /// Whether `path`, an entry of the directory these rules are for,
+    /// should be left out of the tree.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden && !self.hidden {
+            return true;
+        }
+        if self
+            .exclude
+            .matched(
+                path, is_dir,
+            )
+            .is_ignore()
+        {
+            return true;
+        }
+        // the innermost file with an opinion wins, so `!pattern` can
+        // re-include something ignored further up
+        for matcher in self.files.iter().rev() {
+            match matcher.matched(
+                path, is_dir,
+            ) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => continue,
+            }
+        }
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// A directory holding `files`, given by path relative to it and
+    /// contents. Parent directories are created as needed.
+    fn tree_of(files: &[(&str, &str)]) -> TempDir {
+        let root = TempDir::new().unwrap();
+        for (path, contents) in files {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(
+                path, contents,
+            )
+            .unwrap();
+        }
+        return root;
+    }
+
+    /// The rules for `dir`, relative to `root`, as `Tree::load` builds
+    /// them on its way down.
+    fn rules_for(root: &Path, dir: &str, options: &IgnoreOptions) -> IgnoreRules {
+        let mut rules = IgnoreRules::new(
+            root, options,
+        )
+        .unwrap()
+        .for_directory(root);
+        let mut path = root.to_path_buf();
+        for name in Path::new(dir).iter() {
+            path.push(name);
+            rules = rules.for_directory(&path);
+        }
+        return rules;
+    }
+
+    fn ignored(root: &TempDir, path: &str, options: &IgnoreOptions) -> bool {
+        let path = Path::new(path);
+        let dir = path.parent().unwrap().to_str().unwrap();
+        let full_path = root.path().join(path);
+        return rules_for(
+            root.path(),
+            dir,
+            options,
+        )
+        .is_ignored(
+            &full_path,
+            full_path.is_dir(),
+        );
+    }
+
+    #[test]
+    fn hidden_entries_are_ignored_unless_asked_for() {
+        let root = tree_of(&[
+            (
+                ".obsidian/app.json",
+                "{}",
+            ),
+            (
+                ".draft.md",
+                "",
+            ),
+            (
+                "notes/.hidden.md",
+                "",
+            ),
+            (
+                "notes/seen.md",
+                "",
+            ),
+        ]);
+        let defaults = IgnoreOptions::default();
+        assert!(ignored(
+            &root,
+            ".obsidian",
+            &defaults
+        ));
+        assert!(ignored(
+            &root,
+            ".draft.md",
+            &defaults
+        ));
+        assert!(ignored(
+            &root,
+            "notes/.hidden.md",
+            &defaults
+        ));
+        assert!(!ignored(
+            &root,
+            "notes/seen.md",
+            &defaults
+        ));
+
+        let hidden = IgnoreOptions {
+            hidden: true,
+            ..Default::default()
+        };
+        assert!(!ignored(
+            &root,
+            ".obsidian",
+            &hidden
+        ));
+        assert!(!ignored(
+            &root,
+            ".draft.md",
+            &hidden
+        ));
+        assert!(!ignored(
+            &root,
+            "notes/.hidden.md",
+            &hidden
+        ));
+    }
+
+    #[test]
+    fn nested_ignore_files_can_re_include() {
+        let root = tree_of(&[
+            (
+                ".gitignore",
+                "*.log\nbuild/\n",
+            ),
+            (
+                "top.log", "",
+            ),
+            (
+                "notes/.gitignore",
+                "!keep.log\n",
+            ),
+            (
+                "notes/keep.log",
+                "",
+            ),
+            (
+                "notes/other.log",
+                "",
+            ),
+            (
+                "notes/deeper/keep.log",
+                "",
+            ),
+            (
+                "build/out.md",
+                "",
+            ),
+        ]);
+        let defaults = IgnoreOptions::default();
+        assert!(ignored(
+            &root, "top.log", &defaults
+        ));
+        assert!(ignored(
+            &root, "build", &defaults
+        ));
+        assert!(!ignored(
+            &root,
+            "notes/keep.log",
+            &defaults
+        ));
+        assert!(ignored(
+            &root,
+            "notes/other.log",
+            &defaults
+        ));
+        // the re-include is unanchored, so it reaches further down too
+        assert!(!ignored(
+            &root,
+            "notes/deeper/keep.log",
+            &defaults
+        ));
+
+        let no_files = IgnoreOptions {
+            no_ignore_files: true,
+            ..Default::default()
+        };
+        assert!(!ignored(
+            &root, "top.log", &no_files
+        ));
+        assert!(!ignored(
+            &root,
+            "notes/other.log",
+            &no_files
+        ));
+    }
+
+    #[test]
+    fn zkignore_applies_like_gitignore() {
+        let root = tree_of(&[
+            (
+                ".zkignore",
+                "private/\n",
+            ),
+            (
+                "notes/.zkignore",
+                "*.pdf\n",
+            ),
+            (
+                "private/secret.md",
+                "",
+            ),
+            (
+                "notes/paper.pdf",
+                "",
+            ),
+            (
+                "notes/paper.md",
+                "",
+            ),
+            (
+                "paper.pdf",
+                "",
+            ),
+        ]);
+        let defaults = IgnoreOptions::default();
+        assert!(ignored(
+            &root, "private", &defaults
+        ));
+        assert!(ignored(
+            &root,
+            "notes/paper.pdf",
+            &defaults
+        ));
+        assert!(!ignored(
+            &root,
+            "notes/paper.md",
+            &defaults
+        ));
+        // the nested file only applies to its own directory
+        assert!(!ignored(
+            &root,
+            "paper.pdf",
+            &defaults
+        ));
+    }
+
+    #[test]
+    fn exclude_patterns_are_relative_to_the_root() {
+        let root = tree_of(&[
+            (
+                "drafts/a.md",
+                "",
+            ),
+            (
+                "notes/drafts/b.md",
+                "",
+            ),
+            (
+                "archive/a.md",
+                "",
+            ),
+            (
+                "notes/archive/b.md",
+                "",
+            ),
+        ]);
+        let options = IgnoreOptions {
+            exclude: vec!["/drafts".into(), "archive/".into()],
+            ..Default::default()
+        };
+        // anchored: only at the root
+        assert!(ignored(
+            &root, "drafts", &options
+        ));
+        assert!(!ignored(
+            &root,
+            "notes/drafts",
+            &options
+        ));
+        // unanchored: at any depth
+        assert!(ignored(
+            &root, "archive", &options
+        ));
+        assert!(ignored(
+            &root,
+            "notes/archive",
+            &options
+        ));
+    }
+
+    #[test]
+    fn invalid_exclude_patterns_are_errors() {
+        let root = tree_of(&[]);
+        let options = IgnoreOptions {
+            exclude: vec!["notes/[z-a].md".into()],
+            ..Default::default()
+        };
+        assert!(
+            IgnoreRules::new(
+                root.path(),
+                &options
+            )
+            .is_err()
+        );
+    }
+}
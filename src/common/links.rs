@@ -0,0 +1,153 @@
+/// A `[[wiki link]]` in a line of text.
+/// `start` and `end` are byte offsets into the line, covering the brackets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WikiLink {
+    pub target: String,
+    pub heading: Option<String>,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// An image in a line of text, either `![alt](target)` or an
+/// `![[target]]` embed.
+/// `start` and `end` are byte offsets into the line, covering the `!`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageLink {
+    pub target: String,
+    pub alt: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A `#tag` in a line of text.
+/// `start` and `end` are byte offsets into the line, covering the `#`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Finds every `[[target]]`, `[[target#heading]]` and `[[target|alias]]`
+/// in a single line.
+pub fn wiki_links(line: &str) -> Vec<WikiLink> {
+    let mut links = vec![];
+    let mut offset = 0;
+    while let Some(open) = line[offset..].find("[[") {
+        let start = offset + open;
+        let inner_start = start + 2;
+        let close = match line[inner_start..].find("]]") {
+            Some(close) => inner_start + close,
+            None => break,
+        };
+        let inner = &line[inner_start..close];
+        // a link can't contain another link
+        if let Some(nested) = inner.rfind("[[") {
+            offset = inner_start + nested;
+            continue;
+        }
+        let target = inner.split('|').next().unwrap_or("");
+        let (target, heading) = match target.split_once('#') {
+            Some((target, heading)) => (
+                target,
+                Some(heading.trim().to_string()),
+            ),
+            None => (
+                target, None,
+            ),
+        };
+        if !target.trim().is_empty() {
+            links.push(WikiLink {
+                target: target.trim().into(),
+                heading,
+                start,
+                end: close + 2,
+            });
+        }
+        offset = close + 2;
+    }
+    return links;
+}
+
+/// Finds every `![alt](target)` and `![[target]]` in a single line.
+/// Titles (`![alt](target "title")`) and `<>` around targets are dropped.
+pub fn image_links(line: &str) -> Vec<ImageLink> {
+    let mut images = vec![];
+    for link in wiki_links(line) {
+        if link.start > 0 && line.as_bytes()[link.start - 1] == b'!' {
+            images.push(
+                ImageLink {
+                    alt: String::new(),
+                    target: link.target,
+                    start: link.start - 1,
+                    end: link.end,
+                },
+            );
+        }
+    }
+    let mut offset = 0;
+    while let Some(open) = line[offset..].find("![") {
+        let start = offset + open;
+        offset = start + 2;
+        if line[offset..].starts_with('[') {
+            // an embed, found above
+            continue;
+        }
+        let alt_end = match line[offset..].find("](") {
+            Some(alt_end) => offset + alt_end,
+            None => break,
+        };
+        let target_start = alt_end + 2;
+        let close = match line[target_start..].find(')') {
+            Some(close) => target_start + close,
+            None => break,
+        };
+        let target = line[target_start..close].trim();
+        let target = match target.strip_prefix('<') {
+            Some(bracketed) => bracketed.split('>').next().unwrap_or(""),
+            None => target.split_whitespace().next().unwrap_or(""),
+        };
+        if !target.is_empty() {
+            images.push(
+                ImageLink {
+                    target: target.into(),
+                    alt: line[offset..alt_end].into(),
+                    start,
+                    end: close + 1,
+                },
+            );
+        }
+        offset = close + 1;
+    }
+    images.sort_by_key(|image| image.start);
+    return images;
+}
+
+/// Finds every `#tag` in a single line. Tags must follow whitespace or
+/// start the line, so ATX headers and `[[note#heading]]` links aren't tags.
+pub fn tags(line: &str) -> Vec<Tag> {
+    let mut tags = vec![];
+    let mut prev: Option<char> = None;
+    for (start, c) in line.char_indices() {
+        if c == '#' && prev.is_none_or(char::is_whitespace) {
+            let name: String = line[start + 1..]
+                .chars()
+                .take_while(|c| is_tag_char(*c))
+                .collect();
+            // `#1` is more likely an issue number than a tag
+            if !name.is_empty() && !name.chars().all(|c| c.is_ascii_digit()) {
+                tags.push(Tag {
+                    end: start + 1 + name.len(),
+                    name,
+                    start,
+                });
+            }
+        }
+        prev = Some(c);
+    }
+    return tags;
+}
+
+pub fn is_tag_char(c: char) -> bool {
+    return c.is_alphanumeric() || c == '_' || c == '-' || c == '/';
+}
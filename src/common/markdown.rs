@@ -0,0 +1,215 @@
+use nom::branch::alt;
+use nom::character::complete::{anychar, char, line_ending, space0, space1};
+use nom::character::streaming::one_of;
+use nom::combinator::eof;
+use nom::multi::{many0, many_m_n, many_till};
+use nom::sequence::{terminated, tuple};
+use nom::IResult;
+
+use crate::common::metadata::FileMetadata;
+use crate::common::node::{FileState, Node, NodeData, NodeType};
+use crate::common::tree::Tree;
+
+/// Parses the text of the document at `path` into a tree with stable ids.
+pub fn parse_document(path: &str, raw: &str) -> Result<Tree, String> {
+    let (_, mut tree) = match document(raw) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Err(format!(
+                "Failed to parse {}: {}",
+                path, e
+            ))
+        }
+    };
+    let root = tree.get_node_mut(tree.root_node.clone());
+    root.unwrap().data = NodeData::DocumentData {
+        path: path.into(),
+        state: FileState::DEHYDRATED,
+        // only the server knows where the document is in the tree
+        canonical: String::new(),
+        virtual_path: String::new(),
+        metadata: FileMetadata::default(),
+    };
+    // parsers don't know which tree the document belongs to
+    tree.stabilise_ids(Node::stable_id(&["", path]));
+    return Ok(tree);
+}
+
+fn blank_line(raw: &str) -> IResult<&str, Tree> {
+    let result = tuple((
+        line_ending,
+        terminated(
+            space0,
+            alt((
+                line_ending,
+                eof,
+            )),
+        ),
+    ))(raw);
+    match result {
+        Ok((stream, _)) => {
+            let node = Node::new(NodeType::None);
+            let tree = Tree::new(node);
+            return Ok((
+                stream, tree,
+            ));
+        }
+        Err(e) => {
+            return Err(e);
+        }
+    }
+}
+
+fn little_indent(raw: &str) -> IResult<&str, Vec<char>> {
+    many_m_n(
+        0,
+        3,
+        char(' '),
+    )(raw)
+}
+
+fn atx_header(raw: &str) -> IResult<&str, Tree> {
+    let mut parser = tuple((
+        little_indent,
+        many_m_n(
+            1,
+            6,
+            char('#'),
+        ),
+        space1,
+        many_till(
+            anychar,
+            line_ending,
+        ),
+    ));
+    let result = parser(raw);
+    match result {
+        Ok((stream, results)) => {
+            let mut node = Node::new(NodeType::HEADER);
+            let ack: (
+                Vec<char>,
+                &str,
+            ) = results.3;
+            let content: String = ack.0.iter().collect();
+            node.data = NodeData::HeaderData {
+                text: content,
+                level: results.1.len(),
+            };
+            return Ok((
+                stream,
+                Tree::new(node),
+            ));
+        }
+        Err(e) => return Err(e),
+    }
+}
+
+fn thematic_break(raw: &str) -> IResult<&str, Tree> {
+    let result = terminated(
+        tuple((
+            little_indent,
+            // commonmark spec is that spaces are allowed
+            // between the thematic break characters
+            alt((
+                tuple((
+                    char('-'),
+                    space0,
+                    char('-'),
+                    space0,
+                    char('-'),
+                    many0(one_of(
+                        " \t-",
+                    )),
+                )),
+                tuple((
+                    char('_'),
+                    space0,
+                    char('_'),
+                    space0,
+                    char('_'),
+                    many0(one_of(
+                        " \t_",
+                    )),
+                )),
+                tuple((
+                    char('*'),
+                    space0,
+                    char('*'),
+                    space0,
+                    char('*'),
+                    many0(one_of(
+                        " \t*",
+                    )),
+                )),
+            )),
+            line_ending,
+        )),
+        line_ending,
+    )(raw);
+
+    match result {
+        // a thematic break is just a thematic break
+        Ok((stream, _)) => {
+            let mut node = Node::new(NodeType::THEMATIC_BREAK);
+            node.data = NodeData::ThematicBreakData {};
+            let tree = Tree::new(node);
+            return Ok((
+                stream, tree,
+            ));
+        }
+        Err(e) => return Err(e),
+    }
+}
+
+fn paragraph(raw: &str) -> IResult<&str, Tree> {
+    // This is a decent example for transforming a character specification into
+    // a unit of meaning
+    // Might need an indent level modifier
+    let result = tuple((
+        little_indent,
+        many_till(
+            anychar, blank_line,
+        ),
+    ))(raw);
+    match result {
+        Ok((stream, results)) => {
+            let mut node = Node::new(NodeType::PARAGRAPH);
+            let content: String = String::from_iter(results.1 .0);
+            node.data = NodeData::ParagraphData { text: content };
+            return Ok((
+                stream,
+                Tree::new(node),
+            ));
+        }
+        Err(e) => return Err(e),
+    }
+}
+
+fn block(raw: &str) -> IResult<&str, Tree> {
+    return alt((
+        thematic_break,
+        atx_header,
+        blank_line,
+        paragraph,
+    ))(raw);
+}
+
+pub fn document(raw: &str) -> IResult<&str, Tree> {
+    let blocks = many0(block)(raw);
+    match blocks {
+        Ok((stream, results)) => {
+            let root = Node::new(NodeType::DOCUMENT);
+            let mut tree = Tree::new(root);
+            for block in results {
+                tree.insert_child_under(
+                    block,
+                    tree.root_node.clone(),
+                );
+            }
+            return Ok((
+                stream, tree,
+            ));
+        }
+        Err(e) => return Err(e),
+    }
+}
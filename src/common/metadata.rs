@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Facts about the file behind a directory or document node, flattened
+/// into the node's data so selectors can test them, e.g.
+/// `document[modified>now-7d]`. Timestamps are Unix seconds.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct FileMetadata {
+    /// Bytes in a document, or in every document and attachment under a
+    /// directory.
+    pub size: u64,
+    /// Not every filesystem records when a file was created.
+    pub created: Option<u64>,
+    pub modified: Option<u64>,
+    /// Only known once a document has been hydrated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub word_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_count: Option<usize>,
+    /// The BLAKE3 hash of the document's contents, in hex.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl FileMetadata {
+    /// What the filesystem says about `path`, without reading it.
+    pub fn of(path: &Path) -> FileMetadata {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return FileMetadata::default(),
+        };
+        return FileMetadata {
+            size: metadata.len(),
+            created: metadata.created().ok().and_then(unix_seconds),
+            modified: metadata.modified().ok().and_then(unix_seconds),
+            ..FileMetadata::default()
+        };
+    }
+
+    /// `of` plus the counts and hash of the document's contents.
+    pub fn with_contents(path: &Path) -> io::Result<FileMetadata> {
+        let contents = fs::read(path)?;
+        let text = String::from_utf8_lossy(&contents);
+        return Ok(
+            FileMetadata {
+                word_count: Some(text.split_whitespace().count()),
+                line_count: Some(text.lines().count()),
+                hash: Some(blake3::hash(&contents).to_hex().to_string()),
+                ..FileMetadata::of(path)
+            },
+        );
+    }
+}
+
+pub fn unix_seconds(time: SystemTime) -> Option<u64> {
+    return time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+}
@@ -1,9 +1,13 @@
-use axum::extract::FromRef;
 use serde::{Deserialize, Serialize};
-use serde_json::to_string;
-use std::collections::HashMap;
 use uuid::Uuid;
 
+use crate::common::metadata::FileMetadata;
+
+/// Namespace for the UUIDv5 ids of nodes, see `Node::stable_id`.
+pub const NODE_ID_NAMESPACE: Uuid = Uuid::from_u128(0x5a3e_4b1c_7d2f_4e8a_9c61_0b7f_2d4e_8a13);
+
+// the variants are the wire format's names
+#[allow(non_camel_case_types)]
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum NodeType {
     DIRECTORY,
@@ -13,15 +17,29 @@ pub enum NodeType {
     THEMATIC_BREAK,
     LIST_ITEM,
     LIST,
+    /// A file in the tree which isn't a document, e.g. an image or PDF.
+    ATTACHMENT,
     None, // used in parsing to indicate "consume token but don't email anything"
 }
 
+#[allow(non_camel_case_types)]
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum ListType {
     UNORDERED_LIST,
     ORDERED_LIST,
 }
 
+/// How much of a document is in memory. Documents start out dehydrated,
+/// with only their file metadata, and are hydrated by parsing them.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum FileState {
+    #[default]
+    DEHYDRATED,
+    /// Being parsed. Goes back to `DEHYDRATED` if parsing fails.
+    HYDRATING,
+    HYDRATED,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum NodeData {
     None,
@@ -31,10 +49,26 @@ pub enum NodeData {
     },
     DirectoryData {
         path: String,
+        /// The absolute path with symlinks resolved.
+        #[serde(default)]
+        canonical: String,
+        /// The path relative to the tree root, through any symlinks.
+        /// Empty for the root itself.
+        #[serde(default)]
+        virtual_path: String,
+        #[serde(flatten)]
+        metadata: FileMetadata,
     },
     DocumentData {
         path: String,
-        loaded: bool,
+        #[serde(default)]
+        state: FileState,
+        #[serde(default)]
+        canonical: String,
+        #[serde(default)]
+        virtual_path: String,
+        #[serde(flatten)]
+        metadata: FileMetadata,
     },
     ParagraphData {
         text: String,
@@ -48,6 +82,12 @@ pub enum NodeData {
         indent: usize,
     },
     ThematicBreakData {},
+    AttachmentData {
+        path: String,
+        size: u64,
+        /// Guessed from the file extension.
+        mime: String,
+    },
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -59,6 +99,8 @@ pub struct Node {
 }
 
 impl Node {
+    /// Creates a node with a random id. Trees give their nodes stable ids
+    /// once their position is known, so this id is only temporary.
     pub fn new(node_type: NodeType) -> Self {
         let id = Uuid::new_v4();
         let id_as_str = id.to_string();
@@ -69,4 +111,114 @@ impl Node {
             data: NodeData::None,
         }
     }
+
+    /// Derives a UUIDv5 id from `parts`, so the same parts always produce
+    /// the same id. Directories and documents use the tree name and their
+    /// path relative to the tree root; other nodes use their parent's id and
+    /// their position under it. See docs/architecture.md.
+    pub fn stable_id(parts: &[&str]) -> String {
+        // escaped so that a part containing NUL can't pass for two parts
+        let escaped: Vec<String> = parts
+            .iter()
+            .map(|part| {
+                part.replace(
+                    '\\', "\\\\",
+                )
+                .replace(
+                    '\0', "\\0",
+                )
+            })
+            .collect();
+        let name = escaped.join("\0");
+        return Uuid::new_v5(
+            &NODE_ID_NAMESPACE,
+            name.as_bytes(),
+        )
+        .to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_id_is_a_uuidv5_of_the_joined_parts() {
+        let expected = Uuid::new_v5(
+            &NODE_ID_NAMESPACE,
+            b"notes\0daily/2024-01-01.md",
+        );
+        assert_eq!(
+            Node::stable_id(&["notes", "daily/2024-01-01.md"]),
+            expected.to_string()
+        );
+        assert_eq!(
+            Node::stable_id(&["notes", "daily/2024-01-01.md"]),
+            Node::stable_id(&["notes", "daily/2024-01-01.md"])
+        );
+    }
+
+    #[test]
+    fn stable_id_keeps_parts_apart() {
+        let lookalikes: Vec<Vec<&str>> = vec![
+            vec!["ab", "c"],
+            vec!["a", "bc"],
+            vec!["a\0b", "c"],
+            vec!["a", "b\0c"],
+            vec!["a", "b", "c"],
+            vec!["a\0b\0c"],
+            vec!["a\\0b", "c"],
+            vec!["a\\", "0b", "c"],
+            vec!["a", ""],
+            vec!["a\0"],
+            vec!["a\\0"],
+        ];
+        let ids: Vec<String> = lookalikes
+            .iter()
+            .map(|parts| Node::stable_id(parts))
+            .collect();
+        for (i, id) in ids.iter().enumerate() {
+            for (j, other) in ids.iter().enumerate().skip(i + 1) {
+                assert_ne!(
+                    id, other,
+                    "{:?} and {:?} have the same id",
+                    lookalikes[i], lookalikes[j]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stable_id_separates_paths_from_positions() {
+        // a block's parts are its parent's id, index and type, which a
+        // tree name containing NUL could otherwise spell out
+        let parent = Node::stable_id(&["notes", "todo.md"]);
+        let block = Node::stable_id(&[&parent, "0", "PARAGRAPH"]);
+        let tree_name = format!(
+            "{}\0{}",
+            parent, "0"
+        );
+        assert_ne!(
+            block,
+            Node::stable_id(&[&tree_name, "PARAGRAPH"])
+        );
+        assert_ne!(
+            block,
+            Node::stable_id(&[
+                &format!(
+                    "{}\0",
+                    tree_name
+                ),
+                "PARAGRAPH"
+            ])
+        );
+        assert_ne!(
+            Node::stable_id(&[&parent, "0", "PARAGRAPH"]),
+            Node::stable_id(&[&parent, "0", "HEADER"])
+        );
+        assert_ne!(
+            Node::stable_id(&[&parent, "0", "PARAGRAPH"]),
+            Node::stable_id(&[&parent, "1", "PARAGRAPH"])
+        );
+    }
 }
@@ -0,0 +1,688 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::fs::read_to_string;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::common::markdown::parse_document;
+use crate::common::node::NodeType;
+use crate::common::tree::Tree;
+
+/// The value of `--parser` which selects `BuiltinParser`.
+pub const BUILTIN_PARSER: &str = "builtin";
+
+/// Version of the format parsers exchange with the server, see
+/// docs/parser-protocol.md. Parsers report it from `--describe` and in
+/// the `schema_version` of every tree they emit.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a parser reports about itself from `--describe`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParserDescription {
+    pub name: String,
+    pub version: String,
+    pub protocol_version: u32,
+    /// The Markdown dialects the parser understands, e.g. `commonmark`.
+    pub dialects: Vec<String>,
+    /// The node types the parser can emit.
+    pub node_types: Vec<NodeType>,
+    /// Whether the parser accepts `--stdin`, so the server can keep it
+    /// running and send it one path at a time.
+    #[serde(default)]
+    pub streaming: bool,
+}
+
+impl ParserDescription {
+    /// The description of the grammar shared by `md-parse` and `BuiltinParser`.
+    pub fn builtin() -> Self {
+        return ParserDescription {
+            name: "md-parse".into(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            protocol_version: PROTOCOL_VERSION,
+            dialects: vec!["commonmark".into()],
+            node_types: vec![
+                NodeType::DOCUMENT,
+                NodeType::HEADER,
+                NodeType::PARAGRAPH,
+                NodeType::THEMATIC_BREAK,
+            ],
+            streaming: true,
+        };
+    }
+
+    /// Whether the parser understands `dialect`, ignoring case.
+    pub fn supports_dialect(&self, dialect: &str) -> bool {
+        return self
+            .dialects
+            .iter()
+            .any(|supported| supported.eq_ignore_ascii_case(dialect));
+    }
+}
+
+pub type ParseFuture = Pin<Box<dyn Future<Output = Result<Tree, ParseError>> + Send>>;
+
+/// Waits until a parser can start on a document, then gives the parse.
+pub type StartFuture = Pin<Box<dyn Future<Output = Result<ParseFuture, ParseError>> + Send>>;
+
+/// Why a document couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ParseError {
+    /// The document couldn't be read, e.g. it's missing or isn't UTF-8.
+    Read { message: String },
+    /// The built-in grammar couldn't parse the document.
+    Syntax { message: String },
+    /// The parser executable couldn't be started.
+    Spawn { message: String },
+    /// The parser exited unsuccessfully.
+    Exited { status: Option<i32>, stderr: String },
+    /// The parser reported that it couldn't parse the document.
+    Reported { message: String },
+    /// The parser's output wasn't a valid tree.
+    InvalidOutput { message: String, stderr: String },
+    /// The parser emitted a tree in a different version of the protocol.
+    UnsupportedSchema { found: u32, expected: u32 },
+    /// The parsed tree's root wasn't a DOCUMENT.
+    UnexpectedRoot { node_type: NodeType },
+    /// The parser took longer than the hydration timeout.
+    Timeout { seconds: f64 },
+    /// The parser panicked while parsing in-process.
+    Panicked { message: String },
+}
+
+impl ParseError {
+    /// What the parser wrote to stderr, if it ran as a subprocess.
+    pub fn stderr(&self) -> Option<&str> {
+        match self {
+            ParseError::Exited { stderr, .. } | ParseError::InvalidOutput { stderr, .. } => {
+                return Some(stderr)
+            }
+            _ => return None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Read { message } => write!(
+                f,
+                "couldn't read document: {}",
+                message
+            ),
+            ParseError::Syntax { message } => write!(
+                f,
+                "{}",
+                message
+            ),
+            ParseError::Spawn { message } => write!(
+                f,
+                "couldn't start parser: {}",
+                message
+            ),
+            ParseError::Exited { status, stderr } => match status {
+                Some(code) => write!(
+                    f,
+                    "parser exited with status {}: {}",
+                    code,
+                    stderr.trim()
+                ),
+                None => write!(
+                    f,
+                    "parser was killed by a signal: {}",
+                    stderr.trim()
+                ),
+            },
+            ParseError::Reported { message } => write!(
+                f,
+                "{}",
+                message
+            ),
+            ParseError::InvalidOutput { message, .. } => {
+                write!(
+                    f,
+                    "parser output wasn't a valid tree: {}",
+                    message
+                )
+            }
+            ParseError::UnsupportedSchema { found, expected } => write!(
+                f,
+                "parser emitted schema version {} but {} is required",
+                found, expected
+            ),
+            ParseError::UnexpectedRoot { node_type } => {
+                write!(
+                    f,
+                    "expected a DOCUMENT root but got {:?}",
+                    node_type
+                )
+            }
+            ParseError::Timeout { seconds } => write!(
+                f,
+                "parser timed out after {}s",
+                seconds
+            ),
+            ParseError::Panicked { message } => write!(
+                f,
+                "parser panicked: {}",
+                message
+            ),
+        }
+    }
+}
+
+/// One line of output from a parser given several paths or `--stdin`.
+/// Failures are reported in-line so the parser can carry on.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BatchOutput {
+    Error { path: String, error: String },
+    Tree(Tree),
+}
+
+/// Turns a single document into a tree whose root is a DOCUMENT node.
+pub trait DocumentParser: Send + Sync {
+    /// Parses the document at `path`. Dropping the future should stop
+    /// any work that can be stopped.
+    fn parse(&self, path: String) -> ParseFuture;
+
+    /// Waits until the parser can start on the document at `path`, e.g.
+    /// for a free worker, then returns the parse itself. Parses are timed
+    /// from when this resolves, so time spent waiting isn't counted.
+    fn start(&self, path: String) -> StartFuture {
+        let parse = self.parse(path);
+        return Box::pin(async move { Ok(parse) });
+    }
+
+    /// A short description for logs, e.g. the parser's path.
+    fn name(&self) -> String;
+
+    fn describe(&self) -> ParserDescription;
+}
+
+/// Parses documents in-process with the same grammar as `md-parse`.
+pub struct BuiltinParser;
+
+impl DocumentParser for BuiltinParser {
+    fn parse(&self, path: String) -> ParseFuture {
+        return Box::pin(
+            async move {
+                // parsing is CPU bound, so keep it off the async workers
+                let parsed = tokio::task::spawn_blocking(move || {
+                    let raw = read_to_string(&path).map_err(
+                        |e| ParseError::Read {
+                            message: e.to_string(),
+                        },
+                    )?;
+                    return parse_document(
+                        &path, &raw,
+                    )
+                    .map_err(|message| ParseError::Syntax { message });
+                })
+                .await;
+                match parsed {
+                    Ok(result) => return result,
+                    Err(e) => {
+                        return Err(
+                            ParseError::Panicked {
+                                message: e.to_string(),
+                            },
+                        )
+                    }
+                }
+            },
+        );
+    }
+
+    fn name(&self) -> String {
+        return BUILTIN_PARSER.into();
+    }
+
+    fn describe(&self) -> ParserDescription {
+        return ParserDescription::builtin();
+    }
+}
+
+/// Runs an external parser executable once per document, which prints
+/// the document's tree as JSON to stdout.
+pub struct SubprocessParser {
+    pub command: String,
+    /// Passed to the parser before any of the protocol's own arguments.
+    pub args: Vec<String>,
+    pub description: ParserDescription,
+}
+
+impl SubprocessParser {
+    /// Asks the parser to `--describe` itself, and checks that it speaks
+    /// the same version of the protocol as the server.
+    pub async fn connect(command: String, args: Vec<String>) -> Result<Self, String> {
+        let output = Command::new(command.as_str())
+            .args(&args)
+            .arg("--describe")
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| {
+                format!(
+                    "Failed to run {}: {}",
+                    command, e
+                )
+            })?;
+        if !output.status.success() {
+            return Err(format!(
+                "{} --describe failed: {}",
+                command,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let description: ParserDescription =
+            serde_json::from_slice(&output.stdout).map_err(|e| {
+                format!(
+                    "{} --describe returned an invalid description: {}",
+                    command, e
+                )
+            })?;
+        if description.protocol_version != PROTOCOL_VERSION {
+            return Err(format!(
+                "{} speaks protocol version {} but {} is required",
+                command, description.protocol_version, PROTOCOL_VERSION
+            ));
+        }
+        return Ok(
+            SubprocessParser {
+                command,
+                args,
+                description,
+            },
+        );
+    }
+}
+
+impl DocumentParser for SubprocessParser {
+    fn parse(&self, path: String) -> ParseFuture {
+        let command = self.command.clone();
+        let args = self.args.clone();
+        return Box::pin(
+            async move {
+                // the parser is killed if this future is dropped, e.g. by a timeout
+                let output = Command::new(command.as_str())
+                    .args(&args)
+                    .arg(&path)
+                    .kill_on_drop(true)
+                    .output()
+                    .await
+                    .map_err(
+                        |e| ParseError::Spawn {
+                            message: format!(
+                                "{}: {}",
+                                command, e
+                            ),
+                        },
+                    )?;
+
+                let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+                if !output.status.success() {
+                    return Err(
+                        ParseError::Exited {
+                            status: output.status.code(),
+                            stderr,
+                        },
+                    );
+                }
+                let parsed_tree: Tree = serde_json::from_slice(&output.stdout).map_err(|e| {
+                    ParseError::InvalidOutput {
+                        message: e.to_string(),
+                        stderr,
+                    }
+                })?;
+                return Ok(parsed_tree);
+            },
+        );
+    }
+
+    fn name(&self) -> String {
+        return self.command.clone();
+    }
+
+    fn describe(&self) -> ParserDescription {
+        return self.description.clone();
+    }
+}
+
+/// How much of a streaming parser's stderr is kept to explain its errors.
+const STDERR_TAIL_BYTES: usize = 8192;
+
+/// How long a streaming parser which closed its stdout is given to exit.
+const EXIT_GRACE: Duration = Duration::from_millis(500);
+
+/// A parser process started with `--stdin`, which parses one path per line.
+struct ParserWorker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    /// The last `STDERR_TAIL_BYTES` the process wrote to stderr. It's read
+    /// as it's written, so a chatty parser can't block on a full pipe.
+    stderr_tail: Arc<StdMutex<VecDeque<u8>>>,
+    stderr_reader: JoinHandle<()>,
+}
+
+impl ParserWorker {
+    fn spawn(command: &str, args: &[String]) -> Result<Self, ParseError> {
+        let spawn_error = |message: String| ParseError::Spawn {
+            message: format!(
+                "{}: {}",
+                command, message
+            ),
+        };
+        let mut child = Command::new(command)
+            .args(args)
+            .arg("--stdin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| spawn_error(e.to_string()))?;
+        let stdin = child.stdin.take().ok_or(spawn_error("no stdin".into()))?;
+        let stdout = child.stdout.take().ok_or(spawn_error("no stdout".into()))?;
+        let mut stderr = child.stderr.take().ok_or(spawn_error("no stderr".into()))?;
+
+        let stderr_tail = Arc::new(StdMutex::new(VecDeque::new()));
+        let tail = stderr_tail.clone();
+        // ends when the process exits and its end of the pipe closes
+        let stderr_reader = tokio::spawn(
+            async move {
+                let mut buffer = [0; 1024];
+                while let Ok(read @ 1..) = stderr.read(&mut buffer).await {
+                    let mut tail = tail.lock().unwrap();
+                    tail.extend(&buffer[..read]);
+                    let excess = tail.len().saturating_sub(STDERR_TAIL_BYTES);
+                    tail.drain(..excess);
+                }
+            },
+        );
+        return Ok(
+            ParserWorker {
+                child,
+                stdin,
+                stdout: BufReader::new(stdout),
+                stderr_tail,
+                stderr_reader,
+            },
+        );
+    }
+
+    /// Why the worker stopped once its stdout has closed. It's given a
+    /// moment to exit, so its status and last words on stderr are known.
+    async fn exited(&mut self) -> ParseError {
+        let _ = timeout(
+            EXIT_GRACE,
+            &mut self.stderr_reader,
+        )
+        .await;
+        let status = timeout(
+            EXIT_GRACE,
+            self.child.wait(),
+        )
+        .await
+        .ok()
+        .and_then(|status| status.ok())
+        .and_then(|status| status.code());
+        return ParseError::Exited {
+            status,
+            stderr: tail_text(&self.stderr_tail),
+        };
+    }
+
+    /// Sends one path to the worker and reads back its tree. The outer
+    /// error means the worker is no longer usable; the inner one is a
+    /// failure the worker reported for this document only.
+    async fn parse(&mut self, path: &str) -> Result<Result<Tree, ParseError>, ParseError> {
+        if path.contains('\n') {
+            return Ok(Err(
+                ParseError::Read {
+                    message: "Paths containing newlines can't be streamed to a parser.".into(),
+                },
+            ));
+        }
+        let stderr_tail = self.stderr_tail.clone();
+        let broken = |message: String| ParseError::InvalidOutput {
+            message,
+            stderr: tail_text(&stderr_tail),
+        };
+        self.stdin
+            .write_all(
+                format!(
+                    "{}\n",
+                    path
+                )
+                .as_bytes(),
+            )
+            .await
+            .map_err(|e| broken(e.to_string()))?;
+        self.stdin
+            .flush()
+            .await
+            .map_err(|e| broken(e.to_string()))?;
+
+        let mut line = String::new();
+        let read = self
+            .stdout
+            .read_line(&mut line)
+            .await
+            .map_err(|e| broken(e.to_string()))?;
+        if read == 0 {
+            return Err(self.exited().await);
+        }
+        match serde_json::from_str(&line).map_err(|e| broken(e.to_string()))? {
+            BatchOutput::Tree(tree) => return Ok(Ok(tree)),
+            BatchOutput::Error { path: _, error } => {
+                return Ok(Err(
+                    ParseError::Reported { message: error },
+                ))
+            }
+        }
+    }
+}
+
+fn tail_text(tail: &StdMutex<VecDeque<u8>>) -> String {
+    let mut tail = tail.lock().unwrap();
+    return String::from_utf8_lossy(tail.make_contiguous()).into_owned();
+}
+
+/// Keeps up to `workers` parser processes running in `--stdin` mode and
+/// hands each document to an idle one, so hydrating a tree doesn't cost
+/// a process spawn per document.
+pub struct StreamingParser {
+    pub command: String,
+    pub args: Vec<String>,
+    pub description: ParserDescription,
+    idle: Arc<StdMutex<Vec<ParserWorker>>>,
+    slots: Arc<Semaphore>,
+}
+
+impl StreamingParser {
+    pub fn new(
+        command: String,
+        args: Vec<String>,
+        description: ParserDescription,
+        workers: usize,
+    ) -> Self {
+        return StreamingParser {
+            command,
+            args,
+            description,
+            idle: Arc::new(StdMutex::new(vec![])),
+            slots: Arc::new(Semaphore::new(workers.max(1))),
+        };
+    }
+}
+
+impl DocumentParser for StreamingParser {
+    fn parse(&self, path: String) -> ParseFuture {
+        let start = self.start(path);
+        return Box::pin(async move { start.await?.await });
+    }
+
+    /// Waits for a free worker, starting one if there are fewer than
+    /// `workers`, and returns the exchange with it.
+    fn start(&self, path: String) -> StartFuture {
+        let command = self.command.clone();
+        let args = self.args.clone();
+        let idle = self.idle.clone();
+        let slots = self.slots.clone();
+        return Box::pin(
+            async move {
+                let permit = slots.acquire_owned().await.map_err(
+                    |e| ParseError::Spawn {
+                        message: e.to_string(),
+                    },
+                )?;
+                let reused = idle.lock().unwrap().pop();
+                let mut worker = match reused {
+                    Some(worker) => worker,
+                    None => ParserWorker::spawn(
+                        &command, &args,
+                    )?,
+                };
+                let parse: ParseFuture = Box::pin(
+                    async move {
+                        let _permit = permit;
+                        // if this future is dropped mid-document the worker is
+                        // dropped with it, which kills the process rather than
+                        // reusing it
+                        let result = worker.parse(&path).await?;
+                        idle.lock().unwrap().push(worker);
+                        return result;
+                    },
+                );
+                return Ok(parse);
+            },
+        );
+    }
+
+    fn name(&self) -> String {
+        return self.command.clone();
+    }
+
+    fn describe(&self) -> ParserDescription {
+        return self.description.clone();
+    }
+}
+
+/// The parser used when none is configured: the `md-parse` installed
+/// alongside the running executable, or the builtin one if there isn't one.
+pub fn default_parser() -> String {
+    let installed = env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join("md-parse")))
+        .filter(|path| path.is_file());
+    return match installed {
+        Some(path) => path.to_string_lossy().into(),
+        None => BUILTIN_PARSER.into(),
+    };
+}
+
+/// Chooses a parser from the value of a `--parser` argument: either
+/// `builtin` or the path of a parser executable, which must pass the
+/// `--describe` handshake and is run with `args` before the protocol's
+/// own arguments. Executables which support streaming are kept running,
+/// with at most `workers` processes at once.
+pub async fn parser_from_arg(
+    arg: &str,
+    args: &[String],
+    workers: usize,
+) -> Result<Arc<dyn DocumentParser>, String> {
+    if arg == BUILTIN_PARSER {
+        return Ok(Arc::new(
+            BuiltinParser,
+        ));
+    }
+    let parser = SubprocessParser::connect(
+        arg.into(),
+        args.to_vec(),
+    )
+    .await?;
+    if parser.description.streaming {
+        return Ok(Arc::new(
+            StreamingParser::new(
+                parser.command,
+                parser.args,
+                parser.description,
+                workers,
+            ),
+        ));
+    }
+    return Ok(Arc::new(
+        parser,
+    ));
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A streaming parser which runs `script` with `sh` for each worker.
+    fn shell_parser(script: &str) -> StreamingParser {
+        return StreamingParser::new(
+            "sh".into(),
+            vec!["-c".into(), script.into(), "sh".into()],
+            ParserDescription::builtin(),
+            1,
+        );
+    }
+
+    #[tokio::test]
+    async fn a_worker_which_exits_reports_its_stderr() {
+        let parser = shell_parser("read path; echo \"cannot parse $path\" >&2; exit 3");
+        let error = parser.parse("/notes/todo.md".into()).await.unwrap_err();
+        assert_eq!(
+            error,
+            ParseError::Exited {
+                status: Some(3),
+                stderr: "cannot parse /notes/todo.md\n".into(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn a_worker_which_writes_garbage_reports_its_stderr() {
+        let parser =
+            shell_parser("read path; echo 'warming up' >&2; sleep 0.2; echo garbage; sleep 5");
+        let error = parser.parse("/notes/todo.md".into()).await.unwrap_err();
+        match error {
+            ParseError::InvalidOutput { stderr, .. } => assert_eq!(
+                stderr,
+                "warming up\n"
+            ),
+            _ => panic!(
+                "unexpected error: {:?}",
+                error
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_tail_of_stderr_is_kept() {
+        let parser = shell_parser(
+            "read path; head -c 20000 /dev/zero | tr '\\0' x >&2; echo end >&2; exit 1",
+        );
+        let error = parser.parse("/notes/todo.md".into()).await.unwrap_err();
+        let stderr = error.stderr().unwrap();
+        assert_eq!(
+            stderr.len(),
+            STDERR_TAIL_BYTES
+        );
+        assert!(stderr.ends_with("xxxend\n"));
+    }
+}
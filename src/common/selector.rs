@@ -0,0 +1,1121 @@
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while1};
+use nom::character::complete::{char, multispace0, multispace1, none_of};
+use nom::combinator::{eof, map, opt, value, verify};
+use nom::multi::{many0, separated_list1};
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::IResult;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common::node::{Node, NodeType};
+use crate::common::traverse::{Visitor, Walk};
+use crate::common::tree::Tree;
+
+/// A CSS-style selector as described in docs/api.md, e.g.
+/// `directory[path=notes] list_item, header[level=1]`.
+///
+/// A node matches if it matches any of the comma-separated alternatives.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub alternatives: Vec<ComplexSelector>,
+}
+
+/// A chain of compound selectors joined by combinators.
+/// The combinator stored with each part links it to the part before it,
+/// so the combinator of the first part is never used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComplexSelector {
+    pub parts: Vec<(
+        Combinator,
+        CompoundSelector,
+    )>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Combinator {
+    Descendant,
+    Child,
+}
+
+/// A node type (or `*`) followed by any number of attribute tests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompoundSelector {
+    pub node_type: Option<NodeType>,
+    pub attributes: Vec<AttributeSelector>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributeSelector {
+    pub name: String,
+    pub op: AttributeOp,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttributeOp {
+    Equals,
+    NotEquals,
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+}
+
+impl Selector {
+    pub fn parse(raw: &str) -> Result<Selector, String> {
+        match terminated(
+            selector_list,
+            pair(
+                multispace0,
+                eof,
+            ),
+        )(raw)
+        {
+            Ok((_, selector)) => return Ok(selector),
+            Err(_) => {
+                return Err(format!(
+                    "Invalid selector: {}",
+                    raw
+                ))
+            }
+        }
+    }
+
+    /// Tests the last node in `chain` against the selector.
+    /// `chain` runs from the root of the tree down to the node being tested.
+    pub fn matches(&self, chain: &[&Node]) -> bool {
+        if chain.is_empty() {
+            return false;
+        }
+        return self.alternatives.iter().any(|alt| {
+            alt.matches_at(
+                alt.parts.len() - 1,
+                chain,
+                chain.len() - 1,
+            )
+        });
+    }
+
+    /// Returns the ids of all matching nodes in pre-order.
+    pub fn select(&self, tree: &Tree) -> Vec<String> {
+        let selector = self.with_resolved_ids(tree);
+        let mut visitor = SelectVisitor {
+            selector: &selector,
+            chain: vec![],
+            selected: vec![],
+        };
+        tree.walk(
+            &tree.root_node,
+            &mut visitor,
+        );
+        return visitor.selected;
+    }
+
+    /// Whether the node or any of its descendants match the selector.
+    pub fn matches_within(&self, tree: &Tree, node_id: &str) -> bool {
+        let selector = self.with_resolved_ids(tree);
+        let mut chain = tree.ancestors(node_id);
+        chain.reverse();
+        let mut visitor = SelectVisitor {
+            selector: &selector,
+            chain,
+            selected: vec![],
+        };
+        tree.walk(
+            node_id,
+            &mut visitor,
+        );
+        return !visitor.selected.is_empty();
+    }
+}
+
+impl Selector {
+    /// Replaces virtual paths in `[id=...]` tests with the ids of the nodes
+    /// they point to in `tree`, so either can be used to pick out a node.
+    fn with_resolved_ids(&self, tree: &Tree) -> Selector {
+        let mut selector = self.clone();
+        for alternative in selector.alternatives.iter_mut() {
+            for (_, compound) in alternative.parts.iter_mut() {
+                for attribute in compound.attributes.iter_mut() {
+                    if attribute.name != "id" || tree.nodes.contains_key(&attribute.value) {
+                        continue;
+                    }
+                    if let Some(node) = tree.resolve_vpath(&attribute.value) {
+                        attribute.value = node.id.clone();
+                    }
+                }
+            }
+        }
+        return selector;
+    }
+}
+
+/// Sorts node ids by an attribute of their nodes, comparing values as
+/// `compare_values` does. A `-` before the attribute name sorts in
+/// descending order. Nodes without the attribute go last either way.
+pub fn sort_by_attribute(tree: &Tree, node_ids: &mut [String], key: &str) {
+    let (name, descending) = match key.strip_prefix('-') {
+        Some(name) => (name, true),
+        None => (key, false),
+    };
+    let mut keyed: Vec<(
+        String,
+        Option<String>,
+    )> = node_ids
+        .iter()
+        .map(
+            |node_id| {
+                let value = tree
+                    .nodes
+                    .get(node_id)
+                    .and_then(|node| attribute_value(node, name));
+                return (
+                    node_id.clone(),
+                    value,
+                );
+            },
+        )
+        .collect();
+    keyed.sort_by(
+        |(_, a), (_, b)| match (a, b) {
+            (Some(a), Some(b)) if descending => compare_values(b, a),
+            (Some(a), Some(b)) => compare_values(a, b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        },
+    );
+    for (slot, (node_id, _)) in node_ids.iter_mut().zip(keyed) {
+        *slot = node_id;
+    }
+}
+
+/// Collects matching nodes, tracking the chain of ancestors of each node.
+struct SelectVisitor<'s, 'a> {
+    selector: &'s Selector,
+    chain: Vec<&'a Node>,
+    selected: Vec<String>,
+}
+
+impl<'a> Visitor<'a> for SelectVisitor<'_, 'a> {
+    fn enter(&mut self, _tree: &'a Tree, node: &'a Node, _depth: usize) -> Walk {
+        self.chain.push(node);
+        if self.selector.matches(&self.chain) {
+            self.selected.push(node.id.clone());
+        }
+        return Walk::Continue;
+    }
+
+    fn leave(&mut self, _tree: &'a Tree, _node: &'a Node, _depth: usize) {
+        self.chain.pop();
+    }
+}
+
+impl ComplexSelector {
+    fn matches_at(&self, part: usize, chain: &[&Node], pos: usize) -> bool {
+        let (combinator, compound) = &self.parts[part];
+        if !compound.matches(chain[pos]) {
+            return false;
+        }
+        if part == 0 {
+            return true;
+        }
+        match combinator {
+            Combinator::Child => {
+                return pos > 0
+                    && self.matches_at(
+                        part - 1,
+                        chain,
+                        pos - 1,
+                    );
+            }
+            Combinator::Descendant => {
+                return (0..pos).rev().any(
+                    |ancestor| {
+                        self.matches_at(
+                            part - 1,
+                            chain,
+                            ancestor,
+                        )
+                    },
+                );
+            }
+        }
+    }
+}
+
+impl CompoundSelector {
+    pub fn matches(&self, node: &Node) -> bool {
+        if let Some(node_type) = &self.node_type {
+            if *node_type != node.node_type {
+                return false;
+            }
+        }
+        return self.attributes.iter().all(|attr| attr.matches(node));
+    }
+}
+
+impl AttributeSelector {
+    pub fn matches(&self, node: &Node) -> bool {
+        let actual = attribute_value(
+            node, &self.name,
+        );
+        let ordering = match (
+            &actual, self.op,
+        ) {
+            (_, AttributeOp::Equals) => return actual.as_deref() == Some(self.value.as_str()),
+            (_, AttributeOp::NotEquals) => return actual.as_deref() != Some(self.value.as_str()),
+            // nodes without the attribute never match a comparison
+            (None, _) => return false,
+            (Some(actual), _) => compare_values(
+                actual,
+                &self.value,
+            ),
+        };
+        match self.op {
+            AttributeOp::LessThan => return ordering == Ordering::Less,
+            AttributeOp::LessOrEqual => return ordering != Ordering::Greater,
+            AttributeOp::GreaterThan => return ordering == Ordering::Greater,
+            AttributeOp::GreaterOrEqual => return ordering != Ordering::Less,
+            AttributeOp::Equals | AttributeOp::NotEquals => unreachable!(),
+        }
+    }
+}
+
+/// Compares attribute values as numbers if both are finite numbers,
+/// otherwise as strings. `now` and `now-<n><unit>` (units `s`, `m`, `h`, `d` or `w`)
+/// are Unix timestamps, so `[modified>now-7d]` means the last week.
+pub fn compare_values(a: &str, b: &str) -> Ordering {
+    match (
+        numeric_value(a),
+        numeric_value(b),
+    ) {
+        (Some(a), Some(b)) => return a.total_cmp(&b),
+        _ => return a.cmp(b),
+    }
+}
+
+fn numeric_value(raw: &str) -> Option<f64> {
+    // `inf` and `NaN` parse as floats too, but no attribute holds them
+    if let Ok(number) = raw.parse::<f64>() {
+        return Some(number).filter(|number| number.is_finite());
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs_f64();
+    if raw == "now" {
+        return Some(now);
+    }
+    let ago = raw.strip_prefix("now-")?;
+    let unit = match ago.chars().last()? {
+        's' => 1.0,
+        'm' => 60.0,
+        'h' => 60.0 * 60.0,
+        'd' => 24.0 * 60.0 * 60.0,
+        'w' => 7.0 * 24.0 * 60.0 * 60.0,
+        _ => return None,
+    };
+    let count: f64 = ago[..ago.len() - 1].parse().ok()?;
+    return Some(now - count * unit).filter(|time| time.is_finite());
+}
+
+/// Looks up an attribute of a node as a string.
+/// `id` and `type` are common to every node; anything else is read from
+/// the fields of the node's data, including file metadata such as `size`
+/// and `modified`. `rank` is accepted as an alias of `level`.
+pub fn attribute_value(node: &Node, name: &str) -> Option<String> {
+    match name {
+        "id" => return Some(node.id.clone()),
+        "type" => {
+            return Some(type_name(
+                &node.node_type,
+            ))
+        }
+        _ => {}
+    }
+    let name = if name == "rank" { "level" } else { name };
+    let data = serde_json::to_value(&node.data).ok()?;
+    // NodeData is externally tagged, so the fields sit one level down.
+    let fields = data.as_object()?.values().next()?.as_object()?;
+    match fields.get(name)? {
+        Value::String(s) => return Some(s.clone()),
+        Value::Null => return None,
+        other => return Some(other.to_string()),
+    }
+}
+
+/// The name used for a node type in selectors, e.g. `list_item`.
+pub fn type_name(node_type: &NodeType) -> String {
+    return format!(
+        "{:?}",
+        node_type
+    )
+    .to_lowercase();
+}
+
+fn node_type(raw: &str) -> Option<NodeType> {
+    let node_type: NodeType = serde_json::from_value(Value::String(raw.to_uppercase())).ok()?;
+    if node_type == NodeType::None {
+        return None;
+    }
+    return Some(node_type);
+}
+
+fn identifier(raw: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-')(raw)
+}
+
+fn type_selector(raw: &str) -> IResult<&str, Option<NodeType>> {
+    let (stream, name) = alt((
+        tag("*"),
+        identifier,
+    ))(raw)?;
+    if name == "*" {
+        return Ok((
+            stream, None,
+        ));
+    }
+    match node_type(name) {
+        Some(node_type) => {
+            return Ok((
+                stream,
+                Some(node_type),
+            ))
+        }
+        None => {
+            return Err(
+                nom::Err::Error(
+                    nom::error::Error::new(
+                        raw,
+                        nom::error::ErrorKind::Tag,
+                    ),
+                ),
+            )
+        }
+    }
+}
+
+fn attribute_op(raw: &str) -> IResult<&str, AttributeOp> {
+    alt((
+        value(
+            AttributeOp::NotEquals,
+            tag("!="),
+        ),
+        value(
+            AttributeOp::LessOrEqual,
+            tag("<="),
+        ),
+        value(
+            AttributeOp::GreaterOrEqual,
+            tag(">="),
+        ),
+        value(
+            AttributeOp::LessThan,
+            tag("<"),
+        ),
+        value(
+            AttributeOp::GreaterThan,
+            tag(">"),
+        ),
+        value(
+            AttributeOp::Equals,
+            tag("="),
+        ),
+    ))(raw)
+}
+
+fn attribute_value_literal(raw: &str) -> IResult<&str, String> {
+    alt((
+        delimited(
+            char('"'),
+            map(
+                many0(none_of(
+                    "\"",
+                )),
+                String::from_iter,
+            ),
+            char('"'),
+        ),
+        delimited(
+            char('\''),
+            map(
+                many0(none_of(
+                    "'",
+                )),
+                String::from_iter,
+            ),
+            char('\''),
+        ),
+        // a bare value starting with a quote is an unclosed quoted one
+        map(
+            verify(
+                take_while1(|c: char| c != ']' && !c.is_whitespace()),
+                |bare: &str| !bare.starts_with(['"', '\'']),
+            ),
+            String::from,
+        ),
+    ))(raw)
+}
+
+fn attribute_selector(raw: &str) -> IResult<&str, AttributeSelector> {
+    let (stream, (_, name, _, op, _, value, _)) = delimited(
+        char('['),
+        tuple((
+            multispace0,
+            identifier,
+            multispace0,
+            attribute_op,
+            multispace0,
+            attribute_value_literal,
+            multispace0,
+        )),
+        char(']'),
+    )(raw)?;
+    return Ok((
+        stream,
+        AttributeSelector {
+            name: name.into(),
+            op,
+            value,
+        },
+    ));
+}
+
+fn compound_selector(raw: &str) -> IResult<&str, CompoundSelector> {
+    let (stream, (node_type, attributes)) = pair(
+        opt(type_selector),
+        many0(attribute_selector),
+    )(raw)?;
+    if node_type.is_none() && attributes.is_empty() {
+        return Err(
+            nom::Err::Error(
+                nom::error::Error::new(
+                    raw,
+                    nom::error::ErrorKind::Many1,
+                ),
+            ),
+        );
+    }
+    return Ok((
+        stream,
+        CompoundSelector {
+            node_type: node_type.flatten(),
+            attributes,
+        },
+    ));
+}
+
+fn combinator(raw: &str) -> IResult<&str, Combinator> {
+    alt((
+        value(
+            Combinator::Child,
+            delimited(
+                multispace0,
+                char('>'),
+                multispace0,
+            ),
+        ),
+        value(
+            Combinator::Descendant,
+            multispace1,
+        ),
+    ))(raw)
+}
+
+fn complex_selector(raw: &str) -> IResult<&str, ComplexSelector> {
+    let (stream, (first, rest)) = pair(
+        compound_selector,
+        many0(pair(
+            combinator,
+            compound_selector,
+        )),
+    )(raw)?;
+    let mut parts = vec![(
+        Combinator::Descendant,
+        first,
+    )];
+    parts.extend(rest);
+    return Ok((
+        stream,
+        ComplexSelector { parts },
+    ));
+}
+
+fn selector_list(raw: &str) -> IResult<&str, Selector> {
+    map(
+        preceded(
+            multispace0,
+            separated_list1(
+                delimited(
+                    multispace0,
+                    char(','),
+                    multispace0,
+                ),
+                complex_selector,
+            ),
+        ),
+        |alternatives| Selector { alternatives },
+    )(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::metadata::FileMetadata;
+    use crate::common::node::{FileState, ListType, NodeData};
+
+    fn node(id: &str, node_type: NodeType, data: NodeData, children: &[&str]) -> Node {
+        let mut node = Node::new(node_type);
+        node.id = id.into();
+        node.data = data;
+        node.children = children.iter().map(|child| child.to_string()).collect();
+        return node;
+    }
+
+    fn document(id: &str, size: u64, modified: u64, children: &[&str]) -> Node {
+        return node(
+            id,
+            NodeType::DOCUMENT,
+            NodeData::DocumentData {
+                path: format!(
+                    "/notes/{}.md",
+                    id
+                ),
+                state: FileState::HYDRATED,
+                canonical: String::new(),
+                virtual_path: format!(
+                    "{}.md",
+                    id
+                ),
+                metadata: FileMetadata {
+                    size,
+                    modified: Some(modified),
+                    ..FileMetadata::default()
+                },
+            },
+            children,
+        );
+    }
+
+    fn list_item(id: &str, text: &str) -> Node {
+        return node(
+            id,
+            NodeType::LIST_ITEM,
+            NodeData::ListItemData {
+                list_type: ListType::UNORDERED_LIST,
+                text: text.into(),
+                indent: 0,
+            },
+            &[],
+        );
+    }
+
+    /// notes
+    /// ├── todo.md  (120 bytes, modified a day ago)
+    /// │   ├── # Todo
+    /// │   ├── list
+    /// │   │   ├── one
+    /// │   │   └── two
+    /// │   └── Done.
+    /// └── old.md   (40 bytes, modified a month ago)
+    fn sample_tree() -> Tree {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let day = 24 * 60 * 60;
+        let mut tree = Tree::new(node(
+            "notes",
+            NodeType::DIRECTORY,
+            NodeData::DirectoryData {
+                path: "/notes".into(),
+                canonical: String::new(),
+                virtual_path: String::new(),
+                metadata: FileMetadata::default(),
+            },
+            &["todo", "old"],
+        ));
+        for node in [
+            document(
+                "todo",
+                120,
+                now - day,
+                &["title", "list", "done"],
+            ),
+            node(
+                "title",
+                NodeType::HEADER,
+                NodeData::HeaderData {
+                    text: "Todo".into(),
+                    level: 1,
+                },
+                &[],
+            ),
+            node(
+                "list",
+                NodeType::LIST,
+                NodeData::ListData {
+                    list_type: ListType::UNORDERED_LIST,
+                },
+                &["one", "two"],
+            ),
+            list_item(
+                "one", "one",
+            ),
+            list_item(
+                "two", "two",
+            ),
+            node(
+                "done",
+                NodeType::PARAGRAPH,
+                NodeData::ParagraphData {
+                    text: "Done.".into(),
+                },
+                &[],
+            ),
+            document(
+                "old",
+                40,
+                now - 30 * day,
+                &[],
+            ),
+        ] {
+            tree.nodes.insert(
+                node.id.clone(),
+                node,
+            );
+        }
+        tree.rebuild_parents();
+        return tree;
+    }
+
+    fn select(tree: &Tree, raw: &str) -> Vec<String> {
+        return Selector::parse(raw).unwrap().select(tree);
+    }
+
+    #[test]
+    fn parses_types_attributes_and_combinators() {
+        let selector = Selector::parse("directory[path=notes] > list_item[text != 'a b']").unwrap();
+        assert_eq!(
+            selector,
+            Selector {
+                alternatives: vec![ComplexSelector {
+                    parts: vec![
+                        (
+                            Combinator::Descendant,
+                            CompoundSelector {
+                                node_type: Some(NodeType::DIRECTORY),
+                                attributes: vec![AttributeSelector {
+                                    name: "path".into(),
+                                    op: AttributeOp::Equals,
+                                    value: "notes".into(),
+                                }],
+                            },
+                        ),
+                        (
+                            Combinator::Child,
+                            CompoundSelector {
+                                node_type: Some(NodeType::LIST_ITEM),
+                                attributes: vec![AttributeSelector {
+                                    name: "text".into(),
+                                    op: AttributeOp::NotEquals,
+                                    value: "a b".into(),
+                                }],
+                            },
+                        ),
+                    ],
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn parses_every_attribute_operator() {
+        for (raw, op) in [
+            (
+                "=",
+                AttributeOp::Equals,
+            ),
+            (
+                "!=",
+                AttributeOp::NotEquals,
+            ),
+            (
+                "<",
+                AttributeOp::LessThan,
+            ),
+            (
+                "<=",
+                AttributeOp::LessOrEqual,
+            ),
+            (
+                ">",
+                AttributeOp::GreaterThan,
+            ),
+            (
+                ">=",
+                AttributeOp::GreaterOrEqual,
+            ),
+        ] {
+            let selector = Selector::parse(&format!(
+                "[size{}10]",
+                raw
+            ))
+            .unwrap();
+            let compound = &selector.alternatives[0].parts[0].1;
+            assert_eq!(
+                compound.node_type,
+                None
+            );
+            assert_eq!(
+                compound.attributes[0].op, op,
+                "{}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn commas_bind_looser_than_combinators() {
+        let selector = Selector::parse(" document > list list_item ,header,* ").unwrap();
+        let lengths: Vec<usize> = selector
+            .alternatives
+            .iter()
+            .map(|alternative| alternative.parts.len())
+            .collect();
+        assert_eq!(
+            lengths,
+            [3, 1, 1]
+        );
+        let combinators: Vec<Combinator> = selector.alternatives[0]
+            .parts
+            .iter()
+            .map(|(combinator, _)| *combinator)
+            .collect();
+        assert_eq!(
+            combinators,
+            [
+                Combinator::Descendant,
+                Combinator::Child,
+                Combinator::Descendant
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_selectors() {
+        for raw in [
+            "",
+            "   ",
+            "unknown_type",
+            "none",
+            "header[",
+            "header[level]",
+            "header[level=1",
+            "header[=1]",
+            "header[text=\"Todo]",
+            "header,",
+            ", header",
+            "header >",
+            "> header",
+            "header]",
+            "header[level~=1]",
+        ] {
+            assert!(
+                Selector::parse(raw).is_err(),
+                "{:?}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn selects_by_type_in_pre_order() {
+        let tree = sample_tree();
+        assert_eq!(
+            select(
+                &tree,
+                "list_item"
+            ),
+            ["one", "two"]
+        );
+        assert_eq!(
+            select(&tree, "*").len(),
+            tree.nodes.len()
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "paragraph, header"
+            ),
+            ["title", "done"]
+        );
+    }
+
+    #[test]
+    fn selects_descendants_and_children() {
+        let tree = sample_tree();
+        assert_eq!(
+            select(
+                &tree,
+                "directory list_item"
+            ),
+            ["one", "two"]
+        );
+        assert!(select(
+            &tree,
+            "directory > list_item"
+        )
+        .is_empty());
+        assert_eq!(
+            select(
+                &tree,
+                "document > list > list_item"
+            ),
+            ["one", "two"]
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "document[size=120] paragraph, list > list_item[text=two]"
+            ),
+            ["two", "done"]
+        );
+    }
+
+    #[test]
+    fn selects_by_attribute() {
+        let tree = sample_tree();
+        assert_eq!(
+            select(
+                &tree,
+                "header[text=\"Todo\"]"
+            ),
+            ["title"]
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "header[rank=1]"
+            ),
+            ["title"]
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "list_item[text!=two]"
+            ),
+            ["one"]
+        );
+        // nodes without the attribute aren't equal to anything
+        assert_eq!(
+            select(
+                &tree,
+                "[text!=two]"
+            )
+            .len(),
+            tree.nodes.len() - 1
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "[type=list_item][text=one]"
+            ),
+            ["one"]
+        );
+    }
+
+    #[test]
+    fn ids_can_be_virtual_paths() {
+        let tree = sample_tree();
+        assert_eq!(
+            select(
+                &tree,
+                "[id=old.md]"
+            ),
+            ["old"]
+        );
+        assert_eq!(
+            select(&tree, "[id=old]"),
+            ["old"]
+        );
+    }
+
+    #[test]
+    fn matches_within_tests_the_node_and_its_descendants() {
+        let tree = sample_tree();
+        let selector = Selector::parse("directory list_item[text=one]").unwrap();
+        assert!(selector.matches_within(&tree, "todo"));
+        assert!(selector.matches_within(&tree, "one"));
+        assert!(!selector.matches_within(&tree, "old"));
+        assert!(!selector.matches_within(&tree, "two"));
+    }
+
+    #[test]
+    fn compares_numbers_as_numbers() {
+        let tree = sample_tree();
+        assert_eq!(
+            select(
+                &tree,
+                "document[size<120]"
+            ),
+            ["old"]
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "document[size<=120]"
+            ),
+            ["todo", "old"]
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "document[size>40]"
+            ),
+            ["todo"]
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "document[size>=40]"
+            ),
+            ["todo", "old"]
+        );
+        // as strings, "120" would sort before "9"
+        assert_eq!(
+            select(
+                &tree,
+                "document[size>9]"
+            ),
+            ["todo", "old"]
+        );
+    }
+
+    #[test]
+    fn compares_dates_relative_to_now() {
+        let tree = sample_tree();
+        assert_eq!(
+            select(
+                &tree,
+                "document[modified>now-7d]"
+            ),
+            ["todo"]
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "document[modified<now-2w]"
+            ),
+            ["old"]
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "document[modified>=now-36h]"
+            ),
+            ["todo"]
+        );
+        assert_eq!(
+            select(
+                &tree,
+                "document[modified<=now]"
+            ),
+            ["todo", "old"]
+        );
+        // an unknown unit isn't a date, so it's compared as a string
+        assert_eq!(
+            compare_values("now-1y", "now-1d"),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn compares_mixed_values_as_strings() {
+        assert_eq!(
+            compare_values("10", "9"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_values("10", "9a"),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_values("Todo", "Uo"),
+            Ordering::Less
+        );
+        let tree = sample_tree();
+        assert_eq!(
+            select(
+                &tree,
+                "header[text<Uo]"
+            ),
+            ["title"]
+        );
+        // nodes without the attribute never match a comparison
+        assert_eq!(
+            select(
+                &tree,
+                "[size>=0]"
+            ),
+            ["notes", "todo", "old"]
+        );
+    }
+
+    #[test]
+    fn non_finite_numbers_arent_numeric() {
+        for raw in [
+            "inf", "-inf", "infinity", "NaN", "1e999", "now-infd", "now-NaNh",
+        ] {
+            assert_eq!(
+                numeric_value(raw),
+                None,
+                "{}",
+                raw
+            );
+        }
+        assert_eq!(
+            numeric_value("1.5"),
+            Some(1.5)
+        );
+        assert_eq!(
+            compare_values("NaN", "1"),
+            "NaN".cmp("1")
+        );
+    }
+
+    #[test]
+    fn sorts_by_attribute_with_missing_values_last() {
+        let tree = sample_tree();
+        let sorted = |key: &str| {
+            let mut ids: Vec<String> = ["title", "todo", "done", "old"]
+                .iter()
+                .map(|id| id.to_string())
+                .collect();
+            sort_by_attribute(
+                &tree, &mut ids, key,
+            );
+            return ids;
+        };
+        assert_eq!(
+            sorted("size"),
+            ["old", "todo", "title", "done"]
+        );
+        assert_eq!(
+            sorted("-size"),
+            ["todo", "old", "title", "done"]
+        );
+        assert_eq!(
+            sorted("-modified"),
+            ["todo", "old", "title", "done"]
+        );
+        // the sort is stable, so nodes without the attribute keep their order
+        assert_eq!(
+            sorted("missing"),
+            ["title", "todo", "done", "old"]
+        );
+    }
+}
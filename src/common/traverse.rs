@@ -0,0 +1,536 @@
+use std::collections::VecDeque;
+
+use crate::common::node::{Node, NodeType};
+use crate::common::tree::Tree;
+
+/// What a `Visitor` wants to happen after entering a node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Walk {
+    Continue,
+    /// Don't visit the node's children. `leave` is still called for it.
+    SkipChildren,
+    /// End the walk without calling `leave` for any open nodes.
+    Stop,
+}
+
+/// Hooks called by `Tree::walk` in pre-order (`enter`) and post-order
+/// (`leave`). `depth` is relative to the node the walk started from.
+/// Visitors may hold on to nodes for the lifetime `'a` of the tree.
+pub trait Visitor<'a> {
+    fn enter(&mut self, _tree: &'a Tree, _node: &'a Node, _depth: usize) -> Walk {
+        return Walk::Continue;
+    }
+
+    fn leave(&mut self, _tree: &'a Tree, _node: &'a Node, _depth: usize) {}
+}
+
+/// Depth-first iterator yielding each node before its children.
+pub struct PreOrder<'a> {
+    tree: &'a Tree,
+    stack: Vec<(
+        &'a str,
+        usize,
+    )>,
+}
+
+impl<'a> Iterator for PreOrder<'a> {
+    type Item = (
+        usize,
+        &'a Node,
+    );
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node_id, depth)) = self.stack.pop() {
+            // ids without a node are skipped rather than ending the walk
+            let node = match self.tree.nodes.get(node_id) {
+                Some(node) => node,
+                None => continue,
+            };
+            for child in node.children.iter().rev() {
+                self.stack.push((
+                    child,
+                    depth + 1,
+                ));
+            }
+            return Some((
+                depth, node,
+            ));
+        }
+        return None;
+    }
+}
+
+/// Depth-first iterator yielding each node after its children.
+pub struct PostOrder<'a> {
+    tree: &'a Tree,
+    /// Nodes paired with whether their children have been pushed yet.
+    stack: Vec<(
+        &'a Node,
+        usize,
+        bool,
+    )>,
+}
+
+impl<'a> Iterator for PostOrder<'a> {
+    type Item = (
+        usize,
+        &'a Node,
+    );
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, depth, expanded)) = self.stack.pop() {
+            if expanded {
+                return Some((
+                    depth, node,
+                ));
+            }
+            self.stack.push((
+                node, depth, true,
+            ));
+            for child in node.children.iter().rev() {
+                if let Some(child) = self.tree.nodes.get(child) {
+                    self.stack.push((
+                        child,
+                        depth + 1,
+                        false,
+                    ));
+                }
+            }
+        }
+        return None;
+    }
+}
+
+/// Iterator yielding nodes level by level.
+pub struct BreadthFirst<'a> {
+    tree: &'a Tree,
+    queue: VecDeque<(
+        &'a str,
+        usize,
+    )>,
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = (
+        usize,
+        &'a Node,
+    );
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node_id, depth)) = self.queue.pop_front() {
+            let node = match self.tree.nodes.get(node_id) {
+                Some(node) => node,
+                None => continue,
+            };
+            for child in node.children.iter() {
+                self.queue.push_back((
+                    child,
+                    depth + 1,
+                ));
+            }
+            return Some((
+                depth, node,
+            ));
+        }
+        return None;
+    }
+}
+
+impl Tree {
+    /// Iterates over `start` and its descendants, parents before children.
+    /// Yields each node with its depth relative to `start`.
+    pub fn pre_order<'a>(&'a self, start: &'a str) -> PreOrder<'a> {
+        return PreOrder {
+            tree: self,
+            stack: vec![(start, 0)],
+        };
+    }
+
+    /// Iterates over `start` and its descendants, children before parents.
+    pub fn post_order<'a>(&'a self, start: &'a str) -> PostOrder<'a> {
+        let stack = match self.nodes.get(start) {
+            Some(node) => vec![(
+                node, 0, false,
+            )],
+            None => vec![],
+        };
+        return PostOrder { tree: self, stack };
+    }
+
+    /// Iterates over `start` and its descendants one level at a time.
+    pub fn breadth_first<'a>(&'a self, start: &'a str) -> BreadthFirst<'a> {
+        return BreadthFirst {
+            tree: self,
+            queue: VecDeque::from([(start, 0)]),
+        };
+    }
+
+    /// Pre-order iteration over the nodes of one type under `start`.
+    pub fn nodes_of_type<'a>(
+        &'a self,
+        start: &'a str,
+        node_type: NodeType,
+    ) -> impl Iterator<Item = &'a Node> {
+        return self
+            .pre_order(start)
+            .map(|(_, node)| node)
+            .filter(move |node| node.node_type == node_type);
+    }
+
+    /// Walks `start` and its descendants depth-first, calling the visitor's
+    /// `enter` before a node's children and `leave` after them.
+    pub fn walk<'a>(&'a self, start: &'a str, visitor: &mut impl Visitor<'a>) {
+        // the bool is whether we're leaving the node
+        let mut stack: Vec<(
+            &'a str,
+            usize,
+            bool,
+        )> = vec![(
+            start, 0, false,
+        )];
+        while let Some((node_id, depth, leaving)) = stack.pop() {
+            let node = match self.nodes.get(node_id) {
+                Some(node) => node,
+                None => continue,
+            };
+            if leaving {
+                visitor.leave(
+                    self, node, depth,
+                );
+                continue;
+            }
+            let walk = visitor.enter(
+                self, node, depth,
+            );
+            if walk == Walk::Stop {
+                return;
+            }
+            stack.push((
+                node_id, depth, true,
+            ));
+            if walk == Walk::SkipChildren {
+                continue;
+            }
+            for child in node.children.iter().rev() {
+                stack.push((
+                    child,
+                    depth + 1,
+                    false,
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// root
+    /// ├── a
+    /// │   ├── a1
+    /// │   │   └── a1x
+    /// │   └── a2
+    /// └── b
+    ///     └── b1
+    fn sample_tree() -> Tree {
+        let node = |id: &str, children: &[&str]| {
+            let mut node = Node::new(NodeType::PARAGRAPH);
+            node.id = id.into();
+            node.children = children.iter().map(|child| child.to_string()).collect();
+            return node;
+        };
+        let mut tree = Tree::new(node(
+            "root",
+            &["a", "b"],
+        ));
+        for node in [
+            node(
+                "a",
+                &["a1", "a2"],
+            ),
+            node(
+                "a1",
+                &["a1x"],
+            ),
+            node("a1x", &[]),
+            node("a2", &[]),
+            node(
+                "b",
+                &["b1"],
+            ),
+            node("b1", &[]),
+        ] {
+            tree.nodes.insert(
+                node.id.clone(),
+                node,
+            );
+        }
+        tree.rebuild_parents();
+        return tree;
+    }
+
+    fn ids<'a>(
+        nodes: impl Iterator<
+            Item = (
+                usize,
+                &'a Node,
+            ),
+        >,
+    ) -> Vec<(
+        usize,
+        &'a str,
+    )> {
+        return nodes
+            .map(
+                |(depth, node)| {
+                    (
+                        depth,
+                        node.id.as_str(),
+                    )
+                },
+            )
+            .collect();
+    }
+
+    /// Records `enter` and `leave` calls, skipping the children of `skip`
+    /// and stopping at `stop`.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+        skip: &'static str,
+        stop: &'static str,
+    }
+
+    impl<'a> Visitor<'a> for Recorder {
+        fn enter(&mut self, _tree: &'a Tree, node: &'a Node, depth: usize) -> Walk {
+            self.calls.push(format!(
+                "enter {} {}",
+                node.id, depth
+            ));
+            if node.id == self.stop {
+                return Walk::Stop;
+            }
+            if node.id == self.skip {
+                return Walk::SkipChildren;
+            }
+            return Walk::Continue;
+        }
+
+        fn leave(&mut self, _tree: &'a Tree, node: &'a Node, depth: usize) {
+            self.calls.push(format!(
+                "leave {} {}",
+                node.id, depth
+            ));
+        }
+    }
+
+    #[test]
+    fn pre_order_visits_parents_first() {
+        let tree = sample_tree();
+        assert_eq!(
+            ids(tree.pre_order("root")),
+            [
+                (0, "root"),
+                (1, "a"),
+                (2, "a1"),
+                (3, "a1x"),
+                (2, "a2"),
+                (1, "b"),
+                (2, "b1")
+            ]
+        );
+    }
+
+    #[test]
+    fn post_order_visits_children_first() {
+        let tree = sample_tree();
+        assert_eq!(
+            ids(tree.post_order("root")),
+            [
+                (3, "a1x"),
+                (2, "a1"),
+                (2, "a2"),
+                (1, "a"),
+                (2, "b1"),
+                (1, "b"),
+                (0, "root")
+            ]
+        );
+    }
+
+    #[test]
+    fn breadth_first_visits_level_by_level() {
+        let tree = sample_tree();
+        assert_eq!(
+            ids(tree.breadth_first("root")),
+            [
+                (0, "root"),
+                (1, "a"),
+                (1, "b"),
+                (2, "a1"),
+                (2, "a2"),
+                (2, "b1"),
+                (3, "a1x")
+            ]
+        );
+    }
+
+    #[test]
+    fn depths_are_relative_to_the_start() {
+        let tree = sample_tree();
+        assert_eq!(
+            ids(tree.pre_order("a")),
+            [(0, "a"), (1, "a1"), (2, "a1x"), (1, "a2")]
+        );
+        assert_eq!(
+            ids(tree.post_order("a")),
+            [(2, "a1x"), (1, "a1"), (1, "a2"), (0, "a")]
+        );
+        assert_eq!(
+            ids(tree.breadth_first("a")),
+            [(0, "a"), (1, "a1"), (1, "a2"), (2, "a1x")]
+        );
+        let mut recorder = Recorder::default();
+        tree.walk(
+            "b",
+            &mut recorder,
+        );
+        assert_eq!(
+            recorder.calls,
+            ["enter b 0", "enter b1 1", "leave b1 1", "leave b 0"]
+        );
+    }
+
+    #[test]
+    fn missing_nodes_are_skipped() {
+        let mut tree = sample_tree();
+        tree.nodes.get_mut("a").unwrap().children.insert(
+            1,
+            "gone".into(),
+        );
+        assert_eq!(
+            ids(tree.pre_order("a")),
+            [(0, "a"), (1, "a1"), (2, "a1x"), (1, "a2")]
+        );
+        assert_eq!(
+            ids(tree.post_order("a")),
+            [(2, "a1x"), (1, "a1"), (1, "a2"), (0, "a")]
+        );
+        assert_eq!(
+            ids(tree.breadth_first("a")),
+            [(0, "a"), (1, "a1"), (1, "a2"), (2, "a1x")]
+        );
+        assert_eq!(
+            tree.pre_order("gone").count(),
+            0
+        );
+        assert_eq!(
+            tree.post_order("gone").count(),
+            0
+        );
+        assert_eq!(
+            tree.breadth_first("gone").count(),
+            0
+        );
+    }
+
+    #[test]
+    fn walk_leaves_each_node_after_its_children() {
+        let tree = sample_tree();
+        let mut recorder = Recorder::default();
+        tree.walk(
+            "root",
+            &mut recorder,
+        );
+        assert_eq!(
+            recorder.calls,
+            [
+                "enter root 0",
+                "enter a 1",
+                "enter a1 2",
+                "enter a1x 3",
+                "leave a1x 3",
+                "leave a1 2",
+                "enter a2 2",
+                "leave a2 2",
+                "leave a 1",
+                "enter b 1",
+                "enter b1 2",
+                "leave b1 2",
+                "leave b 1",
+                "leave root 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_skips_children_but_still_leaves() {
+        let tree = sample_tree();
+        let mut recorder = Recorder {
+            skip: "a",
+            ..Default::default()
+        };
+        tree.walk(
+            "root",
+            &mut recorder,
+        );
+        assert_eq!(
+            recorder.calls,
+            [
+                "enter root 0",
+                "enter a 1",
+                "leave a 1",
+                "enter b 1",
+                "enter b1 2",
+                "leave b1 2",
+                "leave b 1",
+                "leave root 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_stops_without_leaving_open_nodes() {
+        let tree = sample_tree();
+        let mut recorder = Recorder {
+            stop: "a2",
+            ..Default::default()
+        };
+        tree.walk(
+            "root",
+            &mut recorder,
+        );
+        assert_eq!(
+            recorder.calls,
+            [
+                "enter root 0",
+                "enter a 1",
+                "enter a1 2",
+                "enter a1x 3",
+                "leave a1x 3",
+                "leave a1 2",
+                "enter a2 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn nodes_of_type_filters_in_pre_order() {
+        let mut tree = sample_tree();
+        for id in ["a1x", "b"] {
+            tree.nodes.get_mut(id).unwrap().node_type = NodeType::HEADER;
+        }
+        let headers: Vec<&str> = tree
+            .nodes_of_type(
+                "root",
+                NodeType::HEADER,
+            )
+            .map(|node| node.id.as_str())
+            .collect();
+        assert_eq!(
+            headers,
+            ["a1x", "b"]
+        );
+    }
+}
@@ -1,17 +1,132 @@
 use serde::{Deserialize, Serialize};
 
-use crate::common::node::{Node, NodeData, NodeType};
-use std::collections::{HashMap, VecDeque};
-use std::path::Path;
-use std::time::Instant;
-use tokio::process::Command;
+use crate::common::cache::{Fingerprint, ParseCache};
+use crate::common::ignore_rules::{IgnoreOptions, IgnoreRules};
+use crate::common::metadata::FileMetadata;
+use crate::common::node::{FileState, Node, NodeData, NodeType};
+use crate::common::parser::{DocumentParser, ParseError, StartFuture, PROTOCOL_VERSION};
+use crate::common::selector::Selector;
+use crate::common::traverse::{Visitor, Walk};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use tokio::task::JoinSet;
 
+/// Controls how documents are parsed when hydrating a tree.
+#[derive(Clone)]
+pub struct HydrateOptions {
+    pub parser: Arc<dyn DocumentParser>,
+    /// The maximum number of parsers to run at once.
+    pub processes: usize,
+    /// How long a parser may spend on one document before it's killed.
+    pub timeout: Duration,
+    /// Where parsed documents are kept between runs, unless disabled.
+    pub cache: Option<Arc<ParseCache>>,
+}
+
+/// Controls which files are added to a tree when it's loaded.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    pub follow_symlinks: bool,
+    pub ignore: IgnoreOptions,
+    /// Extensions, without the `.`, of files to parse as documents. Other
+    /// files become attachments.
+    pub document_extensions: Vec<String>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        return LoadOptions {
+            follow_symlinks: false,
+            ignore: IgnoreOptions::default(),
+            document_extensions: vec!["md".into()],
+        };
+    }
+}
+
+impl LoadOptions {
+    pub fn is_document(&self, path: &Path) -> bool {
+        return path.extension().is_some_and(|ext| {
+            self.document_extensions
+                .iter()
+                .any(|doc_ext| ext.eq_ignore_ascii_case(doc_ext.as_str()))
+        });
+    }
+}
+
+/// How much of a tree is in memory.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum TreeState {
+    /// Only the name and path of the tree are known.
+    #[default]
+    UNLOADED,
+    /// The tree's directories are being walked.
+    LOADING_METADATA,
+    /// Every file is known, but no documents are hydrated.
+    LOADED_METADATA_ONLY,
+    /// Some documents are hydrated.
+    LOADED_PARTIAL,
+    /// Every document is hydrated.
+    LOADED_FULL,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SerializedTree")]
 pub struct Tree {
+    /// The version of the parser protocol this tree was serialized with.
+    pub schema_version: u32,
     pub name: String,
+    pub state: TreeState,
     pub root_node: String,
     pub nodes: HashMap<String, Node>,
+    /// Documents which failed to load, by node id.
+    pub failures: HashMap<String, DocumentFailure>,
+    /// Maps each node id to its parent's id. The root has no entry.
+    /// This isn't serialized as it can be rebuilt from `nodes`.
+    #[serde(skip)]
+    pub parents: HashMap<String, String>,
+}
+
+/// Why a document couldn't be loaded into the tree.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentFailure {
+    pub path: String,
+    pub error: ParseError,
+}
+
+/// The serialized form of a tree, which has no parent index.
+#[derive(Deserialize)]
+struct SerializedTree {
+    /// Trees from before the protocol was versioned have no schema version.
+    #[serde(default)]
+    schema_version: u32,
+    name: String,
+    #[serde(default)]
+    state: TreeState,
+    root_node: String,
+    nodes: HashMap<String, Node>,
+    #[serde(default)]
+    failures: HashMap<String, DocumentFailure>,
+}
+
+impl From<SerializedTree> for Tree {
+    fn from(serialized: SerializedTree) -> Self {
+        let mut tree = Tree {
+            schema_version: serialized.schema_version,
+            name: serialized.name,
+            state: serialized.state,
+            root_node: serialized.root_node,
+            nodes: serialized.nodes,
+            failures: serialized.failures,
+            parents: HashMap::new(),
+        };
+        tree.rebuild_parents();
+        return tree;
+    }
 }
 
 impl Tree {
@@ -27,9 +142,63 @@ impl Tree {
         );
 
         return Tree {
+            schema_version: PROTOCOL_VERSION,
             name: String::new(),
+            state: TreeState::UNLOADED,
             root_node: root_id.clone(),
             nodes,
+            failures: HashMap::new(),
+            parents: HashMap::new(),
+        };
+    }
+
+    /// A tree which hasn't been walked yet, with just a root directory,
+    /// to stand in for the tree `load` will return.
+    pub fn unloaded(name: String, path: String) -> Tree {
+        let mut root = Node::new(NodeType::DIRECTORY);
+        root.id = Node::stable_id(&[&name, ""]);
+        root.data = NodeData::DirectoryData {
+            path,
+            canonical: String::new(),
+            virtual_path: String::new(),
+            metadata: FileMetadata::default(),
+        };
+        let mut tree = Tree::new(root);
+        tree.name = name;
+        return tree;
+    }
+
+    /// The number of documents in the tree, and how many are hydrated.
+    pub fn hydration_counts(
+        &self,
+    ) -> (
+        usize,
+        usize,
+    ) {
+        let mut documents = 0;
+        let mut hydrated = 0;
+        for node in self.nodes.values() {
+            if let NodeData::DocumentData { state, .. } = &node.data {
+                documents += 1;
+                if *state == FileState::HYDRATED {
+                    hydrated += 1;
+                }
+            }
+        }
+        return (
+            documents, hydrated,
+        );
+    }
+
+    /// Works out the state of a walked tree from its documents.
+    pub fn refresh_state(&mut self) {
+        let (documents, hydrated) = self.hydration_counts();
+        self.state = if hydrated == documents {
+            TreeState::LOADED_FULL
+        } else if hydrated == 0 {
+            TreeState::LOADED_METADATA_ONLY
+        } else {
+            TreeState::LOADED_PARTIAL
         };
     }
 
@@ -47,6 +216,134 @@ impl Tree {
         return self.nodes.get_mut(&node_id);
     }
 
+    /// Recomputes the parent index from the children of every node.
+    pub fn rebuild_parents(&mut self) {
+        self.parents.clear();
+        for (node_id, node) in self.nodes.iter() {
+            for child_id in node.children.iter() {
+                self.parents.insert(
+                    child_id.clone(),
+                    node_id.clone(),
+                );
+            }
+        }
+    }
+
+    /// Points the parent index at `node_id` for each of its children.
+    fn index_children(&mut self, node_id: &str) {
+        let children = match self.nodes.get(node_id) {
+            Some(node) => node.children.clone(),
+            None => return,
+        };
+        for child_id in children {
+            self.parents.insert(
+                child_id,
+                node_id.into(),
+            );
+        }
+    }
+
+    pub fn get_parent(&self, node_id: &str) -> Option<&Node> {
+        let parent_id = self.parents.get(node_id)?;
+        return self.nodes.get(parent_id);
+    }
+
+    /// Returns the ancestors of a node, starting with its parent and
+    /// ending with the root.
+    pub fn ancestors(&self, node_id: &str) -> Vec<&Node> {
+        let mut ancestors = vec![];
+        let mut cur_id = node_id;
+        while let Some(parent) = self.get_parent(cur_id) {
+            ancestors.push(parent);
+            cur_id = &parent.id;
+        }
+        return ancestors;
+    }
+
+    /// Returns the nodes from the root down to `node_id`, inclusive.
+    pub fn ancestry(&self, node_id: &str) -> Option<Vec<&Node>> {
+        let node = self.nodes.get(node_id)?;
+        let mut chain = self.ancestors(node_id);
+        chain.reverse();
+        chain.push(node);
+        return Some(chain);
+    }
+
+    /// Returns the document containing a node, or the node itself if it's
+    /// a document.
+    pub fn document_of(&self, node_id: &str) -> Option<&Node> {
+        let node = self.nodes.get(node_id)?;
+        if node.node_type == NodeType::DOCUMENT {
+            return Some(node);
+        }
+        return self
+            .ancestors(node_id)
+            .into_iter()
+            .find(|ancestor| ancestor.node_type == NodeType::DOCUMENT);
+    }
+
+    /// Returns the number of ancestors of a node, so the root has depth 0.
+    pub fn depth(&self, node_id: &str) -> Option<usize> {
+        if !self.nodes.contains_key(node_id) {
+            return None;
+        }
+        return Some(self.ancestors(node_id).len());
+    }
+
+    /// Gives the root the id `root_id` and every other node an id derived
+    /// from its position, so parsing the same content twice gives the same ids.
+    pub fn stabilise_ids(&mut self, root_id: String) {
+        let old_root_id = self.root_node.clone();
+        if let Some(mut root) = self.nodes.remove(&old_root_id) {
+            root.id = root_id.clone();
+            self.nodes.insert(
+                root_id.clone(),
+                root,
+            );
+            self.root_node = root_id.clone();
+        }
+        self.stabilise_ids_under(&root_id);
+    }
+
+    /// Re-keys every descendant of `node_id` with an id derived from its
+    /// parent's id, its index among its siblings and its type.
+    pub fn stabilise_ids_under(&mut self, node_id: &str) {
+        let mut queue: VecDeque<String> = VecDeque::new();
+        queue.push_back(node_id.into());
+        while let Some(parent_id) = queue.pop_front() {
+            let children = match self.nodes.get(&parent_id) {
+                Some(parent) => parent.children.clone(),
+                None => continue,
+            };
+            let mut new_children = vec![];
+            for (idx, child_id) in children.iter().enumerate() {
+                let mut child = match self.nodes.remove(child_id) {
+                    Some(child) => child,
+                    None => continue,
+                };
+                self.parents.remove(child_id);
+                child.id = Node::stable_id(&[
+                    &parent_id,
+                    &idx.to_string(),
+                    &format!(
+                        "{:?}",
+                        child.node_type
+                    ),
+                ]);
+                new_children.push(child.id.clone());
+                queue.push_back(child.id.clone());
+                self.nodes.insert(
+                    child.id.clone(),
+                    child,
+                );
+            }
+            if let Some(parent) = self.nodes.get_mut(&parent_id) {
+                parent.children = new_children;
+            }
+            self.index_children(&parent_id);
+        }
+    }
+
     /// Insert the root of the subtree as a child node of the target
     /// node and move all nodes to this tree.
     /// Note that this moves the subtree, which is fine as we're usually
@@ -57,11 +354,16 @@ impl Tree {
             if node_id == child_root_id {
                 let target_node = self.get_node_mut(target_id.clone()).unwrap();
                 target_node.children.push(child_root_id.clone());
+                self.parents.insert(
+                    child_root_id.clone(),
+                    target_id.clone(),
+                );
             }
             self.nodes.insert(
                 node_id.clone(),
                 node,
             );
+            self.index_children(&node_id);
         }
     }
 
@@ -78,175 +380,1919 @@ impl Tree {
                 }
                 target_node.data = node.data;
                 target_node.children = node.children;
+                self.index_children(&target_id);
             } else {
                 // we don't need to copy the root of the subtree
                 self.nodes.insert(
-                    node_id, node,
+                    node_id.clone(),
+                    node,
                 );
+                self.index_children(&node_id);
             }
         }
         return Ok(());
     }
 
-    /// Loads the
-    pub async fn load(name: String, path: String, traverse_symbolic: bool) -> Option<Tree> {
-        let mut root_node: Node = Node::new(NodeType::DIRECTORY);
+    /// Drops the parsed contents of a document from memory, leaving the
+    /// document node itself in place to be loaded again later.
+    pub fn unload_document(&mut self, node_id: &str) -> Result<(), &str> {
+        let document = match self.nodes.get(node_id) {
+            Some(node) => node,
+            None => return Err("No node with this id."),
+        };
+        if document.node_type != NodeType::DOCUMENT {
+            return Err("Node is not a document.");
+        }
+        let descendants: Vec<String> = self
+            .pre_order(node_id)
+            .skip(1)
+            .map(|(_, node)| node.id.clone())
+            .collect();
+        for descendant in descendants {
+            self.nodes.remove(&descendant);
+            self.parents.remove(&descendant);
+        }
+
+        let document = self.nodes.get_mut(node_id).unwrap();
+        document.children.clear();
+        if let NodeData::DocumentData { state, .. } = &mut document.data {
+            *state = FileState::DEHYDRATED;
+        }
+        self.refresh_state();
+        return Ok(());
+    }
+
+    /// Copies the parsed contents of the document `node_id` from `other`,
+    /// an earlier tree of the same files such as one from a snapshot.
+    /// The document keeps its own size and times, taking only what
+    /// parsing worked out from `other`.
+    pub fn copy_document_from(&mut self, other: &Tree, node_id: &str) -> Result<(), &str> {
+        let (parsed_metadata, children) = match other.nodes.get(node_id) {
+            Some(Node {
+                data:
+                    NodeData::DocumentData {
+                        state: FileState::HYDRATED,
+                        metadata,
+                        ..
+                    },
+                children,
+                ..
+            }) => (
+                metadata.clone(),
+                children.clone(),
+            ),
+            _ => return Err("No loaded document with this id in the other tree."),
+        };
+        match self.nodes.get(node_id) {
+            Some(node) if node.node_type == NodeType::DOCUMENT => {}
+            _ => return Err("No document with this id."),
+        }
+        for (_, node) in other.pre_order(node_id).skip(1) {
+            self.nodes.insert(
+                node.id.clone(),
+                node.clone(),
+            );
+            self.index_children(&node.id);
+        }
+
+        let document = self.nodes.get_mut(node_id).unwrap();
+        document.children = children;
+        if let NodeData::DocumentData {
+            state, metadata, ..
+        } = &mut document.data
+        {
+            *state = FileState::HYDRATED;
+            metadata.word_count = parsed_metadata.word_count;
+            metadata.line_count = parsed_metadata.line_count;
+            metadata.hash = parsed_metadata.hash;
+        }
+        self.index_children(node_id);
+        return Ok(());
+    }
+
+    /// Unloads every loaded document matched by the selector or inside a
+    /// matched node (e.g. a directory), returning the ids of the documents.
+    pub fn unload_documents(&mut self, selector: &Selector) -> Vec<String> {
+        let mut to_unload = vec![];
+        let mut seen = HashSet::new();
+        for selected in selector.select(self) {
+            for document in self.nodes_of_type(
+                &selected,
+                NodeType::DOCUMENT,
+            ) {
+                let hydrated = matches!(
+                    document.data,
+                    NodeData::DocumentData {
+                        state: FileState::HYDRATED,
+                        ..
+                    }
+                );
+                if hydrated && seen.insert(document.id.clone()) {
+                    to_unload.push(document.id.clone());
+                }
+            }
+        }
+        for document_id in to_unload.iter() {
+            // these were all checked to be documents above
+            let _ = self.unload_document(document_id);
+        }
+        return to_unload;
+    }
+
+    /// Removes a node and everything under it from the tree, e.g. a
+    /// document whose file was deleted.
+    pub fn remove_subtree(&mut self, node_id: &str) -> Result<(), &str> {
+        if !self.nodes.contains_key(node_id) {
+            return Err("No node with this id.");
+        }
+        if node_id == self.root_node {
+            return Err("The root can't be removed.");
+        }
+        let removed: Vec<String> = self
+            .pre_order(node_id)
+            .map(|(_, node)| node.id.clone())
+            .collect();
+        if let Some(parent_id) = self.parents.get(node_id).cloned() {
+            if let Some(parent) = self.nodes.get_mut(&parent_id) {
+                parent.children.retain(|child| child != node_id);
+            }
+        }
+        for removed_id in removed {
+            self.nodes.remove(&removed_id);
+            self.parents.remove(&removed_id);
+            self.failures.remove(&removed_id);
+        }
+        self.total_directory_sizes();
+        self.refresh_state();
+        return Ok(());
+    }
+
+    /// Adds the document at `path`, somewhere under the tree's root, along
+    /// with any directories between them which aren't in the tree yet.
+    /// The document is left dehydrated. Returns its id, which is the same
+    /// as if the tree had been loaded with it, or `None` if `path` isn't a
+    /// document under the root or the ignore rules skip it.
+    pub fn add_document(&mut self, path: &Path, options: &LoadOptions) -> Option<String> {
+        let (root_path, root_canonical) = match &self.get_root().data {
+            NodeData::DirectoryData {
+                path, canonical, ..
+            } => (
+                PathBuf::from(path),
+                PathBuf::from(canonical),
+            ),
+            _ => return None,
+        };
+        if !path.is_file() || !options.is_document(path) {
+            return None;
+        }
+        let canonical = fs::canonicalize(path).ok()?;
+        let rel_path = canonical.strip_prefix(&root_canonical).ok()?.to_path_buf();
+        let components: Vec<Component> = rel_path.components().collect();
+
+        let mut rules = IgnoreRules::new(
+            &root_path,
+            &options.ignore,
+        )
+        .ok()?
+        .for_directory(&root_path);
+        let mut parent_id = self.root_node.clone();
+        let mut parent_rel = PathBuf::new();
+        for (i, component) in components.iter().enumerate() {
+            let is_document = i + 1 == components.len();
+            let child_rel = parent_rel.join(component);
+            let child_path = root_path.join(&child_rel);
+            if rules.is_ignored(
+                &child_path,
+                !is_document,
+            ) {
+                return None;
+            }
+            let child_id = Node::stable_id(&[&self.name, child_rel.to_str()?]);
+            if !self.nodes.contains_key(&child_id) {
+                let node = file_node(
+                    child_id.clone(),
+                    &child_path,
+                    child_rel.to_str()?.into(),
+                    options,
+                )?;
+                self.nodes.insert(
+                    child_id.clone(),
+                    node,
+                );
+                self.nodes
+                    .get_mut(&parent_id)?
+                    .children
+                    .push(child_id.clone());
+                self.parents.insert(
+                    child_id.clone(),
+                    parent_id.clone(),
+                );
+            }
+            if !is_document {
+                rules = rules.for_directory(&child_path);
+            }
+            parent_id = child_id;
+            parent_rel = child_rel;
+        }
+        self.total_directory_sizes();
+        self.refresh_state();
+        return Some(parent_id);
+    }
+
+    /// Parses the document `node_id` again, e.g. after it was saved, in
+    /// place of whatever was parsed from it before.
+    pub async fn rehydrate_document(
+        &mut self,
+        node_id: &str,
+        options: &HydrateOptions,
+    ) -> Result<(), ParseError> {
+        if let Err(e) = self.unload_document(node_id) {
+            return Err(ParseError::Read { message: e.into() });
+        }
+        let path = match self.nodes.get_mut(node_id).map(|node| &mut node.data) {
+            Some(NodeData::DocumentData { path, state, .. }) => {
+                *state = FileState::HYDRATING;
+                path.clone()
+            }
+            _ => unreachable!("unload_document only succeeds for documents"),
+        };
+        let mut parsed = parse_documents(
+            vec![(
+                node_id.to_string(),
+                path,
+            )],
+            options.clone(),
+        );
+        let result = match parsed.recv().await {
+            Some(document) => self.finish_hydrating(document),
+            None => Err(
+                ParseError::Panicked {
+                    message: "The parser task stopped without a result.".into(),
+                },
+            ),
+        };
+        self.stop_hydrating();
+        return result;
+    }
+
+    /// Finds the attachment an image link in the document `document_id`
+    /// points to. `target` is tried relative to the document's directory
+    /// first, then as a file name or path suffix anywhere in the tree, as
+    /// `![[image.png]]` embeds are usually written. URLs never resolve.
+    pub fn resolve_attachment(&self, document_id: &str, target: &str) -> Option<&Node> {
+        if target.contains("://") || target.starts_with("data:") {
+            return None;
+        }
+        let target = target.replace("%20", " ");
+        let document_path = match &self.nodes.get(document_id)?.data {
+            NodeData::DocumentData { path, .. } => path.clone(),
+            _ => return None,
+        };
+        let relative = normalise(
+            &Path::new(&document_path)
+                .parent()
+                .unwrap_or(Path::new(
+                    "",
+                ))
+                .join(&target),
+        );
+        let suffix = format!(
+            "/{}",
+            target.trim_start_matches("./")
+        );
+
+        let attachments: Vec<(
+            &Node,
+            &str,
+        )> = self
+            .nodes
+            .values()
+            .filter_map(
+                |node| match &node.data {
+                    NodeData::AttachmentData { path, .. } => Some((
+                        node,
+                        path.as_str(),
+                    )),
+                    _ => None,
+                },
+            )
+            .collect();
+        if let Some((node, _)) = attachments.iter().find(
+            |(_, path)| {
+                normalise(Path::new(
+                    path,
+                )) == relative
+            },
+        ) {
+            return Some(node);
+        }
+        // prefer the shortest path when several files share a name
+        return attachments
+            .into_iter()
+            .filter(|(_, path)| path.ends_with(&suffix))
+            .min_by_key(|(_, path)| path.len())
+            .map(|(node, _)| node);
+    }
+
+    /// Walks the directory (or document) at `path`, adding a node for each
+    /// directory and document but not parsing the documents. Ignored files
+    /// and directories are skipped, and ignored directories aren't walked.
+    /// When following symlinks, a directory that's already in the tree is
+    /// skipped the second time it's reached, so links to an ancestor don't
+    /// loop forever.
+    pub async fn load(name: String, path: String, options: &LoadOptions) -> Option<Tree> {
+        // `./notes/` and `notes` should give the same tree
+        let path: String = match normalise(Path::new(
+            &path,
+        )) {
+            normalised if normalised.as_os_str().is_empty() => ".".into(),
+            normalised => normalised.to_str()?.into(),
+        };
+        if !Path::new(&path).exists() {
+            eprintln!(
+                "Can't load {}: {} doesn't exist.",
+                name, path
+            );
+            return None;
+        }
+        let mut visited: HashSet<(u64, u64)> = HashSet::new();
+        let root_node: Node = Node::new(NodeType::DIRECTORY);
         let mut tree: Tree = Tree::new(root_node);
         tree.name = name.clone();
-        let mut queue: VecDeque<String> = VecDeque::new();
+        let mut queue: VecDeque<(
+            String,
+            Arc<IgnoreRules>,
+        )> = VecDeque::new();
         let mut parents: HashMap<String, String> = HashMap::new();
 
-        queue.push_back(path.clone());
+        let rules = match IgnoreRules::new(
+            Path::new(&path),
+            &options.ignore,
+        ) {
+            Ok(rules) => rules,
+            Err(e) => {
+                eprintln!(
+                    "Invalid --exclude pattern: {}",
+                    e
+                );
+                return None;
+            }
+        };
+        queue.push_back((
+            path.clone(),
+            Arc::new(rules),
+        ));
 
-        while queue.len() > 0 {
-            let path_str = queue.pop_front()?;
+        while !queue.is_empty() {
+            let (path_str, rules) = queue.pop_front()?;
             let cur_path = Path::new(&path_str);
-            let mut cur_node: Node = Node::new(NodeType::None);
-            let cur_node_id: String = cur_node.id.clone();
-            println!(
+            let rel_path = cur_path.strip_prefix(&path).unwrap_or(cur_path);
+            let cur_node_id = Node::stable_id(&[&name, rel_path.to_str()?]);
+            eprintln!(
                 "{}",
                 path_str
             );
-            if !cur_path.exists() {
-                continue;
-            } else if cur_path.is_symlink() && !traverse_symbolic {
+            if !cur_path.exists() || (cur_path.is_symlink() && !options.follow_symlinks) {
                 continue;
-            } else if cur_path.is_file() {
-                if cur_path.extension()? != "md" {
+            }
+            let cur_node = match file_node(
+                cur_node_id.clone(),
+                cur_path,
+                rel_path.to_str()?.into(),
+                options,
+            ) {
+                Some(node) => node,
+                None => continue,
+            };
+            if cur_node.node_type == NodeType::DIRECTORY {
+                let first_visit = directory_key(cur_path).is_none_or(|key| visited.insert(key));
+                if !first_visit {
+                    eprintln!(
+                        "Skipping {}, which is already in the tree.",
+                        path_str
+                    );
                     continue;
                 }
-                cur_node.node_type = NodeType::DOCUMENT;
-                cur_node.data = NodeData::DocumentData {
-                    path: cur_path.to_str()?.into(),
-                    loaded: false,
-                };
-                tree.nodes.insert(
-                    cur_node_id.clone(),
-                    cur_node,
-                );
-            } else if cur_path.is_dir() {
-                cur_node.node_type = NodeType::DIRECTORY;
-                cur_node.data = NodeData::DirectoryData {
-                    path: cur_path.to_str()?.into(),
-                };
 
-                let children = cur_path.read_dir().ok()?;
-                for child in children {
-                    let c_path = child.ok()?.path();
-                    let c_path_str: String = c_path.to_str()?.into();
-                    queue.push_back(c_path_str.clone());
+                let rules = Arc::new(rules.for_directory(cur_path));
+                let mut children: Vec<PathBuf> = vec![];
+                match cur_path.read_dir() {
+                    Ok(entries) => {
+                        for child in entries {
+                            match child {
+                                Ok(child) => children.push(child.path()),
+                                Err(e) => eprintln!(
+                                    "Skipping an entry of {}: {}",
+                                    path_str, e
+                                ),
+                            }
+                        }
+                    }
+                    // the directory is kept, but empty
+                    Err(e) => eprintln!(
+                        "Skipping the contents of {}: {}",
+                        path_str, e
+                    ),
+                }
+                // walk real directories before links to them, so the link
+                // is the one that's skipped
+                children.sort_by_key(|c_path| c_path.is_symlink());
+                for c_path in children {
+                    if rules.is_ignored(
+                        &c_path,
+                        c_path.is_dir(),
+                    ) {
+                        continue;
+                    }
+                    let c_path_str: String = match c_path.to_str() {
+                        Some(c_path_str) => c_path_str.into(),
+                        None => {
+                            eprintln!(
+                                "Skipping {}, whose path isn't valid UTF-8.",
+                                c_path.display()
+                            );
+                            continue;
+                        }
+                    };
+                    queue.push_back((
+                        c_path_str.clone(),
+                        rules.clone(),
+                    ));
                     parents.insert(
                         c_path_str,
                         cur_node_id.clone(),
                     );
                 }
-                tree.nodes.insert(
-                    cur_node_id.clone(),
-                    cur_node,
-                );
             }
+            tree.nodes.insert(
+                cur_node_id.clone(),
+                cur_node,
+            );
             // link the parents to the children by ID using hash table
             let parent_id = parents.get(&path_str);
             match parent_id {
                 Some(id) => {
                     let parent = tree.nodes.get_mut(id)?;
                     parent.children.push(cur_node_id.clone());
+                    tree.parents.insert(
+                        cur_node_id.clone(),
+                        id.clone(),
+                    );
+                }
+                None => {
+                    // replace the placeholder root
+                    tree.nodes.remove(&tree.root_node);
+                    tree.root_node = cur_node_id.clone();
                 }
-                None => tree.root_node = cur_node_id.clone(),
             }
         }
+        tree.total_directory_sizes();
+        tree.refresh_state();
         return Some(tree);
     }
 
-    pub async fn load_document(path: String, parser: String) -> Tree {
-        let output = Command::new(parser.as_str())
-            .arg(path)
-            .output()
-            .await
-            .expect("");
+    /// Sets the size of each directory to the total size of the documents
+    /// and attachments under it.
+    fn total_directory_sizes(&mut self) {
+        let mut totals: HashMap<String, u64> = HashMap::new();
+        for (_, node) in self.post_order(&self.root_node) {
+            let size = match &node.data {
+                NodeData::DocumentData { metadata, .. } => metadata.size,
+                NodeData::AttachmentData { size, .. } => *size,
+                NodeData::DirectoryData { .. } => node
+                    .children
+                    .iter()
+                    .filter_map(|child| totals.get(child))
+                    .sum(),
+                _ => continue,
+            };
+            totals.insert(
+                node.id.clone(),
+                size,
+            );
+        }
+        for (node_id, total) in totals {
+            if let Some(node) = self.nodes.get_mut(&node_id) {
+                if let NodeData::DirectoryData { metadata, .. } = &mut node.data {
+                    metadata.size = total;
+                }
+            }
+        }
+    }
 
-        let parsed_json = String::from_utf8(output.stdout).expect("");
-        let parsed_tree: Tree = serde_json::from_str(parsed_json.as_str()).unwrap();
-        return parsed_tree;
+    /// Parses every unloaded document and splices it into the tree.
+    /// At most `options.processes` parsers run at once, and documents
+    /// that take longer than `options.timeout` to parse are skipped.
+    /// Documents which fail are left unloaded and recorded in `failures`.
+    /// `on_loaded` is called as each document finishes, whether or not it
+    /// succeeded, with its id and the number of documents done so far out
+    /// of the total.
+    pub async fn load_all_unloaded_docs(
+        &mut self,
+        options: &HydrateOptions,
+        mut on_loaded: impl FnMut(&str, usize, usize),
+    ) {
+        let pending = self.start_hydrating(
+            &HydrationScope {
+                retry_failed: true,
+                ..Default::default()
+            },
+        );
+        let total = pending.len();
+        let mut counted = 0;
+        let mut failed = 0;
+        let mut from_cache = 0;
+        let before = Instant::now();
+
+        let mut parsed = parse_documents(
+            pending,
+            options.clone(),
+        );
+        while let Some(document) = parsed.recv().await {
+            counted += 1;
+            if document.cached {
+                from_cache += 1;
+            }
+            let node_id = document.node_id.clone();
+            if self.finish_hydrating(document).is_err() {
+                failed += 1;
+            }
+            on_loaded(
+                &node_id, counted, total,
+            );
+        }
+        self.stop_hydrating();
+        eprintln!(
+            "Loaded {} documents ({} failed, {} from cache) in {:.4?}.",
+            counted,
+            failed,
+            from_cache,
+            before.elapsed()
+        );
     }
 
-    pub async fn load_all_unloaded_docs(&mut self, parser: String) {
-        // trigger full document load
-        let mut set = JoinSet::new();
-        let mut path_to_id: HashMap<String, String> = HashMap::new();
+    /// The dehydrated documents in `scope`, with their paths.
+    fn dehydrated_documents(
+        &self,
+        scope: &HydrationScope,
+    ) -> Vec<(
+        String,
+        String,
+    )> {
+        let mut finder = DehydratedDocuments {
+            scope,
+            found: vec![],
+        };
+        self.walk(
+            scope.root.as_deref().unwrap_or(&self.root_node),
+            &mut finder,
+        );
+        return finder.found;
+    }
 
-        for (_, node) in self.nodes.iter() {
-            match node.data.clone() {
-                NodeData::DocumentData { path, loaded } => {
-                    if !loaded {
-                        path_to_id.insert(
-                            path.clone(),
-                            node.id.clone(),
-                        );
-                        set.spawn(
-                            Tree::load_document(
-                                path.clone(),
-                                parser.clone(),
-                            ),
-                        );
+    /// Whether `start_hydrating` would find any documents to hydrate.
+    pub fn has_dehydrated(&self, scope: &HydrationScope) -> bool {
+        return !self.dehydrated_documents(scope).is_empty();
+    }
+
+    /// Marks the dehydrated documents in `scope` as hydrating, returning
+    /// their ids and paths to be handed to `parse_documents`.
+    pub fn start_hydrating(
+        &mut self,
+        scope: &HydrationScope,
+    ) -> Vec<(
+        String,
+        String,
+    )> {
+        let pending = self.dehydrated_documents(scope);
+        for (node_id, _) in pending.iter() {
+            if let Some(NodeData::DocumentData { state, .. }) =
+                self.nodes.get_mut(node_id).map(|node| &mut node.data)
+            {
+                *state = FileState::HYDRATING;
+            }
+        }
+        return pending;
+    }
+
+    /// Splices a document parsed by `parse_documents` into the tree, or
+    /// records why it couldn't be parsed and marks it dehydrated again.
+    pub fn finish_hydrating(&mut self, document: ParsedDocument) -> Result<(), ParseError> {
+        let ParsedDocument {
+            node_id,
+            path,
+            result,
+            metadata,
+            ..
+        } = document;
+        let result = result.and_then(
+            |doc_tree| {
+                self.splice_document(
+                    &node_id, doc_tree,
+                )
+            },
+        );
+        match result {
+            Ok(()) => {
+                self.failures.remove(&node_id);
+                if let (Some(node), Some(new_metadata)) = (
+                    self.nodes.get_mut(&node_id),
+                    metadata,
+                ) {
+                    if let NodeData::DocumentData { metadata, .. } = &mut node.data {
+                        *metadata = new_metadata;
                     }
                 }
-                _ => {}
+                // counting every document here would be quadratic, so
+                // whether the tree is full is left to `stop_hydrating`
+                if self.state == TreeState::LOADED_METADATA_ONLY {
+                    self.state = TreeState::LOADED_PARTIAL;
+                }
+                return Ok(());
+            }
+            Err(error) => {
+                eprintln!(
+                    "Failed to load {}: {}",
+                    path, error
+                );
+                if let Some(NodeData::DocumentData { state, .. }) =
+                    self.nodes.get_mut(&node_id).map(|node| &mut node.data)
+                {
+                    *state = FileState::DEHYDRATED;
+                }
+                self.failures.insert(
+                    node_id,
+                    DocumentFailure {
+                        path,
+                        error: error.clone(),
+                    },
+                );
+                return Err(error);
             }
         }
+    }
 
-        let mut counted = 0;
-        let before = Instant::now();
+    /// Marks documents still hydrating as dehydrated, e.g. when hydration
+    /// was cancelled, and works out the tree's state.
+    pub fn stop_hydrating(&mut self) {
+        for node in self.nodes.values_mut() {
+            if let NodeData::DocumentData {
+                state: state @ FileState::HYDRATING,
+                ..
+            } = &mut node.data
+            {
+                *state = FileState::DEHYDRATED;
+            }
+        }
+        self.refresh_state();
+    }
 
-        while let Some(res) = set.join_next().await {
-            counted += 1;
+    /// Moves the contents of a parsed document under the document node
+    /// `node_id`, keeping the node's id and parent, and marks it hydrated.
+    fn splice_document(&mut self, node_id: &str, doc_tree: Tree) -> Result<(), ParseError> {
+        if doc_tree.schema_version != PROTOCOL_VERSION {
+            return Err(
+                ParseError::UnsupportedSchema {
+                    found: doc_tree.schema_version,
+                    expected: PROTOCOL_VERSION,
+                },
+            );
+        }
+        let root_id = doc_tree.root_node.clone();
+        let new_root = match doc_tree.nodes.get(&root_id) {
+            Some(root) => root,
+            None => {
+                return Err(
+                    ParseError::InvalidOutput {
+                        message: "The tree has no root node.".into(),
+                        stderr: String::new(),
+                    },
+                )
+            }
+        };
+        if new_root.node_type != NodeType::DOCUMENT {
+            return Err(
+                ParseError::UnexpectedRoot {
+                    node_type: new_root.node_type.clone(),
+                },
+            );
+        }
+        let og_node = match self.nodes.get_mut(node_id) {
+            Some(node) => node,
+            // the document was removed while it was being parsed
+            None => return Ok(()),
+        };
+        // copy data to original node, rather than replacing it (so we don't need to recalculate parent links)
+        og_node.children = new_root.children.clone();
+        if let NodeData::DocumentData { state, .. } = &mut og_node.data {
+            *state = FileState::HYDRATED;
+        }
+
+        for (child_id, node) in doc_tree.nodes.into_iter() {
+            if child_id == root_id {
+                continue;
+            }
+            self.nodes.insert(
+                child_id.clone(),
+                node,
+            );
+            self.index_children(&child_id);
+        }
+        self.index_children(node_id);
+        self.stabilise_ids_under(node_id);
+        return Ok(());
+    }
+}
+
+/// Which documents `Tree::start_hydrating` picks. The default is every
+/// dehydrated document in the tree which hasn't failed before.
+#[derive(Debug, Clone, Default)]
+pub struct HydrationScope {
+    /// Only documents under this node, rather than the whole tree.
+    pub root: Option<String>,
+    /// Only documents whose contents are within this many levels of the
+    /// root, i.e. documents less than `max_depth` levels below it.
+    pub max_depth: Option<usize>,
+    /// Also documents which failed to parse last time.
+    pub retry_failed: bool,
+}
+
+/// Collects the dehydrated documents in a `HydrationScope`.
+struct DehydratedDocuments<'s> {
+    scope: &'s HydrationScope,
+    found: Vec<(
+        String,
+        String,
+    )>,
+}
 
-            let mut doc_tree = Tree::new(Node::new(
-                NodeType::None,
-            ));
-            match res {
-                Ok(res_ok) => {
-                    doc_tree = res_ok;
+impl<'a> Visitor<'a> for DehydratedDocuments<'_> {
+    fn enter(&mut self, tree: &'a Tree, node: &'a Node, depth: usize) -> Walk {
+        if self
+            .scope
+            .max_depth
+            .is_some_and(|max_depth| depth >= max_depth)
+        {
+            return Walk::SkipChildren;
+        }
+        if let NodeData::DocumentData {
+            path,
+            state: FileState::DEHYDRATED,
+            ..
+        } = &node.data
+        {
+            if self.scope.retry_failed || !tree.failures.contains_key(&node.id) {
+                self.found.push((
+                    node.id.clone(),
+                    path.clone(),
+                ));
+            }
+        }
+        // documents don't contain other documents
+        if node.node_type == NodeType::DOCUMENT {
+            return Walk::SkipChildren;
+        }
+        return Walk::Continue;
+    }
+}
+
+/// A document parsed by `parse_documents`, ready for `Tree::finish_hydrating`.
+pub struct ParsedDocument {
+    pub node_id: String,
+    pub path: String,
+    pub result: Result<Tree, ParseError>,
+    /// Whether the tree came from the parse cache.
+    pub cached: bool,
+    /// The document's metadata including its contents, if it parsed.
+    pub metadata: Option<FileMetadata>,
+}
+
+/// Parses documents in the background, at most `options.processes` at
+/// once, sending each to the returned channel as it finishes. The channel
+/// holds as many documents as there are parsers, so parsing waits for a
+/// receiver which falls behind rather than piling up parsed documents in
+/// memory. Dropping the receiver stops any parsers still running.
+pub fn parse_documents(
+    mut pending: Vec<(
+        String,
+        String,
+    )>,
+    options: HydrateOptions,
+) -> mpsc::Receiver<ParsedDocument> {
+    let (tx, rx) = mpsc::channel(options.processes.max(1));
+    tokio::spawn(
+        async move {
+            let mut set = JoinSet::new();
+            loop {
+                // only start another parser once one has finished, so we never
+                // hold more than `processes` sets of pipes open
+                while set.len() < options.processes.max(1) {
+                    let (node_id, path) = match pending.pop() {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    let start = options.parser.start(path.clone());
+                    let timeout = options.timeout;
+                    let cache = options.cache.clone();
+                    set.spawn(
+                        async move {
+                            let (result, cached) = parse_cached(
+                                path.clone(),
+                                start,
+                                timeout,
+                                cache,
+                            )
+                            .await;
+                            let metadata = match result {
+                                Ok(_) => {
+                                    let path = path.clone();
+                                    tokio::task::spawn_blocking(move || {
+                                        FileMetadata::with_contents(Path::new(
+                                            &path,
+                                        ))
+                                        .ok()
+                                    })
+                                    .await
+                                    .ok()
+                                    .flatten()
+                                }
+                                Err(_) => None,
+                            };
+                            return ParsedDocument {
+                                node_id,
+                                path,
+                                result,
+                                cached,
+                                metadata,
+                            };
+                        },
+                    );
                 }
-                Err(_) => {
-                    continue;
+                let document = match set.join_next().await {
+                    Some(Ok(document)) => document,
+                    Some(Err(e)) => {
+                        // parsers catch their own panics, so this is a bug in the task itself
+                        eprintln!(
+                            "Parser task failed: {}",
+                            e
+                        );
+                        continue;
+                    }
+                    None => break,
+                };
+                if tx.send(document).await.is_err() {
+                    // nobody wants the rest, and dropping the set kills them
+                    break;
                 }
             }
+        },
+    );
+    return rx;
+}
 
-            let root_id = doc_tree.root_node.clone();
-            let new_root = doc_tree.nodes.get(&root_id).unwrap();
-            let path = match new_root.data.clone() {
-                NodeData::DocumentData { path, loaded: _ } => path,
-                NodeData::DirectoryData { path } => path,
-                _ => return,
-            };
-            // copy data to original node, rather than replacing it (so we don't need to recalculate parent links)
+/// Parses the document at `path`, using the cache if it has an up-to-date
+/// tree and storing the result in it if not. Returns whether the tree came
+/// from the cache.
+async fn parse_cached(
+    path: String,
+    start: StartFuture,
+    timeout: Duration,
+    cache: Option<Arc<ParseCache>>,
+) -> (
+    Result<Tree, ParseError>,
+    bool,
+) {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => {
+            return (
+                parse_with_timeout(
+                    start, timeout,
+                )
+                .await,
+                false,
+            )
+        }
+    };
+    // the cache reads and hashes files, so keep it off the async threads
+    let lookup = {
+        let cache = cache.clone();
+        let path = path.clone();
+        tokio::task::spawn_blocking(
+            move || match cache.get(&path) {
+                Some(tree) => (
+                    Some(tree),
+                    None,
+                ),
+                None => (
+                    None,
+                    Fingerprint::of(&path).ok(),
+                ),
+            },
+        )
+        .await
+    };
+    let fingerprint = match lookup {
+        Ok((Some(tree), _)) => {
+            return (
+                Ok(tree),
+                true,
+            )
+        }
+        Ok((None, fingerprint)) => fingerprint,
+        Err(_) => None,
+    };
+    let result = parse_with_timeout(
+        start, timeout,
+    )
+    .await;
+    if let (Ok(tree), Some(fingerprint)) = (
+        &result,
+        fingerprint,
+    ) {
+        let tree = tree.clone();
+        let _ = tokio::task::spawn_blocking(move || {
+            cache.put(
+                &path,
+                fingerprint,
+                tree,
+            )
+        })
+        .await;
+    }
+    return (
+        result, false,
+    );
+}
 
-            // unfortunately in this context splice_at() seems to be a poor choice
-            let og_node_id = path_to_id.get(&path).unwrap();
-            let og_node = self.nodes.get_mut(og_node_id).unwrap();
-            og_node.children = new_root.children.clone();
-            match og_node.data.clone() {
-                NodeData::DocumentData { path, loaded: _ } => {
-                    og_node.data = NodeData::DocumentData { path, loaded: true };
+/// Times the parse from when the parser starts on the document, so
+/// waiting for a busy parser doesn't count.
+async fn parse_with_timeout(start: StartFuture, timeout: Duration) -> Result<Tree, ParseError> {
+    let parse = start.await?;
+    return match tokio::time::timeout(
+        timeout, parse,
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => Err(
+            ParseError::Timeout {
+                seconds: timeout.as_secs_f64(),
+            },
+        ),
+    };
+}
+
+/// Resolves `.` and `..` in a path without touching the filesystem.
+fn normalise(path: &Path) -> PathBuf {
+    let mut normalised = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalised.pop() {
+                    normalised.push("..");
                 }
-                _ => {}
             }
+            component => normalised.push(component),
+        }
+    }
+    return normalised;
+}
 
-            for (node_id, node) in doc_tree.nodes.iter() {
-                if node_id.clone() == root_id {
-                    continue;
+/// A node for the document, attachment or directory at `path`, without
+/// any children. Directories have a size of 0 until
+/// `Tree::total_directory_sizes` adds up what's under them.
+fn file_node(id: String, path: &Path, virtual_path: String, options: &LoadOptions) -> Option<Node> {
+    let mut node = Node::new(NodeType::None);
+    node.id = id;
+    let canonical: String = match fs::canonicalize(path) {
+        Ok(canonical) => canonical.to_str()?.into(),
+        Err(_) => path.to_str()?.into(),
+    };
+    if path.is_file() && options.is_document(path) {
+        node.node_type = NodeType::DOCUMENT;
+        node.data = NodeData::DocumentData {
+            path: path.to_str()?.into(),
+            state: FileState::DEHYDRATED,
+            canonical,
+            virtual_path,
+            metadata: FileMetadata::of(path),
+        };
+    } else if path.is_file() {
+        node.node_type = NodeType::ATTACHMENT;
+        node.data = NodeData::AttachmentData {
+            path: path.to_str()?.into(),
+            size: path.metadata().map_or(
+                0,
+                |metadata| metadata.len(),
+            ),
+            mime: mime_guess::from_path(path)
+                .first_or_octet_stream()
+                .to_string(),
+        };
+    } else if path.is_dir() {
+        node.node_type = NodeType::DIRECTORY;
+        node.data = NodeData::DirectoryData {
+            path: path.to_str()?.into(),
+            canonical,
+            virtual_path,
+            metadata: FileMetadata {
+                size: 0,
+                ..FileMetadata::of(path)
+            },
+        };
+    } else {
+        return None;
+    }
+    return Some(node);
+}
+
+/// Identifies a directory however it was reached, by device and inode.
+#[cfg(unix)]
+fn directory_key(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::metadata(path).ok()?;
+    return Some((
+        metadata.dev(),
+        metadata.ino(),
+    ));
+}
+
+/// Identifies a directory however it was reached, by its canonical path.
+#[cfg(not(unix))]
+fn directory_key(path: &Path) -> Option<(u64, u64)> {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    fs::canonicalize(path).ok()?.hash(&mut hasher);
+    return Some((
+        0,
+        hasher.finish(),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::markdown::parse_document;
+    use tempfile::TempDir;
+
+    const DOCUMENT: &str = "# Title\n\nSome text.\n\n- one\n- two\n\n## Section\n\nMore text.\n";
+
+    /// A tree called `name` holding one dehydrated document, `todo.md`,
+    /// whose id is returned alongside it.
+    fn tree_with_document(
+        name: &str,
+    ) -> (
+        Tree,
+        String,
+    ) {
+        let mut tree = Tree::unloaded(
+            name.into(),
+            "/notes".into(),
+        );
+        let mut document = Node::new(NodeType::DOCUMENT);
+        document.id = Node::stable_id(&[name, "todo.md"]);
+        document.data = NodeData::DocumentData {
+            path: "/notes/todo.md".into(),
+            state: FileState::HYDRATING,
+            canonical: String::new(),
+            virtual_path: "todo.md".into(),
+            metadata: FileMetadata::default(),
+        };
+        let document_id = document.id.clone();
+        let root_id = tree.root_node.clone();
+        tree.nodes
+            .get_mut(&root_id)
+            .unwrap()
+            .children
+            .push(document_id.clone());
+        tree.nodes.insert(
+            document_id.clone(),
+            document,
+        );
+        tree.rebuild_parents();
+        return (
+            tree,
+            document_id,
+        );
+    }
+
+    fn hydrate(tree: &mut Tree, document_id: &str, parsed: Tree) {
+        tree.finish_hydrating(
+            ParsedDocument {
+                node_id: document_id.into(),
+                path: "/notes/todo.md".into(),
+                result: Ok(parsed),
+                cached: false,
+                metadata: None,
+            },
+        )
+        .unwrap();
+    }
+
+    /// `tree` as another parser might return it: the same nodes under
+    /// ids of its own choosing.
+    fn with_random_ids(tree: &Tree) -> Tree {
+        let new_ids: HashMap<String, String> = tree
+            .nodes
+            .keys()
+            .map(|id| {
+                (
+                    id.clone(),
+                    Node::new(NodeType::DOCUMENT).id,
+                )
+            })
+            .collect();
+        let mut nodes = HashMap::new();
+        for node in tree.nodes.values() {
+            let mut node = node.clone();
+            node.id = new_ids[&node.id].clone();
+            node.children = node
+                .children
+                .iter()
+                .map(|child| new_ids[child].clone())
+                .collect();
+            nodes.insert(
+                node.id.clone(),
+                node,
+            );
+        }
+        let mut rekeyed = Tree::new(nodes.remove(&new_ids[&tree.root_node]).unwrap());
+        rekeyed.nodes.extend(nodes);
+        rekeyed.rebuild_parents();
+        return rekeyed;
+    }
+
+    /// Every node's id and type, in document order.
+    fn ids_and_types(
+        tree: &Tree,
+    ) -> Vec<(
+        String,
+        NodeType,
+    )> {
+        return tree
+            .pre_order(&tree.root_node)
+            .map(
+                |(_, node)| {
+                    (
+                        node.id.clone(),
+                        node.node_type.clone(),
+                    )
+                },
+            )
+            .collect();
+    }
+
+    #[test]
+    fn hydrated_ids_are_the_same_across_reloads() {
+        let (mut first, document_id) = tree_with_document("notes");
+        hydrate(
+            &mut first,
+            &document_id,
+            parse_document(
+                "/notes/todo.md",
+                DOCUMENT,
+            )
+            .unwrap(),
+        );
+        let (mut second, _) = tree_with_document("notes");
+        hydrate(
+            &mut second,
+            &document_id,
+            parse_document(
+                "/notes/todo.md",
+                DOCUMENT,
+            )
+            .unwrap(),
+        );
+        assert!(first.nodes.len() > 5);
+        assert_eq!(
+            ids_and_types(&first),
+            ids_and_types(&second)
+        );
+    }
+
+    #[test]
+    fn hydrated_ids_are_the_same_across_parsers() {
+        let parsed = parse_document(
+            "/notes/todo.md",
+            DOCUMENT,
+        )
+        .unwrap();
+        let (mut builtin, document_id) = tree_with_document("notes");
+        hydrate(
+            &mut builtin,
+            &document_id,
+            parsed.clone(),
+        );
+        let (mut other, _) = tree_with_document("notes");
+        hydrate(
+            &mut other,
+            &document_id,
+            with_random_ids(&parsed),
+        );
+        assert_eq!(
+            ids_and_types(&builtin),
+            ids_and_types(&other)
+        );
+    }
+
+    #[test]
+    fn stabilise_ids_depends_only_on_the_root_id_and_positions() {
+        let parsed = parse_document(
+            "/notes/todo.md",
+            DOCUMENT,
+        )
+        .unwrap();
+        let mut first = with_random_ids(&parsed);
+        first.stabilise_ids("root".into());
+        let mut again = first.clone();
+        again.stabilise_ids("root".into());
+        assert_eq!(
+            ids_and_types(&first),
+            ids_and_types(&again)
+        );
+        for (_, node) in first.pre_order(&first.root_node) {
+            for child_id in &node.children {
+                assert_eq!(
+                    first.get_parent(child_id).map(|parent| &parent.id),
+                    Some(&node.id)
+                );
+            }
+        }
+
+        let mut elsewhere = parsed.clone();
+        elsewhere.stabilise_ids("other root".into());
+        let first_ids: HashSet<&String> = first.nodes.keys().collect();
+        assert!(elsewhere.nodes.keys().all(|id| !first_ids.contains(id)));
+    }
+
+    #[test]
+    fn the_same_document_in_another_tree_has_other_ids() {
+        let parsed = parse_document(
+            "/notes/todo.md",
+            DOCUMENT,
+        )
+        .unwrap();
+        let (mut notes, notes_document) = tree_with_document("notes");
+        hydrate(
+            &mut notes,
+            &notes_document,
+            parsed.clone(),
+        );
+        let (mut archive, archive_document) = tree_with_document("archive");
+        hydrate(
+            &mut archive,
+            &archive_document,
+            parsed,
+        );
+        let notes_ids: HashSet<&String> = notes.nodes.keys().collect();
+        assert!(archive.nodes.keys().all(|id| !notes_ids.contains(id)));
+    }
+
+    /// Loads a tree of `files`, given by path relative to the root and
+    /// contents, and returns the paths it holds relative to the root.
+    async fn loaded_paths(files: &[(&str, &str)], options: &LoadOptions) -> Vec<String> {
+        let root = TempDir::new().unwrap();
+        for (path, contents) in files {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(
+                path, contents,
+            )
+            .unwrap();
+        }
+        let tree = Tree::load(
+            "test".into(),
+            root.path().to_str().unwrap().into(),
+            options,
+        )
+        .await
+        .unwrap();
+        let mut paths: Vec<String> = tree
+            .nodes
+            .values()
+            .filter_map(
+                |node| match &node.data {
+                    NodeData::DirectoryData { path, .. }
+                    | NodeData::DocumentData { path, .. }
+                    | NodeData::AttachmentData { path, .. } => Some(path),
+                    _ => None,
+                },
+            )
+            .map(|path| {
+                let path = Path::new(path).strip_prefix(root.path()).unwrap();
+                return path.to_str().unwrap().to_string();
+            })
+            .filter(|path| !path.is_empty())
+            .collect();
+        paths.sort();
+        return paths;
+    }
+
+    #[tokio::test]
+    async fn load_skips_hidden_entries_unless_asked_for() {
+        let files = [
+            (
+                ".obsidian/app.json",
+                "{}",
+            ),
+            (
+                ".hidden.md",
+                "",
+            ),
+            (
+                "notes/todo.md",
+                "",
+            ),
+        ];
+        assert_eq!(
+            loaded_paths(
+                &files,
+                &LoadOptions::default()
+            )
+            .await,
+            ["notes", "notes/todo.md"]
+        );
+        let options = LoadOptions {
+            ignore: IgnoreOptions {
+                hidden: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            loaded_paths(&files, &options).await,
+            [
+                ".hidden.md",
+                ".obsidian",
+                ".obsidian/app.json",
+                "notes",
+                "notes/todo.md"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn load_applies_nested_ignore_files() {
+        let files = [
+            (
+                ".gitignore",
+                "*.log\n",
+            ),
+            (
+                ".zkignore",
+                "private/\n",
+            ),
+            (
+                "top.log", "",
+            ),
+            (
+                "private/secret.md",
+                "",
+            ),
+            (
+                "notes/.gitignore",
+                "!keep.log\n",
+            ),
+            (
+                "notes/keep.log",
+                "",
+            ),
+            (
+                "notes/other.log",
+                "",
+            ),
+            (
+                "notes/todo.md",
+                "",
+            ),
+        ];
+        assert_eq!(
+            loaded_paths(
+                &files,
+                &LoadOptions::default()
+            )
+            .await,
+            ["notes", "notes/keep.log", "notes/todo.md"]
+        );
+    }
+
+    #[tokio::test]
+    async fn load_anchors_excludes_at_the_root() {
+        let files = [
+            (
+                "drafts/a.md",
+                "",
+            ),
+            (
+                "notes/drafts/b.md",
+                "",
+            ),
+            (
+                "archive/c.md",
+                "",
+            ),
+            (
+                "notes/archive/d.md",
+                "",
+            ),
+        ];
+        let options = LoadOptions {
+            ignore: IgnoreOptions {
+                exclude: vec!["/drafts".into(), "archive/".into()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            loaded_paths(&files, &options).await,
+            ["notes", "notes/drafts", "notes/drafts/b.md"]
+        );
+    }
+
+    #[tokio::test]
+    async fn load_never_enters_ignored_directories() {
+        // as in git, a file can't be re-included from inside an ignored
+        // directory, since the directory's ignore file is never read
+        let files = [
+            (
+                ".gitignore",
+                "build/\n",
+            ),
+            (
+                "build/.gitignore",
+                "!*\n",
+            ),
+            (
+                "build/keep.md",
+                "",
+            ),
+            (
+                "build/nested/deep.md",
+                "",
+            ),
+            (
+                "todo.md", "",
+            ),
+        ];
+        assert_eq!(
+            loaded_paths(
+                &files,
+                &LoadOptions::default()
+            )
+            .await,
+            ["todo.md"]
+        );
+    }
+
+    /// The canonical and virtual path of every document in `tree`, with
+    /// canonical paths relative to `root`.
+    fn document_paths(
+        tree: &Tree,
+        root: &Path,
+    ) -> Vec<(
+        String,
+        String,
+    )> {
+        let root = fs::canonicalize(root).unwrap();
+        let mut paths: Vec<(
+            String,
+            String,
+        )> = tree
+            .nodes_of_type(
+                &tree.root_node,
+                NodeType::DOCUMENT,
+            )
+            .map(
+                |node| match &node.data {
+                    NodeData::DocumentData {
+                        canonical,
+                        virtual_path,
+                        ..
+                    } => (
+                        Path::new(canonical)
+                            .strip_prefix(&root)
+                            .unwrap()
+                            .to_str()
+                            .unwrap()
+                            .into(),
+                        virtual_path.clone(),
+                    ),
+                    _ => unreachable!(),
+                },
+            )
+            .collect();
+        paths.sort();
+        return paths;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn following_symlinks_skips_directories_already_in_the_tree() {
+        use std::os::unix::fs::symlink;
+
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("notes")).unwrap();
+        fs::create_dir_all(root.path().join("other")).unwrap();
+        fs::write(
+            root.path().join("notes/a.md"),
+            "# A\n",
+        )
+        .unwrap();
+        fs::write(
+            root.path().join("other/b.md"),
+            "# B\n",
+        )
+        .unwrap();
+        // a link back to the root, which would otherwise never end
+        symlink(
+            "..",
+            root.path().join("notes/loop"),
+        )
+        .unwrap();
+        // a link to a directory walked anyway
+        symlink(
+            "../other",
+            root.path().join("notes/alias"),
+        )
+        .unwrap();
+        let options = LoadOptions {
+            follow_symlinks: true,
+            ..LoadOptions::default()
+        };
+
+        let tree = tokio::time::timeout(
+            Duration::from_secs(10),
+            Tree::load(
+                "test".into(),
+                root.path().to_str().unwrap().into(),
+                &options,
+            ),
+        )
+        .await
+        .expect("loading should finish")
+        .unwrap();
+        assert!(tree.find_node("notes/loop").is_none());
+        assert!(tree.find_node("notes/alias").is_none());
+        assert_eq!(
+            document_paths(
+                &tree,
+                root.path()
+            ),
+            [
+                (
+                    "notes/a.md".to_string(),
+                    "notes/a.md".to_string()
+                ),
+                (
+                    "other/b.md".to_string(),
+                    "other/b.md".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn followed_file_links_keep_the_path_they_were_reached_by() {
+        use std::os::unix::fs::symlink;
+
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("notes")).unwrap();
+        fs::create_dir_all(root.path().join("other")).unwrap();
+        fs::write(
+            root.path().join("other/b.md"),
+            "# B\n",
+        )
+        .unwrap();
+        symlink(
+            "../other/b.md",
+            root.path().join("notes/b.md"),
+        )
+        .unwrap();
+        let load = |follow_symlinks| {
+            let path = root.path().to_str().unwrap().to_string();
+            return async move {
+                let options = LoadOptions {
+                    follow_symlinks,
+                    ..LoadOptions::default()
+                };
+                return Tree::load(
+                    "test".into(),
+                    path,
+                    &options,
+                )
+                .await
+                .unwrap();
+            };
+        };
+
+        assert_eq!(
+            document_paths(
+                &load(true).await,
+                root.path()
+            ),
+            [
+                (
+                    "other/b.md".to_string(),
+                    "notes/b.md".to_string()
+                ),
+                (
+                    "other/b.md".to_string(),
+                    "other/b.md".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            document_paths(
+                &load(false).await,
+                root.path()
+            ),
+            [(
+                "other/b.md".to_string(),
+                "other/b.md".to_string()
+            )]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn load_skips_paths_which_arent_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = TempDir::new().unwrap();
+        fs::create_dir(root.path().join("notes")).unwrap();
+        fs::write(
+            root.path().join("notes/todo.md"),
+            "# Todo\n",
+        )
+        .unwrap();
+        fs::write(
+            root.path().join(OsStr::from_bytes(b"\xff.md")),
+            "# Bad\n",
+        )
+        .unwrap();
+        let tree = Tree::load(
+            "test".into(),
+            root.path().to_str().unwrap().into(),
+            &LoadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            tree.nodes_of_type(
+                &tree.root_node,
+                NodeType::DOCUMENT
+            )
+            .count(),
+            1
+        );
+        assert!(tree.find_node("notes/todo.md").is_some());
+    }
+
+    #[tokio::test]
+    async fn start_hydrating_only_picks_documents_in_scope() {
+        let root = TempDir::new().unwrap();
+        for path in ["top.md", "notes/a.md", "notes/deeper/b.md", "other/c.md"] {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(
+                path,
+                "# Title\n",
+            )
+            .unwrap();
+        }
+        let tree = Tree::load(
+            "test".into(),
+            root.path().to_str().unwrap().into(),
+            &LoadOptions::default(),
+        )
+        .await
+        .unwrap();
+        let notes = tree.find_node("notes").unwrap().id.clone();
+        let picked = |scope: HydrationScope| {
+            let mut tree = tree.clone();
+            let mut paths: Vec<String> = tree
+                .start_hydrating(&scope)
+                .into_iter()
+                .map(
+                    |(_, path)| {
+                        let path = Path::new(&path).strip_prefix(root.path()).unwrap();
+                        return path.to_str().unwrap().to_string();
+                    },
+                )
+                .collect();
+            paths.sort();
+            return paths;
+        };
+
+        assert_eq!(
+            picked(HydrationScope::default()),
+            ["notes/a.md", "notes/deeper/b.md", "other/c.md", "top.md"]
+        );
+        assert_eq!(
+            picked(
+                HydrationScope {
+                    root: Some(notes.clone()),
+                    ..Default::default()
                 }
-                self.nodes.insert(
-                    node_id.clone(),
-                    node.clone(),
+            ),
+            ["notes/a.md", "notes/deeper/b.md"]
+        );
+        // a document's contents are a level below it, so `a.md` at depth 1
+        // only has them within a depth of 2
+        assert_eq!(
+            picked(
+                HydrationScope {
+                    root: Some(notes.clone()),
+                    max_depth: Some(1),
+                    ..Default::default()
+                }
+            ),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            picked(
+                HydrationScope {
+                    root: Some(notes),
+                    max_depth: Some(2),
+                    ..Default::default()
+                }
+            ),
+            ["notes/a.md"]
+        );
+    }
+
+    #[tokio::test]
+    async fn start_hydrating_skips_failed_documents_unless_retrying() {
+        let (mut tree, document_id) = tree_with_document("notes");
+        tree.finish_hydrating(
+            ParsedDocument {
+                node_id: document_id.clone(),
+                path: "/notes/todo.md".into(),
+                result: Err(
+                    ParseError::Syntax {
+                        message: "broken".into(),
+                    },
+                ),
+                cached: false,
+                metadata: None,
+            },
+        )
+        .unwrap_err();
+        assert!(!tree.has_dehydrated(&HydrationScope::default()));
+        let retry = HydrationScope {
+            retry_failed: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            tree.start_hydrating(&retry),
+            [(
+                document_id,
+                "/notes/todo.md".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn copy_document_from_keeps_the_files_own_metadata() {
+        let (mut saved, document_id) = tree_with_document("notes");
+        hydrate(
+            &mut saved,
+            &document_id,
+            parse_document(
+                "/notes/todo.md",
+                DOCUMENT,
+            )
+            .unwrap(),
+        );
+        if let NodeData::DocumentData { metadata, .. } =
+            &mut saved.nodes.get_mut(&document_id).unwrap().data
+        {
+            metadata.size = 10;
+            metadata.word_count = Some(3);
+        }
+        let (mut fresh, _) = tree_with_document("notes");
+        fresh.stop_hydrating();
+        if let NodeData::DocumentData { metadata, .. } =
+            &mut fresh.nodes.get_mut(&document_id).unwrap().data
+        {
+            metadata.size = 20;
+        }
+
+        fresh
+            .copy_document_from(
+                &saved,
+                &document_id,
+            )
+            .unwrap();
+        fresh.refresh_state();
+        assert_eq!(
+            ids_and_types(&fresh),
+            ids_and_types(&saved)
+        );
+        assert_eq!(
+            fresh.state,
+            TreeState::LOADED_FULL
+        );
+        let first_child = &fresh.nodes[&document_id].children[0];
+        assert_eq!(
+            fresh.get_parent(first_child).unwrap().id,
+            document_id
+        );
+        match &fresh.nodes[&document_id].data {
+            NodeData::DocumentData {
+                state, metadata, ..
+            } => {
+                assert_eq!(
+                    *state,
+                    FileState::HYDRATED
+                );
+                assert_eq!(
+                    metadata.size,
+                    20
+                );
+                assert_eq!(
+                    metadata.word_count,
+                    Some(3)
                 );
             }
+            _ => unreachable!(),
         }
-        println!(
-            "Loaded {} documents in {:.4?}.",
-            counted,
-            before.elapsed()
+    }
+
+    #[test]
+    fn copy_document_from_needs_a_loaded_document() {
+        let (mut saved, document_id) = tree_with_document("notes");
+        saved.stop_hydrating();
+        let (mut fresh, _) = tree_with_document("notes");
+        assert!(fresh
+            .copy_document_from(
+                &saved,
+                &document_id,
+            )
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn waiting_for_a_busy_parser_isnt_timed() {
+        use crate::common::parser::{ParserDescription, StreamingParser};
+
+        let root = TempDir::new().unwrap();
+        let tree_json = root.path().join("tree.json");
+        let parsed = parse_document(
+            "/notes/todo.md",
+            DOCUMENT,
+        )
+        .unwrap();
+        fs::write(
+            &tree_json,
+            serde_json::to_string(&parsed).unwrap() + "\n",
+        )
+        .unwrap();
+        // one worker which takes 0.3s a document, for three documents
+        let parser = StreamingParser::new(
+            "sh".into(),
+            vec![
+                "-c".into(),
+                "while read path; do sleep 0.3; cat \"$1\"; done".into(),
+                "sh".into(),
+                tree_json.to_str().unwrap().into(),
+            ],
+            ParserDescription::builtin(),
+            1,
+        );
+        let options = HydrateOptions {
+            parser: Arc::new(parser),
+            processes: 3,
+            timeout: Duration::from_millis(500),
+            cache: None,
+        };
+        let pending = (0..3)
+            .map(|i| {
+                (
+                    i.to_string(),
+                    format!(
+                        "/notes/{}.md",
+                        i
+                    ),
+                )
+            })
+            .collect();
+        let mut parsed = parse_documents(
+            pending, options,
+        );
+        let mut results = vec![];
+        while let Some(document) = parsed.recv().await {
+            results.push(document.result.map(|_| ()));
+        }
+        assert_eq!(
+            results,
+            [Ok(()), Ok(()), Ok(())]
         );
     }
 }
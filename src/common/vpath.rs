@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::common::node::{Node, NodeData, NodeType};
+use crate::common::tree::Tree;
+
+/// Separates the path of a file in the tree from the part inside it.
+pub const FRAGMENT_SEPARATOR: char = '#';
+
+/// Starts the number of a block within its section or parent.
+pub const INDEX_PREFIX: char = '@';
+
+/// Escapes a heading for use as a path segment, so headings containing
+/// `/` or `~` can't be mistaken for more than one segment, nor headings
+/// starting with `@` for a block's number.
+fn escape_segment(text: &str) -> String {
+    let escaped = text
+        .trim()
+        .replace('%', "%25")
+        .replace('/', "%2F")
+        .replace('~', "%7E");
+    return match escaped.strip_prefix(INDEX_PREFIX) {
+        Some(rest) => format!(
+            "%40{}",
+            rest
+        ),
+        None => escaped,
+    };
+}
+
+/// Escapes a path relative to the tree root, so a `#` in a file name
+/// can't be mistaken for the start of the fragment.
+fn escape_file_path(path: &str) -> String {
+    return path.replace('%', "%25").replace('#', "%23");
+}
+
+impl Tree {
+    /// The virtual path of a node: its file's path relative to the tree
+    /// root, then for nodes inside a document a `#` followed by the chain
+    /// of headings above the node and its index in that section, e.g.
+    /// `notes/todo.md#Todo/@2`. See docs/api.md for the full scheme.
+    pub fn vpath_of(&self, node_id: &str) -> Option<String> {
+        let node = self.nodes.get(node_id)?;
+        if let Some(file_path) = self.file_vpath(node) {
+            return Some(file_path);
+        }
+        let document = self.document_of(node_id)?;
+        return self
+            .block_vpaths(document)
+            .into_iter()
+            .find(|(_, block_id)| block_id == node_id)
+            .map(|(vpath, _)| vpath);
+    }
+
+    /// Finds the node with a virtual path, as returned by `vpath_of`.
+    pub fn resolve_vpath(&self, vpath: &str) -> Option<&Node> {
+        let vpath = vpath.trim_matches('/');
+        let (file_path, fragment) = match vpath.split_once(FRAGMENT_SEPARATOR) {
+            Some((file_path, fragment)) => (
+                file_path,
+                Some(fragment),
+            ),
+            None => (
+                vpath, None,
+            ),
+        };
+        let file = self
+            .nodes
+            .values()
+            .find(|node| self.file_vpath(node).as_deref() == Some(file_path))?;
+        let fragment = match fragment {
+            Some(fragment) if !fragment.is_empty() => fragment,
+            _ => return Some(file),
+        };
+        let wanted = format!(
+            "{}{}{}",
+            file_path, FRAGMENT_SEPARATOR, fragment
+        );
+        let (_, block_id) = self
+            .block_vpaths(file)
+            .into_iter()
+            .find(|(block_vpath, _)| *block_vpath == wanted)?;
+        return self.nodes.get(&block_id);
+    }
+
+    /// Looks a node up by id, falling back to treating `id_or_vpath` as a
+    /// virtual path, so the API can accept either.
+    pub fn find_node(&self, id_or_vpath: &str) -> Option<&Node> {
+        if let Some(node) = self.nodes.get(id_or_vpath) {
+            return Some(node);
+        }
+        return self.resolve_vpath(id_or_vpath);
+    }
+
+    /// The path of a directory, document or attachment relative to the
+    /// tree root, or `None` for nodes inside documents.
+    fn file_vpath(&self, node: &Node) -> Option<String> {
+        match &node.data {
+            NodeData::DirectoryData { virtual_path, .. } => {
+                return Some(escape_file_path(virtual_path))
+            }
+            NodeData::DocumentData { virtual_path, .. } => {
+                return Some(escape_file_path(virtual_path))
+            }
+            NodeData::AttachmentData { path, .. } => {
+                let parent = self.get_parent(&node.id)?;
+                let name = escape_file_path(Path::new(path).file_name()?.to_str()?);
+                return match self.file_vpath(parent)?.as_str() {
+                    "" => Some(name),
+                    parent_path => Some(format!(
+                        "{}/{}",
+                        parent_path, name
+                    )),
+                };
+            }
+            _ => return None,
+        }
+    }
+
+    /// The virtual path of every node inside a document, with its id.
+    /// Headers open a section named after them, closing any sections at
+    /// the same or a deeper level. Other blocks are numbered from `@1`
+    /// within their section, and their descendants from `@1` within them,
+    /// so numbers never clash with headings. A heading
+    /// that repeats within a section gets `~2`, `~3`... appended.
+    fn block_vpaths(
+        &self,
+        document: &Node,
+    ) -> Vec<(
+        String,
+        String,
+    )> {
+        let document_path = match self.file_vpath(document) {
+            Some(document_path) => document_path,
+            None => return vec![],
+        };
+        let mut vpaths = vec![];
+        // open sections as (level, segment), outermost first
+        let mut sections: Vec<(
+            usize,
+            String,
+        )> = vec![];
+        let mut index = 0;
+        let mut seen_headings: HashMap<String, usize> = HashMap::new();
+
+        for child_id in document.children.iter() {
+            let child = match self.nodes.get(child_id) {
+                Some(child) => child,
+                None => continue,
+            };
+            let prefix = |sections: &Vec<(
+                usize,
+                String,
+            )>| {
+                sections
+                    .iter()
+                    .map(|(_, segment)| segment.as_str())
+                    .collect::<Vec<&str>>()
+                    .join("/")
+            };
+            if let NodeData::HeaderData { text, level } = &child.data {
+                while sections.last().is_some_and(|(open, _)| open >= level) {
+                    sections.pop();
+                }
+                let mut segment = escape_segment(text);
+                let key = format!(
+                    "{}/{}",
+                    prefix(&sections),
+                    segment
+                );
+                let count = seen_headings.entry(key).or_insert(0);
+                *count += 1;
+                if *count > 1 {
+                    segment = format!(
+                        "{}~{}",
+                        segment, count
+                    );
+                }
+                sections.push((
+                    *level, segment,
+                ));
+                index = 0;
+                vpaths.push((
+                    format!(
+                        "{}{}{}",
+                        document_path,
+                        FRAGMENT_SEPARATOR,
+                        prefix(&sections)
+                    ),
+                    child.id.clone(),
+                ));
+                continue;
+            }
+            index += 1;
+            let section = prefix(&sections);
+            let fragment = match section.is_empty() {
+                true => format!(
+                    "{}{}",
+                    INDEX_PREFIX, index
+                ),
+                false => format!(
+                    "{}/{}{}",
+                    section, INDEX_PREFIX, index
+                ),
+            };
+            let vpath = format!(
+                "{}{}{}",
+                document_path, FRAGMENT_SEPARATOR, fragment
+            );
+            self.push_descendant_vpaths(
+                child,
+                &vpath,
+                &mut vpaths,
+            );
+            vpaths.push((
+                vpath,
+                child.id.clone(),
+            ));
+        }
+        return vpaths;
+    }
+
+    fn push_descendant_vpaths(
+        &self,
+        node: &Node,
+        vpath: &str,
+        vpaths: &mut Vec<(
+            String,
+            String,
+        )>,
+    ) {
+        for (i, child_id) in node.children.iter().enumerate() {
+            let child = match self.nodes.get(child_id) {
+                Some(child) if child.node_type != NodeType::DOCUMENT => child,
+                _ => continue,
+            };
+            let child_vpath = format!(
+                "{}/{}{}",
+                vpath,
+                INDEX_PREFIX,
+                i + 1
+            );
+            self.push_descendant_vpaths(
+                child,
+                &child_vpath,
+                vpaths,
+            );
+            vpaths.push((
+                child_vpath,
+                child.id.clone(),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::markdown::parse_document;
+    use crate::common::metadata::FileMetadata;
+    use crate::common::node::FileState;
+    use crate::common::tree::ParsedDocument;
+
+    /// A tree holding one document at `virtual_path`, hydrated from `raw`.
+    fn tree_with_document(
+        virtual_path: &str,
+        raw: &str,
+    ) -> (
+        Tree,
+        String,
+    ) {
+        let mut tree = Tree::unloaded(
+            "notes".into(),
+            "/notes".into(),
+        );
+        let path = format!(
+            "/notes/{}",
+            virtual_path
+        );
+        let mut document = Node::new(NodeType::DOCUMENT);
+        document.id = Node::stable_id(&["notes", virtual_path]);
+        document.data = NodeData::DocumentData {
+            path: path.clone(),
+            state: FileState::HYDRATING,
+            canonical: String::new(),
+            virtual_path: virtual_path.into(),
+            metadata: FileMetadata::default(),
+        };
+        let document_id = document.id.clone();
+        let root_id = tree.root_node.clone();
+        tree.nodes
+            .get_mut(&root_id)
+            .unwrap()
+            .children
+            .push(document_id.clone());
+        tree.nodes.insert(
+            document_id.clone(),
+            document,
+        );
+        tree.rebuild_parents();
+        tree.finish_hydrating(
+            ParsedDocument {
+                node_id: document_id.clone(),
+                path: path.clone(),
+                result: Ok(parse_document(&path, raw).unwrap()),
+                cached: false,
+                metadata: None,
+            },
+        )
+        .unwrap();
+        return (
+            tree,
+            document_id,
+        );
+    }
+
+    /// Every virtual path in the tree resolves back to its node.
+    fn assert_round_trips(tree: &Tree) {
+        for node_id in tree.nodes.keys() {
+            let vpath = tree.vpath_of(node_id).unwrap();
+            assert_eq!(
+                &tree.resolve_vpath(&vpath).unwrap().id,
+                node_id,
+                "{}",
+                vpath
+            );
+        }
+    }
+
+    #[test]
+    fn numbered_blocks_dont_clash_with_headings() {
+        let (tree, _) = tree_with_document(
+            "doc.md",
+            "# Section\n\nFirst.\n\nSecond.\n\n## 2\n\nThird.\n\n## @1\n",
+        );
+        let second = tree.resolve_vpath("doc.md#Section/@2").unwrap();
+        assert_eq!(
+            second.node_type,
+            NodeType::PARAGRAPH
+        );
+        let heading = tree.resolve_vpath("doc.md#Section/2").unwrap();
+        assert_eq!(
+            heading.node_type,
+            NodeType::HEADER
+        );
+        let at_heading = tree.resolve_vpath("doc.md#Section/%401").unwrap();
+        assert_eq!(
+            at_heading.node_type,
+            NodeType::HEADER
+        );
+        assert!(tree.resolve_vpath("doc.md#Section/2/@1").is_some());
+        assert_round_trips(&tree);
+    }
+
+    #[test]
+    fn hashes_in_file_names_are_escaped() {
+        let (tree, document_id) = tree_with_document(
+            "C#/100%.md",
+            "# Notes\n\nText.\n",
+        );
+        assert_eq!(
+            tree.vpath_of(&document_id).unwrap(),
+            "C%23/100%25.md"
+        );
+        let paragraph = tree.resolve_vpath("C%23/100%25.md#Notes/@1").unwrap();
+        assert_eq!(
+            paragraph.node_type,
+            NodeType::PARAGRAPH
+        );
+        assert!(tree.resolve_vpath("C#/100%.md").is_none());
+        assert_round_trips(&tree);
+    }
+}
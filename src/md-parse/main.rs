@@ -1,221 +1,211 @@
 use clap::Parser;
-use nom::branch::alt;
-use nom::bytes::complete::{is_a, tag, take, take_till, take_until};
-use nom::character::complete::{
-    alphanumeric0, anychar, char, line_ending, not_line_ending, space0, space1,
-};
-use nom::character::is_space;
-use nom::character::streaming::one_of;
-use nom::combinator::{eof, not, opt, rest};
-use nom::error::ErrorKind;
-use nom::multi::{fold_many0, many0, many1, many1_count, many_m_n, many_till};
-use nom::sequence::{terminated, tuple};
 use serde_json::to_string;
-use std::fs::{self, read_to_string};
-use std::io::{self, Write};
+use std::fs::read_to_string;
+use std::io::{self, BufRead, Write};
+use std::process;
 
+// compiled into every binary, each of which only uses part of it
+#[allow(dead_code)]
 #[path = "../common.rs"]
 mod common;
-use common::node::{ListType, Node, NodeData, NodeType};
+use common::markdown::parse_document;
+use common::parser::{BatchOutput, ParserDescription};
 use common::tree::Tree;
 
-use nom::IResult;
 #[derive(Parser, Debug)]
 struct Args {
-    path: String,
+    #[arg(required_unless_present_any = ["describe", "stdin"])]
+    paths: Vec<String>,
+
+    /// Print a description of the parser for the server's handshake
+    #[arg(long)]
+    describe: bool,
+
+    /// Read newline-delimited paths from stdin, printing one line of JSON per document
+    #[arg(long)]
+    stdin: bool,
 }
 
-fn blank_line(raw: &str) -> IResult<&str, Tree> {
-    let result = tuple((
-        line_ending,
-        terminated(
-            space0,
-            alt((
-                line_ending,
-                eof,
-            )),
-        ),
-    ))(raw);
-    match result {
-        Ok((stream, results)) => {
-            let node = Node::new(NodeType::None);
-            let tree = Tree::new(node);
-            return Ok((
-                stream, tree,
-            ));
-        }
+fn parse_path(path: &str) -> Result<Tree, String> {
+    let str = match read_to_string(path) {
+        Ok(str) => str,
         Err(e) => {
-            return Err(e);
+            return Err(format!(
+                "Failed to read {}: {}",
+                path, e
+            ))
         }
-    }
+    };
+    return parse_document(
+        path,
+        str.as_str(),
+    );
 }
 
-fn little_indent(raw: &str) -> IResult<&str, Vec<char>> {
-    many_m_n(
-        0,
-        3,
-        char(' '),
-    )(raw)
+/// Prints one line for the document.
+fn write_line(out: &mut impl Write, path: &str) -> io::Result<()> {
+    let line = match parse_path(path) {
+        Ok(tree) => BatchOutput::Tree(tree),
+        Err(error) => BatchOutput::Error {
+            path: path.into(),
+            error,
+        },
+    };
+    return write_output(out, &line);
 }
 
-fn atx_header(raw: &str) -> IResult<&str, Tree> {
-    let mut parser = tuple((
-        little_indent,
-        many_m_n(
-            1,
-            6,
-            char('#'),
-        ),
-        space1,
-        many_till(
-            anychar,
-            line_ending,
-        ),
-    ));
-    let result = parser(raw);
-    match result {
-        Ok((stream, results)) => {
-            let mut node = Node::new(NodeType::HEADER);
-            let ack: (
-                Vec<char>,
-                &str,
-            ) = results.3;
-            let content: String = ack.0.iter().collect();
-            node.data = NodeData::HeaderData {
-                text: content,
-                level: results.1.len(),
-            };
-            return Ok((
-                stream,
-                Tree::new(node),
-            ));
-        }
-        Err(e) => return Err(e),
-    }
+/// Prints one line of output, flushing so a waiting server sees it
+/// straight away.
+fn write_output(out: &mut impl Write, line: &BatchOutput) -> io::Result<()> {
+    writeln!(
+        out,
+        "{}",
+        to_string(line).expect("")
+    )?;
+    return out.flush();
 }
 
-fn thematic_break(raw: &str) -> IResult<&str, Tree> {
-    let result = terminated(
-        tuple((
-            little_indent,
-            // commonmark spec is that spaces are allowed
-            // between the thematic break characters
-            alt((
-                tuple((
-                    char('-'),
-                    space0,
-                    char('-'),
-                    space0,
-                    char('-'),
-                    many0(one_of(
-                        " \t-",
-                    )),
-                )),
-                tuple((
-                    char('_'),
-                    space0,
-                    char('_'),
-                    space0,
-                    char('_'),
-                    many0(one_of(
-                        " \t_",
-                    )),
-                )),
-                tuple((
-                    char('*'),
-                    space0,
-                    char('*'),
-                    space0,
-                    char('*'),
-                    many0(one_of(
-                        " \t*",
-                    )),
-                )),
-            )),
-            line_ending,
-        )),
-        line_ending,
-    )(raw);
-
-    match result {
-        // a thematic break is just a thematic break
-        Ok((stream, _)) => {
-            let mut node = Node::new(NodeType::THEMATIC_BREAK);
-            node.data = NodeData::ThematicBreakData {};
-            let tree = Tree::new(node);
-            return Ok((
-                stream, tree,
-            ));
+/// Answers each line of `input` with a line for the document at that
+/// path, until `input` ends or the server hangs up. A line which isn't
+/// UTF-8 gets an error of its own, so one bad path doesn't take down the
+/// documents after it. Only failing to read `input` at all is an error.
+fn serve_lines(mut input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if input.read_until(
+            b'\n', &mut line,
+        )? == 0
+        {
+            return Ok(());
         }
-        Err(e) => return Err(e),
-    }
-}
-
-fn paragraph(raw: &str) -> IResult<&str, Tree> {
-    // This is a decent example for transforming a character specification into
-    // a unit of meaning
-    // Might need an indent level modifier
-    let result = tuple((
-        little_indent,
-        many_till(
-            anychar, blank_line,
-        ),
-    ))(raw);
-    match result {
-        Ok((stream, results)) => {
-            let mut node = Node::new(NodeType::PARAGRAPH);
-            let content: String = String::from_iter(results.1 .0);
-            node.data = NodeData::ParagraphData { text: content };
-            return Ok((
-                stream,
-                Tree::new(node),
-            ));
+        let raw = line.strip_suffix(b"\n").unwrap_or(&line);
+        let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+        if raw.is_empty() {
+            continue;
+        }
+        let written = match std::str::from_utf8(raw) {
+            Ok(path) => write_line(out, path),
+            Err(e) => write_output(
+                out,
+                &BatchOutput::Error {
+                    path: String::from_utf8_lossy(raw).into(),
+                    error: format!(
+                        "Path isn't valid UTF-8: {}",
+                        e
+                    ),
+                },
+            ),
+        };
+        if written.is_err() {
+            // the server hung up
+            return Ok(());
         }
-        Err(e) => return Err(e),
     }
 }
 
-fn block(raw: &str) -> IResult<&str, Tree> {
-    return alt((
-        thematic_break,
-        atx_header,
-        blank_line,
-        paragraph,
-    ))(raw);
-}
+fn main() {
+    let args = Args::parse();
 
-fn document(raw: &str) -> IResult<&str, Tree> {
-    let blocks = many0(block)(raw);
-    match blocks {
-        Ok((stream, results)) => {
-            let root = Node::new(NodeType::DOCUMENT);
-            let mut tree = Tree::new(root);
-            for block in results {
-                tree.insert_child_under(
-                    block,
-                    tree.root_node.clone(),
-                );
+    if args.describe {
+        let json = to_string(&ParserDescription::builtin()).expect("");
+        io::stdout().write_all(json.as_bytes()).expect("");
+        return;
+    }
+
+    let mut out = io::stdout().lock();
+    if args.stdin {
+        if let Err(e) = serve_lines(
+            io::stdin().lock(),
+            &mut out,
+        ) {
+            eprintln!(
+                "Failed to read paths from stdin: {}",
+                e
+            );
+            process::exit(1);
+        }
+        return;
+    }
+    if args.paths.len() > 1 {
+        for path in args.paths.iter() {
+            if write_line(
+                &mut out, path,
+            )
+            .is_err()
+            {
+                return;
             }
-            return Ok((
-                stream, tree,
-            ));
         }
-        Err(e) => return Err(e),
+        return;
     }
-}
-
-fn main() {
-    let args = Args::parse();
 
-    let str = read_to_string(args.path.clone()).unwrap();
-    let (_, mut tree) = document(str.as_str()).unwrap();
-    let root = tree.get_node_mut(tree.root_node.clone());
-    root.unwrap().data = NodeData::DocumentData {
-        path: args.path.clone(),
-        loaded: false,
+    // errors go to stderr with a failing status, so the server can report them
+    let tree = match parse_path(&args.paths[0]) {
+        Ok(tree) => tree,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
     };
 
     let json = to_string(&tree).expect("");
 
-    io::stdout().write_all(json.as_bytes()).expect("");
+    out.write_all(json.as_bytes()).expect("");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn answers_every_line_even_when_it_cant_be_read() {
+        let root = TempDir::new().unwrap();
+        let note = root.path().join("note.md");
+        fs::write(
+            &note, "# Note\n",
+        )
+        .unwrap();
+        let note = note.to_str().unwrap().as_bytes();
+        let mut input = vec![];
+        input.extend_from_slice(b"/no/such/file.md\n\xff\xfe.md\r\n\n");
+        input.extend_from_slice(note);
+
+        let mut out = vec![];
+        serve_lines(
+            input.as_slice(),
+            &mut out,
+        )
+        .unwrap();
+        let lines: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines.len(),
+            3
+        );
+        assert_eq!(
+            lines[0]["path"],
+            "/no/such/file.md"
+        );
+        assert!(
+            lines[1]["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Path isn't valid UTF-8")
+        );
+        assert_eq!(
+            lines[1]["path"],
+            "\u{fffd}\u{fffd}.md"
+        );
+        assert_eq!(
+            lines[2]["nodes"].as_object().unwrap().len(),
+            2
+        );
+    }
 }
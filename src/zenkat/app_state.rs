@@ -1,17 +1,65 @@
-use crate::Tree;
+use crate::common::cache::ParseCache;
+use crate::common::parser::DocumentParser;
+use crate::common::tree::{HydrateOptions, LoadOptions};
+use crate::events::TreeEvent;
+use crate::jobs::Jobs;
+use crate::store::Store;
+use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
 
 #[derive(Clone)]
 pub struct AppState {
     pub app_config: AppConfig,
-    pub trees: Arc<Mutex<Vec<Tree>>>,
+    pub trees: Arc<Store>,
+    pub events: broadcast::Sender<TreeEvent>,
+    /// Background hydration jobs, see `Jobs::hydrate`.
+    pub jobs: Jobs,
+    /// Becomes `true` when the server starts shutting down, so responses
+    /// which would otherwise never end, like `/events`, can finish.
+    pub shutdown: watch::Receiver<bool>,
+}
+
+/// How one tree is walked and parsed. Trees can have their own parser and
+/// ignore patterns, see `[[tree]]` in docs/configuration.md.
+#[derive(Clone)]
+pub struct TreeSettings {
+    pub load_options: LoadOptions,
+    pub doc_parser: Arc<dyn DocumentParser>,
+    pub cache: Option<Arc<ParseCache>>,
 }
 
 #[derive(Clone)]
 pub struct AppConfig {
-    pub follow_symlinks: bool,
-    pub doc_parser: String,
+    /// The settings of each tree, by name.
+    pub trees: HashMap<String, TreeSettings>,
+    /// For trees which aren't in `trees`.
+    pub default_settings: TreeSettings,
     pub processes: NonZeroUsize,
+    pub parse_timeout: Duration,
+    /// Where the store is saved and restored from, if anywhere.
+    pub snapshot: Option<PathBuf>,
+}
+
+impl AppConfig {
+    pub fn settings(&self, tree: &str) -> &TreeSettings {
+        return self.trees.get(tree).unwrap_or(&self.default_settings);
+    }
+
+    pub fn load_options(&self, tree: &str) -> &LoadOptions {
+        return &self.settings(tree).load_options;
+    }
+
+    pub fn hydrate_options(&self, tree: &str) -> HydrateOptions {
+        let settings = self.settings(tree);
+        return HydrateOptions {
+            parser: settings.doc_parser.clone(),
+            processes: self.processes.get(),
+            timeout: self.parse_timeout,
+            cache: settings.cache.clone(),
+        };
+    }
 }
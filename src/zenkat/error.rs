@@ -0,0 +1,402 @@
+use axum::{
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
+    extract::{FromRequest, FromRequestParts},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+
+use crate::common::tree::{DocumentFailure, TreeState};
+
+/// The body of every error response, e.g.
+/// `{"code": "tree_not_found", "message": "No tree named notes"}`.
+/// `code` is stable for clients to match on, `message` is for people.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: String) -> ApiError {
+        return ApiError {
+            status,
+            code,
+            message,
+            details: None,
+        };
+    }
+
+    pub fn with_details(mut self, details: Value) -> ApiError {
+        self.details = Some(details);
+        return self;
+    }
+
+    pub fn tree_not_found(name: &str) -> ApiError {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "tree_not_found",
+            format!(
+                "No tree named {}",
+                name
+            ),
+        );
+    }
+
+    pub fn node_not_found(tree: &str, node: &str) -> ApiError {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "node_not_found",
+            format!(
+                "No node {} in {}",
+                node, tree
+            ),
+        );
+    }
+
+    pub fn job_not_found(id: &str) -> ApiError {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "job_not_found",
+            format!(
+                "No job {}",
+                id
+            ),
+        );
+    }
+
+    pub fn bad_selector(query: &str, error: String) -> ApiError {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "bad_selector",
+            error,
+        )
+        .with_details(json!({ "query": query }));
+    }
+
+    pub fn bad_request(message: String) -> ApiError {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "bad_request",
+            message,
+        );
+    }
+
+    /// The tree hasn't been walked yet, or couldn't be, so there's nothing
+    /// to return or change.
+    pub fn tree_not_loaded(name: &str, state: TreeState) -> ApiError {
+        return ApiError::new(
+            StatusCode::CONFLICT,
+            "tree_not_loaded",
+            format!(
+                "{} isn't loaded yet",
+                name
+            ),
+        )
+        .with_details(json!({ "state": state }));
+    }
+
+    pub fn job_finished(id: &str) -> ApiError {
+        return ApiError::new(
+            StatusCode::CONFLICT,
+            "job_finished",
+            format!(
+                "Job {} has already finished",
+                id
+            ),
+        );
+    }
+
+    /// A document the request needed couldn't be parsed.
+    pub fn parser_failed(failure: &DocumentFailure) -> ApiError {
+        return ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "parser_failed",
+            format!(
+                "Failed to load {}: {}",
+                failure.path, failure.error
+            ),
+        )
+        .with_details(json!({ "path": failure.path, "error": failure.error }));
+    }
+
+    pub fn internal(message: String) -> ApiError {
+        return ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            message,
+        );
+    }
+
+    /// For requests to routes that don't exist.
+    pub fn no_route() -> ApiError {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "no_route",
+            "No such route".into(),
+        );
+    }
+
+    pub fn method_not_allowed() -> ApiError {
+        return ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "The route doesn't accept this method".into(),
+        );
+    }
+
+    /// Replaces the empty 405 responses of routes called with the wrong
+    /// method, keeping their `Allow` header. Used as middleware, as those
+    /// responses don't come from a handler.
+    pub async fn replace_method_not_allowed(response: Response) -> Response {
+        if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+            return response;
+        }
+        let mut replaced = ApiError::method_not_allowed().into_response();
+        if let Some(allow) = response.headers().get(header::ALLOW) {
+            replaced.headers_mut().insert(
+                header::ALLOW,
+                allow.clone(),
+            );
+        }
+        return replaced;
+    }
+
+    /// For handlers that panicked, rather than dropping the connection.
+    pub fn from_panic(panic: Box<dyn Any + Send + 'static>) -> Response {
+        let message = match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => match panic.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => "Unknown panic".into(),
+            },
+        };
+        eprintln!(
+            "Handler panicked: {}",
+            message
+        );
+        return ApiError::internal(message).into_response();
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        return (
+            self.status,
+            Json(self),
+        )
+            .into_response();
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        return ApiError::new(
+            rejection.status(),
+            "bad_request",
+            rejection.body_text(),
+        );
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        return ApiError::new(
+            rejection.status(),
+            "bad_request",
+            rejection.body_text(),
+        );
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        return ApiError::new(
+            rejection.status(),
+            "bad_request",
+            rejection.body_text(),
+        );
+    }
+}
+
+/// `axum::Json`, rejecting bad bodies with an `ApiError`.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+/// `axum::extract::Query`, rejecting bad query strings with an `ApiError`.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct ApiQuery<T>(pub T);
+
+/// `axum::extract::Path`, rejecting bad paths with an `ApiError`.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct ApiPath<T>(pub T);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::parser::ParseError;
+    use http_body_util::BodyExt;
+
+    async fn body(response: Response) -> Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        return serde_json::from_slice(&bytes).unwrap();
+    }
+
+    #[test]
+    fn each_code_has_its_status() {
+        let failure = DocumentFailure {
+            path: "notes/bad.md".into(),
+            error: ParseError::Timeout { seconds: 30.0 },
+        };
+        let errors = [
+            (
+                ApiError::bad_selector(
+                    "[[[",
+                    "Invalid".into(),
+                ),
+                StatusCode::BAD_REQUEST,
+                "bad_selector",
+            ),
+            (
+                ApiError::bad_request("Bad".into()),
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+            ),
+            (
+                ApiError::tree_not_found("notes"),
+                StatusCode::NOT_FOUND,
+                "tree_not_found",
+            ),
+            (
+                ApiError::node_not_found(
+                    "notes", "todo.md",
+                ),
+                StatusCode::NOT_FOUND,
+                "node_not_found",
+            ),
+            (
+                ApiError::job_not_found("job"),
+                StatusCode::NOT_FOUND,
+                "job_not_found",
+            ),
+            (
+                ApiError::no_route(),
+                StatusCode::NOT_FOUND,
+                "no_route",
+            ),
+            (
+                ApiError::method_not_allowed(),
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method_not_allowed",
+            ),
+            (
+                ApiError::tree_not_loaded(
+                    "notes",
+                    TreeState::LOADING_METADATA,
+                ),
+                StatusCode::CONFLICT,
+                "tree_not_loaded",
+            ),
+            (
+                ApiError::job_finished("job"),
+                StatusCode::CONFLICT,
+                "job_finished",
+            ),
+            (
+                ApiError::parser_failed(&failure),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "parser_failed",
+            ),
+            (
+                ApiError::internal("Broken".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+        ];
+        for (error, status, code) in errors {
+            assert_eq!(
+                (
+                    error.status,
+                    error.code
+                ),
+                (status, code)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_carry_the_status_and_a_json_body() {
+        let response = ApiError::tree_not_loaded(
+            "notes",
+            TreeState::LOADING_METADATA,
+        )
+        .into_response();
+        assert_eq!(
+            response.status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            body(response).await,
+            json!({
+                "code": "tree_not_loaded",
+                "message": "notes isn't loaded yet",
+                "details": {"state": "LOADING_METADATA"},
+            })
+        );
+        // no details, no field
+        let response = ApiError::no_route().into_response();
+        assert_eq!(
+            body(response).await,
+            json!({"code": "no_route", "message": "No such route"})
+        );
+    }
+
+    #[tokio::test]
+    async fn only_405s_are_replaced() {
+        let empty = (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(
+                header::ALLOW,
+                "GET,DELETE",
+            )],
+        )
+            .into_response();
+        let replaced = ApiError::replace_method_not_allowed(empty).await;
+        assert_eq!(
+            replaced.headers()[header::ALLOW],
+            "GET,DELETE"
+        );
+        assert_eq!(
+            body(replaced).await["code"],
+            "method_not_allowed"
+        );
+
+        let other = ApiError::no_route().into_response();
+        let kept = ApiError::replace_method_not_allowed(other).await;
+        assert_eq!(
+            body(kept).await["code"],
+            "no_route"
+        );
+    }
+
+    #[test]
+    fn panics_become_internal_errors() {
+        let response = ApiError::from_panic(Box::new(
+            "oops",
+        ));
+        assert_eq!(
+            response.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use serde::Serialize;
+
+/// Changes pushed to clients subscribed to `GET /events`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TreeEvent {
+    TreeLoaded {
+        tree: String,
+    },
+    TreeUnloaded {
+        tree: String,
+    },
+    DocumentAdded {
+        tree: String,
+        node: String,
+    },
+    DocumentRemoved {
+        tree: String,
+        node: String,
+    },
+    NodeChanged {
+        tree: String,
+        node: String,
+    },
+    HydrationProgress {
+        tree: String,
+        done: usize,
+        total: usize,
+    },
+}
+
+impl TreeEvent {
+    /// The event name used for the SSE `event:` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TreeEvent::TreeLoaded { .. } => return "tree_loaded",
+            TreeEvent::TreeUnloaded { .. } => return "tree_unloaded",
+            TreeEvent::DocumentAdded { .. } => return "document_added",
+            TreeEvent::DocumentRemoved { .. } => return "document_removed",
+            TreeEvent::NodeChanged { .. } => return "node_changed",
+            TreeEvent::HydrationProgress { .. } => return "hydration_progress",
+        }
+    }
+
+    pub fn tree(&self) -> &str {
+        match self {
+            TreeEvent::TreeLoaded { tree }
+            | TreeEvent::TreeUnloaded { tree }
+            | TreeEvent::DocumentAdded { tree, .. }
+            | TreeEvent::DocumentRemoved { tree, .. }
+            | TreeEvent::NodeChanged { tree, .. }
+            | TreeEvent::HydrationProgress { tree, .. } => return tree,
+        }
+    }
+
+    /// The node the event is about, if it concerns a single node.
+    pub fn node(&self) -> Option<&str> {
+        match self {
+            TreeEvent::DocumentAdded { node, .. }
+            | TreeEvent::DocumentRemoved { node, .. }
+            | TreeEvent::NodeChanged { node, .. } => return Some(node),
+            _ => return None,
+        }
+    }
+}
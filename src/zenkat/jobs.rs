@@ -0,0 +1,460 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+use crate::common::tree::{parse_documents, HydrationScope};
+use crate::events::TreeEvent;
+use crate::store::TreeCell;
+
+/// The least time a job waits between publishing versions of a tree.
+const MIN_PUBLISH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a job is kept around after it's over, for clients to poll.
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum JobState {
+    RUNNING,
+    FINISHED,
+    CANCELLED,
+}
+
+/// The progress of a hydration job, as returned by `GET /jobs/:id`.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub tree: String,
+    pub state: JobState,
+    /// Documents parsed so far, including those which failed.
+    pub done: usize,
+    pub failed: usize,
+    pub total: usize,
+    /// Seconds since the job started, or that it ran for once it's over.
+    pub elapsed: f64,
+}
+
+/// A handle on a running or finished hydration job.
+#[derive(Clone)]
+pub struct HydrationJob {
+    status: watch::Receiver<JobStatus>,
+    started: Instant,
+    cancel: Arc<Notify>,
+}
+
+impl HydrationJob {
+    pub fn status(&self) -> JobStatus {
+        let mut status = self.status.borrow().clone();
+        if status.state == JobState::RUNNING {
+            status.elapsed = self.started.elapsed().as_secs_f64();
+        }
+        return status;
+    }
+
+    /// Asks the job to stop. Documents it hasn't parsed yet are left
+    /// dehydrated.
+    pub fn cancel(&self) {
+        // stores a permit, so a job between documents still sees it
+        self.cancel.notify_one();
+    }
+
+    /// Whether, at `now`, the job has been over for longer than
+    /// `FINISHED_JOB_TTL`.
+    fn expired(&self, now: Instant) -> bool {
+        let status = self.status.borrow();
+        if status.state == JobState::RUNNING {
+            return false;
+        }
+        let over_for = now.duration_since(self.started).as_secs_f64() - status.elapsed;
+        return over_for > FINISHED_JOB_TTL.as_secs_f64();
+    }
+
+    /// Waits for the job to finish or be cancelled.
+    pub async fn wait(&self) -> JobStatus {
+        let mut status = self.status.clone();
+        // the sender is only dropped once the job is over
+        let _ = status
+            .wait_for(|status| status.state != JobState::RUNNING)
+            .await;
+        return self.status();
+    }
+}
+
+/// Every hydration job the server has started, by id.
+#[derive(Clone, Default)]
+pub struct Jobs {
+    jobs: Arc<StdMutex<HashMap<String, HydrationJob>>>,
+}
+
+impl Jobs {
+    /// The jobs, without those which have expired.
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, HydrationJob>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let now = Instant::now();
+        jobs.retain(|_, job| !job.expired(now));
+        return jobs;
+    }
+
+    pub fn get(&self, id: &str) -> Option<HydrationJob> {
+        return self.lock().get(id).cloned();
+    }
+
+    /// The status of every job, oldest first.
+    pub fn list(&self) -> Vec<JobStatus> {
+        let jobs = self.lock();
+        let mut jobs: Vec<&HydrationJob> = jobs.values().collect();
+        jobs.sort_by_key(|job| job.started);
+        return jobs.iter().map(|job| job.status()).collect();
+    }
+
+    /// The running job hydrating the tree `name`, if there is one.
+    pub fn running(&self, name: &str) -> Option<HydrationJob> {
+        return self
+            .lock()
+            .values()
+            .find(|job| {
+                let status = job.status.borrow();
+                return status.tree == name && status.state == JobState::RUNNING;
+            })
+            .cloned();
+    }
+
+    /// Starts hydrating the dehydrated documents in `scope` of the tree
+    /// `name` in the background, or returns the job already hydrating the
+    /// tree, which may have been started for another scope. Returns `None`
+    /// if there's no such tree or nothing in `scope` to hydrate.
+    pub async fn hydrate(
+        state: &AppState,
+        name: &str,
+        scope: &HydrationScope,
+    ) -> Option<HydrationJob> {
+        let cell = state.trees.get(name)?;
+        if let Some(job) = state.jobs.running(name) {
+            return Some(job);
+        }
+        // most reads find the tree hydrated, and shouldn't have to copy it
+        if !cell.read().has_dehydrated(scope) {
+            return None;
+        }
+        // checked again while writing, as only one request can be writing
+        // to the tree, so two requests can't both start a job
+        let job = cell
+            .update(|tree| {
+                if let Some(job) = state.jobs.running(name) {
+                    return Some(job);
+                }
+                let pending = tree.start_hydrating(scope);
+                if pending.is_empty() {
+                    return None;
+                }
+                let id = Uuid::new_v4().to_string();
+                let (status_tx, status_rx) = watch::channel(
+                    JobStatus {
+                        id: id.clone(),
+                        tree: name.into(),
+                        state: JobState::RUNNING,
+                        done: 0,
+                        failed: 0,
+                        total: pending.len(),
+                        elapsed: 0.0,
+                    },
+                );
+                let job = HydrationJob {
+                    status: status_rx,
+                    started: Instant::now(),
+                    cancel: Arc::new(Notify::new()),
+                };
+                state.jobs.lock().insert(
+                    id,
+                    job.clone(),
+                );
+                tokio::spawn(run(
+                    state.clone(),
+                    cell.clone(),
+                    pending,
+                    status_tx,
+                    job.clone(),
+                ));
+                return Some(job);
+            })
+            .await;
+        return job;
+    }
+}
+
+/// Splices documents into the tree as they're parsed, in batches so the
+/// tree isn't copied for every document. A batch is published after
+/// `MIN_PUBLISH_INTERVAL`, or four times as long as the last one took to
+/// splice, so copying big trees doesn't take over from parsing.
+async fn run(
+    state: AppState,
+    cell: Arc<TreeCell>,
+    pending: Vec<(
+        String,
+        String,
+    )>,
+    status: watch::Sender<JobStatus>,
+    job: HydrationJob,
+) {
+    let name = status.borrow().tree.clone();
+    let total = pending.len();
+    let mut from_cache = 0;
+    let mut parsed = parse_documents(
+        pending,
+        state.app_config.hydrate_options(&name),
+    );
+    let mut cancelled = false;
+    let mut finished = false;
+    let mut interval = MIN_PUBLISH_INTERVAL;
+    while !finished {
+        let mut batch = vec![];
+        let mut deadline = tokio::time::Instant::now();
+        loop {
+            let next = tokio::select! {
+                document = parsed.recv() => document,
+                _ = tokio::time::sleep_until(deadline), if !batch.is_empty() => break,
+                _ = job.cancel.notified() => {
+                    cancelled = true;
+                    None
+                }
+            };
+            match next {
+                Some(document) => {
+                    if batch.is_empty() {
+                        deadline = tokio::time::Instant::now() + interval;
+                    }
+                    batch.push(document);
+                }
+                None => {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        if batch.is_empty() {
+            continue;
+        }
+        let splicing = Instant::now();
+        from_cache += batch.iter().filter(|document| document.cached).count();
+        let results: Vec<(
+            String,
+            bool,
+        )> = cell
+            .update(|tree| {
+                return batch
+                    .into_iter()
+                    .map(
+                        |document| {
+                            let node_id = document.node_id.clone();
+                            return (
+                                node_id,
+                                tree.finish_hydrating(document).is_ok(),
+                            );
+                        },
+                    )
+                    .collect();
+            })
+            .await;
+        interval = MIN_PUBLISH_INTERVAL.max(splicing.elapsed() * 4);
+        for (node_id, succeeded) in results {
+            status.send_modify(|status| {
+                status.done += 1;
+                if !succeeded {
+                    status.failed += 1;
+                }
+            });
+            // sending only fails when nobody is subscribed
+            let _ = state.events.send(
+                match succeeded {
+                    true => TreeEvent::DocumentAdded {
+                        tree: name.clone(),
+                        node: node_id,
+                    },
+                    // still dehydrated, but now with a failure recorded
+                    false => TreeEvent::NodeChanged {
+                        tree: name.clone(),
+                        node: node_id,
+                    },
+                },
+            );
+            let _ = state.events.send(
+                TreeEvent::HydrationProgress {
+                    tree: name.clone(),
+                    done: status.borrow().done,
+                    total,
+                },
+            );
+        }
+    }
+    // stops the parsers still running
+    drop(parsed);
+
+    cell.update(|tree| tree.stop_hydrating()).await;
+    status.send_modify(|status| {
+        status.state = match cancelled {
+            true => JobState::CANCELLED,
+            false => JobState::FINISHED,
+        };
+        status.elapsed = job.started.elapsed().as_secs_f64();
+    });
+    let status = status.borrow();
+    eprintln!(
+        "Loaded {} documents in {} ({} failed, {} from cache) in {:.4}s{}.",
+        status.done,
+        name,
+        status.failed,
+        from_cache,
+        status.elapsed,
+        if cancelled { ", then cancelled" } else { "" }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{serve, GatedParser};
+
+    fn job(state: JobState, elapsed: f64) -> HydrationJob {
+        let (_, status) = watch::channel(
+            JobStatus {
+                id: "job".into(),
+                tree: "notes".into(),
+                state,
+                done: 0,
+                failed: 0,
+                total: 1,
+                elapsed,
+            },
+        );
+        return HydrationJob {
+            status,
+            started: Instant::now(),
+            cancel: Arc::new(Notify::new()),
+        };
+    }
+
+    #[test]
+    fn finished_jobs_expire_after_the_ttl() {
+        let ran_for = 5.0;
+        for state in [JobState::FINISHED, JobState::CANCELLED] {
+            let job = job(
+                state, ran_for,
+            );
+            let finished = job.started + Duration::from_secs_f64(ran_for);
+            assert!(!job.expired(finished + FINISHED_JOB_TTL - Duration::from_secs(1)));
+            assert!(job.expired(finished + FINISHED_JOB_TTL + Duration::from_secs(1)));
+        }
+    }
+
+    #[test]
+    fn running_jobs_never_expire() {
+        let job = job(
+            JobState::RUNNING,
+            0.0,
+        );
+        assert!(!job.expired(job.started + 10 * FINISHED_JOB_TTL));
+    }
+
+    #[test]
+    fn expired_jobs_are_dropped() {
+        let jobs = Jobs::default();
+        let fresh = job(
+            JobState::FINISHED,
+            0.0,
+        );
+        let mut old = job(
+            JobState::FINISHED,
+            0.0,
+        );
+        // started long enough ago to have expired, if the clock allows
+        let Some(started) = Instant::now().checked_sub(FINISHED_JOB_TTL * 2) else {
+            return;
+        };
+        old.started = started;
+        jobs.jobs.lock().unwrap().insert(
+            "fresh".into(),
+            fresh,
+        );
+        jobs.jobs.lock().unwrap().insert(
+            "old".into(),
+            old,
+        );
+        assert!(jobs.get("old").is_none());
+        assert!(jobs.get("fresh").is_some());
+        assert_eq!(
+            jobs.list().len(),
+            1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_hydrates_share_one_job() {
+        let parser = GatedParser::closed();
+        let (_root, state) = serve(
+            &[
+                (
+                    "a.md",
+                    "# Alpha\n",
+                ),
+                (
+                    "b.md", "# Beta\n",
+                ),
+            ],
+            parser.clone(),
+        )
+        .await;
+        let requests: Vec<_> = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                return tokio::spawn(
+                    async move {
+                        let job = Jobs::hydrate(
+                            &state,
+                            "notes",
+                            &HydrationScope::default(),
+                        )
+                        .await;
+                        return job.unwrap().status().id;
+                    },
+                );
+            })
+            .collect();
+        let mut ids = vec![];
+        for request in requests {
+            ids.push(request.await.unwrap());
+        }
+        assert!(ids.iter().all(|id| *id == ids[0]));
+        assert_eq!(
+            state.jobs.list().len(),
+            1
+        );
+
+        parser.allow(2);
+        let status = state.jobs.get(&ids[0]).unwrap().wait().await;
+        assert_eq!(
+            (
+                status.state,
+                status.done,
+                status.total
+            ),
+            (
+                JobState::FINISHED,
+                2,
+                2
+            )
+        );
+        // and once it's over, there's nothing left to start another for
+        assert!(
+            Jobs::hydrate(
+                &state,
+                "notes",
+                &HydrationScope::default()
+            )
+            .await
+            .is_none()
+        );
+    }
+}
@@ -0,0 +1,1369 @@
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{canonicalize, read_to_string};
+use std::path::{Path, PathBuf};
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Stdout,
+};
+
+use crate::app_state::AppConfig;
+use crate::common::links::{self, ImageLink, WikiLink};
+use crate::common::node::{NodeData, NodeType};
+use crate::common::tree::Tree;
+
+// LSP constants, see the specification for their meaning.
+const ERROR_PARSE: i64 = -32700;
+const ERROR_METHOD_NOT_FOUND: i64 = -32601;
+const TEXT_DOCUMENT_SYNC_FULL: i64 = 1;
+const SYMBOL_KIND_FILE: i64 = 1;
+const SYMBOL_KIND_STRING: i64 = 15;
+const COMPLETION_KIND_FILE: i64 = 17;
+const COMPLETION_KIND_KEYWORD: i64 = 14;
+const DIAGNOSTIC_WARNING: i64 = 2;
+
+/// A document known to one of the loaded trees.
+struct DocumentEntry {
+    tree: usize,
+    node_id: String,
+    path: PathBuf,
+    /// The file name without its extension, which is how `[[links]]` refer to it.
+    stem: String,
+}
+
+/// A `[[link]]` in the text of a document, positioned in UTF-16 units.
+struct IndexedLink {
+    target: String,
+    line: usize,
+    start: usize,
+    end: usize,
+}
+
+/// A heading located in the text of a document.
+struct Heading {
+    text: String,
+    level: usize,
+    line: usize,
+    /// Length of the line in UTF-16 code units.
+    width: usize,
+}
+
+/// Serves the Language Server Protocol over stdio, backed by `trees`.
+/// The trees should be fully hydrated before calling this.
+pub struct LanguageServer {
+    trees: Vec<Tree>,
+    /// How to load and parse documents which are saved while serving.
+    app_config: AppConfig,
+    documents: Vec<DocumentEntry>,
+    /// The `[[links]]` in each document, by path, so finding backlinks
+    /// doesn't read every document again. Kept up to date as documents
+    /// are edited and saved.
+    link_index: HashMap<PathBuf, Vec<IndexedLink>>,
+    /// Text of documents the client has open, by URI.
+    open_documents: HashMap<String, String>,
+    stdout: Stdout,
+}
+
+impl LanguageServer {
+    pub fn new(trees: Vec<Tree>, app_config: AppConfig) -> Self {
+        let documents = document_entries(&trees);
+        let mut server = LanguageServer {
+            trees,
+            app_config,
+            documents,
+            link_index: HashMap::new(),
+            open_documents: HashMap::new(),
+            stdout: io::stdout(),
+        };
+        let paths: Vec<PathBuf> = server
+            .documents
+            .iter()
+            .map(|document| document.path.clone())
+            .collect();
+        for path in paths {
+            server.index_links(&path);
+        }
+        return server;
+    }
+
+    /// Handles messages until the client sends `exit` or closes stdin.
+    pub async fn run(&mut self) -> io::Result<()> {
+        let mut stdin = BufReader::new(io::stdin());
+        while let Some(message) = read_message(&mut stdin).await? {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    // the id can't be known, so the response has none
+                    let response = json!({
+                        "jsonrpc": "2.0",
+                        "id": null,
+                        "error": {"code": ERROR_PARSE, "message": e},
+                    });
+                    self.send(&response).await?;
+                    continue;
+                }
+            };
+            let method = message["method"].as_str().unwrap_or("").to_string();
+            let params = message["params"].clone();
+            let id = message.get("id").cloned();
+
+            if method == "exit" {
+                break;
+            }
+
+            match id {
+                // requests expect exactly one response
+                Some(id) => {
+                    let response = match self.handle_request(
+                        &method, &params,
+                    ) {
+                        Some(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                        None => json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "error": {
+                                "code": ERROR_METHOD_NOT_FOUND,
+                                "message": format!("Unsupported method: {}", method),
+                            },
+                        }),
+                    };
+                    self.send(&response).await?;
+                }
+                None => {
+                    self.handle_notification(
+                        &method, &params,
+                    )
+                    .await?
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn handle_request(&self, method: &str, params: &Value) -> Option<Value> {
+        match method {
+            "initialize" => {
+                return Some(json!({
+                    "capabilities": {
+                        "textDocumentSync": TEXT_DOCUMENT_SYNC_FULL,
+                        "definitionProvider": true,
+                        "referencesProvider": true,
+                        "completionProvider": {"triggerCharacters": ["[", "#"]},
+                        "documentSymbolProvider": true,
+                        "workspaceSymbolProvider": true,
+                    },
+                    "serverInfo": {"name": "zenkat", "version": env!("CARGO_PKG_VERSION")},
+                }))
+            }
+            "shutdown" => return Some(Value::Null),
+            "textDocument/definition" => return Some(self.definition(params)),
+            "textDocument/references" => return Some(self.references(params)),
+            "textDocument/completion" => return Some(self.completion(params)),
+            "textDocument/documentSymbol" => return Some(self.document_symbols(params)),
+            "workspace/symbol" => return Some(self.workspace_symbols(params)),
+            _ => return None,
+        }
+    }
+
+    async fn handle_notification(&mut self, method: &str, params: &Value) -> io::Result<()> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.open_document(&uri, text);
+            }
+            "textDocument/didChange" => {
+                // with full sync the last change holds the whole document
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                {
+                    self.open_document(&uri, text);
+                }
+            }
+            "textDocument/didSave" => {
+                self.refresh_document(&uri).await;
+                self.reindex_links(&uri);
+                // links to and from other documents may resolve differently now
+                let open: Vec<String> = self.open_documents.keys().cloned().collect();
+                for open_uri in open {
+                    self.publish_diagnostics(&open_uri).await?;
+                }
+                if self.open_documents.contains_key(&uri) {
+                    return Ok(());
+                }
+            }
+            "textDocument/didClose" => {
+                self.open_documents.remove(&uri);
+                // back to what's saved
+                self.reindex_links(&uri);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+        return self.publish_diagnostics(&uri).await;
+    }
+
+    async fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = self.diagnostics(uri);
+        return self
+            .send(&json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/publishDiagnostics",
+                "params": {"uri": uri, "diagnostics": diagnostics},
+            }))
+            .await;
+    }
+
+    /// Brings the trees up to date with the file at `uri` after it was
+    /// saved: parses it again, adds it if it's new to its tree, or removes
+    /// it if it's gone.
+    async fn refresh_document(&mut self, uri: &str) {
+        let path = uri_to_path(uri);
+        let known = self.document_for_uri(uri).map(
+            |document| {
+                (
+                    document.tree,
+                    document.node_id.clone(),
+                )
+            },
+        );
+        let (tree_idx, node_id) = match known {
+            Some((tree_idx, node_id)) if path.is_file() => (
+                tree_idx, node_id,
+            ),
+            Some((tree_idx, node_id)) => {
+                let _ = self.trees[tree_idx].remove_subtree(&node_id);
+                self.documents = document_entries(&self.trees);
+                return;
+            }
+            None => {
+                let tree_idx = match self.tree_containing(&canonical_path(&path)) {
+                    Some(tree_idx) => tree_idx,
+                    None => return,
+                };
+                let tree = &mut self.trees[tree_idx];
+                let options = self.app_config.load_options(&tree.name);
+                match tree.add_document(
+                    &path, options,
+                ) {
+                    Some(node_id) => (
+                        tree_idx, node_id,
+                    ),
+                    None => return,
+                }
+            }
+        };
+        let tree = &mut self.trees[tree_idx];
+        let options = self.app_config.hydrate_options(&tree.name);
+        if let Err(e) = tree
+            .rehydrate_document(
+                &node_id, &options,
+            )
+            .await
+        {
+            eprintln!(
+                "Failed to parse {}: {}",
+                path.display(),
+                e
+            );
+        }
+        self.documents = document_entries(&self.trees);
+    }
+
+    /// Records the client's copy of the document at `uri`.
+    fn open_document(&mut self, uri: &str, text: &str) {
+        self.open_documents.insert(
+            uri.into(),
+            text.into(),
+        );
+        self.reindex_links(uri);
+    }
+
+    /// Indexes the links of the document at `uri` again after its text
+    /// changed, and forgets documents which are no longer in a tree.
+    fn reindex_links(&mut self, uri: &str) {
+        let documents = &self.documents;
+        self.link_index
+            .retain(|path, _| documents.iter().any(|d| &d.path == path));
+        if let Some(path) = self.document_for_uri(uri).map(|d| d.path.clone()) {
+            self.index_links(&path);
+        }
+    }
+
+    fn index_links(&mut self, path: &Path) {
+        let text = self.text_of(path);
+        let mut links = vec![];
+        for (line_no, line) in text.lines().enumerate() {
+            for link in links::wiki_links(line) {
+                links.push(
+                    IndexedLink {
+                        target: link.target,
+                        line: line_no,
+                        start: utf16_len(&line[..link.start]),
+                        end: utf16_len(&line[..link.end]),
+                    },
+                );
+            }
+        }
+        self.link_index.insert(
+            path.to_path_buf(),
+            links,
+        );
+    }
+
+    /// The tree whose root is the deepest directory containing `path`.
+    fn tree_containing(&self, path: &Path) -> Option<usize> {
+        return self
+            .trees
+            .iter()
+            .enumerate()
+            .filter_map(
+                |(tree_idx, tree)| match &tree.get_root().data {
+                    NodeData::DirectoryData { canonical, .. } if path.starts_with(canonical) => {
+                        Some((
+                            tree_idx,
+                            canonical.len(),
+                        ))
+                    }
+                    _ => None,
+                },
+            )
+            .max_by_key(|(_, depth)| *depth)
+            .map(|(tree_idx, _)| tree_idx);
+    }
+
+    async fn send(&mut self, message: &Value) -> io::Result<()> {
+        let body = message.to_string();
+        let header = format!(
+            "Content-Length: {}\r\n\r\n",
+            body.len()
+        );
+        self.stdout.write_all(header.as_bytes()).await?;
+        self.stdout.write_all(body.as_bytes()).await?;
+        return self.stdout.flush().await;
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        if let Some(image) = self.image_at(
+            uri,
+            &params["position"],
+        ) {
+            return match self.resolve_image(
+                uri,
+                &image.target,
+            ) {
+                Some(path) => location(
+                    &path, 0, 0, 0,
+                ),
+                None => Value::Null,
+            };
+        }
+        let link = match self.link_at(
+            uri,
+            &params["position"],
+        ) {
+            Some(link) => link,
+            None => return Value::Null,
+        };
+        let target = match self.resolve(&link.target) {
+            Some(target) => target,
+            None => return Value::Null,
+        };
+        let line = match &link.heading {
+            Some(heading) => self
+                .headings(target)
+                .into_iter()
+                .find(|h| h.text.eq_ignore_ascii_case(heading))
+                .map_or(0, |h| {
+                    h.line
+                }),
+            None => 0,
+        };
+        return location(
+            &target.path,
+            line,
+            0,
+            0,
+        );
+    }
+
+    /// Backlinks to the link under the cursor, or to the current document
+    /// if the cursor isn't on a link.
+    fn references(&self, params: &Value) -> Value {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let target = match self.link_at(
+            uri,
+            &params["position"],
+        ) {
+            Some(link) => self.resolve(&link.target),
+            None => self.document_for_uri(uri),
+        };
+        let target = match target {
+            Some(target) => target,
+            None => return json!([]),
+        };
+
+        let mut locations = vec![];
+        for document in self.documents.iter() {
+            let links = match self.link_index.get(&document.path) {
+                Some(links) => links,
+                None => continue,
+            };
+            for link in links.iter() {
+                let resolved = self.resolve(&link.target);
+                if resolved.is_some_and(|r| r.path == target.path) {
+                    locations.push(location(
+                        &document.path,
+                        link.line,
+                        link.start,
+                        link.end,
+                    ));
+                }
+            }
+        }
+        return Value::Array(locations);
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let (line, offset) = match self.line_at(
+            uri,
+            &params["position"],
+        ) {
+            Some(found) => found,
+            None => return json!([]),
+        };
+        let prefix = &line[..offset];
+
+        let open = prefix.rfind("[[");
+        if open.is_some() && open > prefix.rfind("]]") {
+            let mut seen = BTreeSet::new();
+            let items: Vec<Value> = self
+                .documents
+                .iter()
+                .filter(|document| seen.insert(document.stem.clone()))
+                .map(
+                    |document| {
+                        json!({
+                            "label": document.stem,
+                            "kind": COMPLETION_KIND_FILE,
+                            "detail": document.path.to_string_lossy(),
+                        })
+                    },
+                )
+                .collect();
+            return Value::Array(items);
+        }
+
+        let word_start = prefix.rfind(|c: char| !links::is_tag_char(c)).unwrap_or(0);
+        let starts_tag = prefix[word_start..].starts_with('#')
+            && prefix[..word_start]
+                .chars()
+                .last()
+                .is_none_or(char::is_whitespace);
+        if starts_tag {
+            let items: Vec<Value> = self
+                .all_tags()
+                .into_iter()
+                .map(|tag| {
+                    json!({
+                        "label": format!("#{}", tag),
+                        "insertText": tag,
+                        "kind": COMPLETION_KIND_KEYWORD,
+                    })
+                })
+                .collect();
+            return Value::Array(items);
+        }
+        return json!([]);
+    }
+
+    fn document_symbols(&self, params: &Value) -> Value {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let document = match self.document_for_uri(uri) {
+            Some(document) => document,
+            None => return json!([]),
+        };
+
+        // nest headings by level, so an h2 sits under the h1 before it
+        let headings = self.headings(document);
+        let mut roots: Vec<Value> = vec![];
+        let mut stack: Vec<(
+            usize,
+            Value,
+        )> = vec![];
+        for heading in headings {
+            while stack
+                .last()
+                .is_some_and(|(level, _)| *level >= heading.level)
+            {
+                let (_, finished) = stack.pop().unwrap();
+                push_symbol(
+                    &mut stack, &mut roots, finished,
+                );
+            }
+            let range = range(
+                heading.line,
+                0,
+                heading.width,
+            );
+            stack.push((
+                heading.level,
+                json!({
+                    "name": heading.text,
+                    "kind": SYMBOL_KIND_STRING,
+                    "range": range,
+                    "selectionRange": range,
+                    "children": [],
+                }),
+            ));
+        }
+        while let Some((_, finished)) = stack.pop() {
+            push_symbol(
+                &mut stack, &mut roots, finished,
+            );
+        }
+        return Value::Array(roots);
+    }
+
+    fn workspace_symbols(&self, params: &Value) -> Value {
+        let query = params["query"].as_str().unwrap_or("").to_lowercase();
+        let mut symbols = vec![];
+        for document in self.documents.iter() {
+            if document.stem.to_lowercase().contains(&query) {
+                symbols.push(json!({
+                    "name": document.stem,
+                    "kind": SYMBOL_KIND_FILE,
+                    "location": location(&document.path, 0, 0, 0),
+                }));
+            }
+            for heading in self.headings(document) {
+                if heading.text.to_lowercase().contains(&query) {
+                    symbols.push(json!({
+                        "name": heading.text,
+                        "kind": SYMBOL_KIND_STRING,
+                        "location": location(&document.path, heading.line, 0, heading.width),
+                        "containerName": document.stem,
+                    }));
+                }
+            }
+        }
+        return Value::Array(symbols);
+    }
+
+    /// Warnings for every `[[link]]` that doesn't resolve to a document,
+    /// and every image that doesn't resolve to an attachment.
+    fn diagnostics(&self, uri: &str) -> Value {
+        let text = match self.open_documents.get(uri) {
+            Some(text) => text.clone(),
+            None => self.text_of(&uri_to_path(uri)),
+        };
+        let mut diagnostics = vec![];
+        for (line_no, line) in text.lines().enumerate() {
+            let images = links::image_links(line);
+            for image in images.iter() {
+                if image.target.contains("://")
+                    || self
+                        .resolve_image(
+                            uri,
+                            &image.target,
+                        )
+                        .is_some()
+                {
+                    continue;
+                }
+                diagnostics.push(json!({
+                    "range": range(
+                        line_no,
+                        utf16_len(&line[..image.start]),
+                        utf16_len(&line[..image.end]),
+                    ),
+                    "severity": DIAGNOSTIC_WARNING,
+                    "source": "zenkat",
+                    "message": format!("Broken image: no attachment at '{}'", image.target),
+                }));
+            }
+            for link in links::wiki_links(line) {
+                // embeds were checked as images
+                if images.iter().any(|image| image.end == link.end) {
+                    continue;
+                }
+                if self.resolve(&link.target).is_some() {
+                    continue;
+                }
+                diagnostics.push(json!({
+                    "range": range(
+                        line_no,
+                        utf16_len(&line[..link.start]),
+                        utf16_len(&line[..link.end]),
+                    ),
+                    "severity": DIAGNOSTIC_WARNING,
+                    "source": "zenkat",
+                    "message": format!("Broken link: no note named '{}'", link.target),
+                }));
+            }
+        }
+        return Value::Array(diagnostics);
+    }
+
+    /// Finds the document a link target refers to, either by file name
+    /// (`[[todo]]`) or by a path suffix (`[[notes/todo]]`).
+    fn resolve(&self, target: &str) -> Option<&DocumentEntry> {
+        let target = target.trim_end_matches(".md").to_lowercase();
+        return self.documents.iter().find(
+            |document| {
+                if document.stem.to_lowercase() == target {
+                    return true;
+                }
+                let without_ext = document.path.with_extension("");
+                return without_ext
+                    .to_string_lossy()
+                    .to_lowercase()
+                    .ends_with(&format!(
+                        "/{}",
+                        target
+                    ));
+            },
+        );
+    }
+
+    /// Finds the attachment an image in the document at `uri` points to.
+    fn resolve_image(&self, uri: &str, target: &str) -> Option<PathBuf> {
+        let document = self.document_for_uri(uri)?;
+        let attachment = self.trees[document.tree].resolve_attachment(
+            &document.node_id,
+            target,
+        )?;
+        return match &attachment.data {
+            NodeData::AttachmentData { path, .. } => {
+                Some(canonicalize(path).unwrap_or(PathBuf::from(path)))
+            }
+            _ => None,
+        };
+    }
+
+    fn document_for_uri(&self, uri: &str) -> Option<&DocumentEntry> {
+        let path = canonical_path(&uri_to_path(uri));
+        return self.documents.iter().find(|document| document.path == path);
+    }
+
+    /// The text of a document, preferring the client's unsaved copy.
+    fn text_of(&self, path: &Path) -> String {
+        if let Some(text) = self.open_documents.get(&path_to_uri(path)) {
+            return text.clone();
+        }
+        return read_to_string(path).unwrap_or_default();
+    }
+
+    /// The line under an LSP position and the cursor's byte offset into it.
+    fn line_at(
+        &self,
+        uri: &str,
+        position: &Value,
+    ) -> Option<(
+        String,
+        usize,
+    )> {
+        let line_no = position["line"].as_u64()? as usize;
+        let character = position["character"].as_u64()? as usize;
+        let text = match self.open_documents.get(uri) {
+            Some(text) => text.clone(),
+            None => self.text_of(&uri_to_path(uri)),
+        };
+        let line = text.lines().nth(line_no)?.to_string();
+        let offset = byte_offset(
+            &line, character,
+        );
+        return Some((
+            line, offset,
+        ));
+    }
+
+    fn link_at(&self, uri: &str, position: &Value) -> Option<WikiLink> {
+        let (line, offset) = self.line_at(
+            uri, position,
+        )?;
+        return links::wiki_links(&line)
+            .into_iter()
+            .find(|link| link.start <= offset && offset <= link.end);
+    }
+
+    fn image_at(&self, uri: &str, position: &Value) -> Option<ImageLink> {
+        let (line, offset) = self.line_at(
+            uri, position,
+        )?;
+        return links::image_links(&line)
+            .into_iter()
+            .find(|image| image.start <= offset && offset <= image.end);
+    }
+
+    /// Locates the document's HEADER nodes in its text, in order.
+    fn headings(&self, document: &DocumentEntry) -> Vec<Heading> {
+        let tree = &self.trees[document.tree];
+        let node = match tree.nodes.get(&document.node_id) {
+            Some(node) => node,
+            None => return vec![],
+        };
+        let text = self.text_of(&document.path);
+        let lines: Vec<&str> = text.lines().collect();
+        let mut next_line = 0;
+        let mut headings = vec![];
+        for child_id in node.children.iter() {
+            let child = match tree.nodes.get(child_id) {
+                Some(child) if child.node_type == NodeType::HEADER => child,
+                _ => continue,
+            };
+            let (text, level) = match &child.data {
+                NodeData::HeaderData { text, level } => (
+                    text.trim().to_string(),
+                    *level,
+                ),
+                _ => continue,
+            };
+            let found = (next_line..lines.len()).find(|i| {
+                let line = lines[*i].trim_start();
+                line.starts_with('#') && line.trim_start_matches('#').trim() == text
+            });
+            if let Some(line) = found {
+                headings.push(Heading {
+                    text,
+                    level,
+                    line,
+                    width: utf16_len(lines[line]),
+                });
+                next_line = line + 1;
+            }
+        }
+        return headings;
+    }
+
+    fn all_tags(&self) -> BTreeSet<String> {
+        let mut tags = BTreeSet::new();
+        for tree in self.trees.iter() {
+            for node in tree.nodes.values() {
+                let text = match &node.data {
+                    NodeData::ParagraphData { text } => text,
+                    NodeData::HeaderData { text, .. } => text,
+                    NodeData::ListItemData { text, .. } => text,
+                    _ => continue,
+                };
+                for line in text.lines() {
+                    for tag in links::tags(line) {
+                        tags.insert(tag.name);
+                    }
+                }
+            }
+        }
+        return tags;
+    }
+}
+
+/// Reads one `Content-Length` framed message, or `None` at end of input.
+/// A message which isn't valid JSON, or has no length, is read past and
+/// returned as an error for the client, since the next one may be fine.
+async fn read_message(
+    stdin: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<Option<Result<Value, String>>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if stdin.read_line(&mut header).await? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let content_length = match content_length {
+        Some(length) => length,
+        None => {
+            return Ok(Some(Err(
+                "Missing Content-Length header".into(),
+            )))
+        }
+    };
+    let mut body = vec![0; content_length];
+    match stdin.read_exact(&mut body).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    return Ok(Some(
+        serde_json::from_slice(&body).map_err(|e| e.to_string()),
+    ));
+}
+
+/// The documents of every tree, sorted by path.
+fn document_entries(trees: &[Tree]) -> Vec<DocumentEntry> {
+    let mut documents = vec![];
+    for (tree_idx, tree) in trees.iter().enumerate() {
+        for node in tree.nodes.values() {
+            if let NodeData::DocumentData {
+                path, canonical, ..
+            } = &node.data
+            {
+                let path = match canonical.is_empty() {
+                    true => canonicalize(path).unwrap_or(PathBuf::from(path)),
+                    false => PathBuf::from(canonical),
+                };
+                let stem = match path.file_stem() {
+                    Some(stem) => stem.to_string_lossy().to_string(),
+                    None => continue,
+                };
+                documents.push(
+                    DocumentEntry {
+                        tree: tree_idx,
+                        node_id: node.id.clone(),
+                        path,
+                        stem,
+                    },
+                );
+            }
+        }
+    }
+    documents.sort_by(|a, b| a.path.cmp(&b.path));
+    return documents;
+}
+
+/// `path` with symlinks resolved. A file that no longer exists is
+/// resolved through its directory, so it still matches its old entry.
+fn canonical_path(path: &Path) -> PathBuf {
+    if let Ok(canonical) = canonicalize(path) {
+        return canonical;
+    }
+    return match (
+        path.parent(),
+        path.file_name(),
+    ) {
+        (Some(parent), Some(name)) => match canonicalize(parent) {
+            Ok(parent) => parent.join(name),
+            Err(_) => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    };
+}
+
+fn push_symbol(
+    stack: &mut [(
+        usize,
+        Value,
+    )],
+    roots: &mut Vec<Value>,
+    symbol: Value,
+) {
+    match stack.last_mut() {
+        Some((_, parent)) => parent["children"].as_array_mut().unwrap().push(symbol),
+        None => roots.push(symbol),
+    }
+}
+
+fn range(line: usize, start: usize, end: usize) -> Value {
+    return json!({
+        "start": {"line": line, "character": start},
+        "end": {"line": line, "character": end},
+    });
+}
+
+fn location(path: &Path, line: usize, start: usize, end: usize) -> Value {
+    return json!({
+        "uri": path_to_uri(path),
+        "range": range(line, start, end),
+    });
+}
+
+/// LSP positions count UTF-16 code units by default.
+fn utf16_len(text: &str) -> usize {
+    return text.chars().map(char::len_utf16).sum();
+}
+
+fn byte_offset(line: &str, utf16_offset: usize) -> usize {
+    let mut units = 0;
+    for (idx, c) in line.char_indices() {
+        if units >= utf16_offset {
+            return idx;
+        }
+        units += c.len_utf16();
+    }
+    return line.len();
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    return PathBuf::from(String::from_utf8_lossy(&decoded).to_string());
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = canonicalize(path).unwrap_or(path.to_path_buf());
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!(
+                "%{:02X}",
+                byte
+            ));
+        }
+    }
+    return uri;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::TreeSettings;
+    use crate::common::parser::BuiltinParser;
+    use crate::common::tree::LoadOptions;
+    use std::fs;
+    use std::num::NonZeroUsize;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn frame(body: &str) -> String {
+        return format!(
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+    }
+
+    async fn read_all(input: &str) -> Vec<Result<Value, String>> {
+        let mut input = input.as_bytes();
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut input).await.unwrap() {
+            messages.push(message);
+        }
+        return messages;
+    }
+
+    #[tokio::test]
+    async fn reads_framed_messages_back_to_back() {
+        let input = format!(
+            "{}content-length: 13\r\nContent-Type: application/json\r\n\r\n{{\"id\": \"two\"}}",
+            frame("{\"id\": 1}")
+        );
+        assert_eq!(
+            read_all(&input).await,
+            [Ok(json!({"id": 1})), Ok(json!({"id": "two"}))]
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_messages_are_errors_and_reading_goes_on() {
+        let input = format!(
+            "Content-Type: application/json\r\n\r\n{}{}",
+            frame("{not json"),
+            frame("{\"id\": 3}")
+        );
+        let messages = read_all(&input).await;
+        assert_eq!(
+            messages.len(),
+            3
+        );
+        assert_eq!(
+            messages[0],
+            Err("Missing Content-Length header".into())
+        );
+        assert!(messages[1].is_err());
+        assert_eq!(
+            messages[2],
+            Ok(json!({"id": 3}))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_truncated_body_ends_the_input() {
+        assert!(
+            read_all("Content-Length: 20\r\n\r\n{\"id\": 1}")
+                .await
+                .is_empty()
+        );
+        assert!(read_all("").await.is_empty());
+    }
+
+    /// a.md, b.md and sub/c.md linking to each other, with one broken
+    /// link and one broken image in c.md.
+    async fn notes() -> (
+        TempDir,
+        LanguageServer,
+    ) {
+        let root = TempDir::new().unwrap();
+        let files = [
+            (
+                "a.md",
+                "# Alpha\n\nSee [[b]] and [[b#Beta Two]].\n",
+            ),
+            (
+                "b.md",
+                "# Beta\n\nBack to [[a]] #project\n\n## Beta Two\n\nText.\n",
+            ),
+            (
+                "sub/c.md",
+                "Here [[missing]] and [[A|alias]] ![pic](pic.png) ![gone](gone.png)\n",
+            ),
+            (
+                "sub/pic.png",
+                "",
+            ),
+        ];
+        for (path, text) in files {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        let settings = TreeSettings {
+            load_options: LoadOptions::default(),
+            doc_parser: Arc::new(BuiltinParser),
+            cache: None,
+        };
+        let app_config = AppConfig {
+            trees: HashMap::new(),
+            default_settings: settings,
+            processes: NonZeroUsize::new(1).unwrap(),
+            parse_timeout: Duration::from_secs(30),
+            snapshot: None,
+        };
+        let mut tree = Tree::load(
+            "notes".into(),
+            root.path().to_str().unwrap().into(),
+            app_config.load_options("notes"),
+        )
+        .await
+        .unwrap();
+        tree.load_all_unloaded_docs(
+            &app_config.hydrate_options("notes"),
+            |_, _, _| {},
+        )
+        .await;
+        return (
+            root,
+            LanguageServer::new(
+                vec![tree],
+                app_config,
+            ),
+        );
+    }
+
+    fn uri(root: &TempDir, path: &str) -> String {
+        return path_to_uri(&root.path().join(path));
+    }
+
+    fn at(root: &TempDir, path: &str, line: usize, character: usize) -> Value {
+        return json!({
+            "textDocument": {"uri": uri(root, path)},
+            "position": {"line": line, "character": character},
+        });
+    }
+
+    /// The file name and line of each location.
+    fn places(
+        locations: &Value,
+    ) -> Vec<(
+        String,
+        u64,
+    )> {
+        return locations
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(
+                |location| {
+                    let uri = location["uri"].as_str().unwrap();
+                    (
+                        uri.rsplit('/').next().unwrap().to_string(),
+                        location["range"]["start"]["line"].as_u64().unwrap(),
+                    )
+                },
+            )
+            .collect();
+    }
+
+    #[tokio::test]
+    async fn initialize_lists_capabilities_and_unknown_methods_are_refused() {
+        let (_root, server) = notes().await;
+        let result = server
+            .handle_request(
+                "initialize",
+                &json!({}),
+            )
+            .unwrap();
+        assert_eq!(
+            result["capabilities"]["referencesProvider"],
+            true
+        );
+        assert!(server
+            .handle_request(
+                "textDocument/hover",
+                &json!({})
+            )
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn definition_goes_to_the_linked_document_and_heading() {
+        let (root, server) = notes().await;
+        let definition = |params| {
+            server
+                .handle_request(
+                    "textDocument/definition",
+                    &params,
+                )
+                .unwrap()
+        };
+        assert_eq!(
+            definition(at(
+                &root, "a.md", 2, 6
+            )),
+            location(
+                &root.path().join("b.md"),
+                0,
+                0,
+                0
+            )
+        );
+        assert_eq!(
+            places(&json!([
+                definition(at(
+                    &root, "a.md", 2, 18
+                ))
+            ])),
+            [(
+                "b.md".to_string(),
+                4
+            )]
+        );
+        assert_eq!(
+            definition(at(
+                &root, "a.md", 0, 2
+            )),
+            Value::Null
+        );
+        assert_eq!(
+            places(&json!([
+                definition(at(
+                    &root, "sub/c.md", 0, 45
+                ))
+            ])),
+            [(
+                "pic.png".to_string(),
+                0
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn references_are_backlinks_to_the_link_or_document() {
+        let (root, mut server) = notes().await;
+        // off any link, so backlinks to b.md itself
+        let references = server
+            .handle_request(
+                "textDocument/references",
+                &at(
+                    &root, "b.md", 0, 0,
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            places(&references),
+            [
+                (
+                    "a.md".to_string(),
+                    2
+                ),
+                (
+                    "a.md".to_string(),
+                    2
+                )
+            ]
+        );
+        assert_eq!(
+            references[0]["range"],
+            range(2, 4, 9)
+        );
+        // on [[a]], found by name in c.md too
+        let references = server
+            .handle_request(
+                "textDocument/references",
+                &at(
+                    &root, "b.md", 2, 10,
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            places(&references),
+            [
+                (
+                    "b.md".to_string(),
+                    2
+                ),
+                (
+                    "c.md".to_string(),
+                    0
+                )
+            ]
+        );
+
+        // unsaved edits are indexed as they're made
+        server.open_document(
+            &uri(
+                &root, "a.md",
+            ),
+            "# Alpha\n\nNo links now.\n",
+        );
+        let references = server
+            .handle_request(
+                "textDocument/references",
+                &at(
+                    &root, "b.md", 0, 0,
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            references,
+            json!([])
+        );
+    }
+
+    #[tokio::test]
+    async fn completes_note_names_and_tags() {
+        let (root, mut server) = notes().await;
+        server.open_document(
+            &uri(
+                &root, "a.md",
+            ),
+            "See [[\nand #pro",
+        );
+        let labels = |result: Value| -> Vec<String> {
+            return result
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["label"].as_str().unwrap().to_string())
+                .collect();
+        };
+        let completion = |server: &LanguageServer, line, character| {
+            server
+                .handle_request(
+                    "textDocument/completion",
+                    &at(
+                        &root, "a.md", line, character,
+                    ),
+                )
+                .unwrap()
+        };
+        assert_eq!(
+            labels(completion(&server, 0, 6)),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            labels(completion(&server, 1, 8)),
+            ["#project"]
+        );
+        assert_eq!(
+            completion(&server, 1, 2),
+            json!([])
+        );
+    }
+
+    #[tokio::test]
+    async fn symbols_come_from_headings() {
+        let (root, server) = notes().await;
+        let symbols = server
+            .handle_request(
+                "textDocument/documentSymbol",
+                &json!({"textDocument": {"uri": uri(&root, "b.md")}}),
+            )
+            .unwrap();
+        assert_eq!(
+            symbols.as_array().unwrap().len(),
+            1
+        );
+        assert_eq!(
+            symbols[0]["name"],
+            "Beta"
+        );
+        assert_eq!(
+            symbols[0]["children"][0]["name"],
+            "Beta Two"
+        );
+        assert_eq!(
+            symbols[0]["children"][0]["range"],
+            range(4, 0, 11)
+        );
+
+        let symbols = server
+            .handle_request(
+                "workspace/symbol",
+                &json!({"query": "BETA"}),
+            )
+            .unwrap();
+        let names: Vec<&str> = symbols
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|symbol| symbol["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["Beta", "Beta Two"]
+        );
+    }
+
+    #[tokio::test]
+    async fn diagnostics_warn_of_broken_links_and_images() {
+        let (root, server) = notes().await;
+        let diagnostics = server.diagnostics(&uri(
+            &root, "sub/c.md",
+        ));
+        let messages: Vec<&str> = diagnostics
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|diagnostic| diagnostic["message"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "Broken image: no attachment at 'gone.png'",
+                "Broken link: no note named 'missing'"
+            ]
+        );
+        assert_eq!(
+            diagnostics[1]["range"],
+            range(0, 5, 16)
+        );
+        assert_eq!(
+            server.diagnostics(&uri(
+                &root, "a.md"
+            )),
+            json!([])
+        );
+    }
+}
@@ -1,165 +1,1906 @@
 use clap::Parser;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::process;
 use std::sync::Arc;
 use std::thread;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tower_http::catch_panic::CatchPanicLayer;
 
 use axum::{
-    extract::{Path, Query, State},
+    extract::State,
+    http::StatusCode,
+    middleware,
+    response::sse::{Event, KeepAlive, Sse},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 
+// compiled into every binary, each of which only uses part of it
+#[allow(dead_code)]
 #[path = "../common.rs"]
 mod common;
-use common::node::NodeData;
-use common::tree::Tree;
+use common::cache::ParseCache;
+use common::config::{Config, TreeConfig};
+use common::detail::LevelOfDetail;
+use common::ignore_rules::IgnoreOptions;
+use common::node::{Node, NodeData};
+use common::parser::{default_parser, parser_from_arg, DocumentParser};
+use common::selector::{sort_by_attribute, Selector};
+use common::tree::{HydrationScope, LoadOptions, Tree, TreeState};
 
 mod app_state;
-use app_state::{AppConfig, AppState};
+use app_state::{AppConfig, AppState, TreeSettings};
+
+mod error;
+use error::{ApiError, ApiJson, ApiPath, ApiQuery};
+
+mod events;
+use events::TreeEvent;
+
+mod jobs;
+use jobs::{JobState, JobStatus, Jobs};
+
+mod lsp;
+use lsp::LanguageServer;
+
+mod operations;
+use operations::{Operation, OperationResult, StoreOperation};
+
+mod snapshot;
+use snapshot::Snapshot;
+
+#[cfg(unix)]
+mod socket;
+
+#[cfg(test)]
+mod testing;
+
+mod store;
+use store::{Store, TreeCell};
 
 #[derive(Deserialize)]
 struct GetTreeParams {
-    lod: Option<String>, // Level of Detail
-                         // The lowest level type of node to display.
-                         // E.g. if it's "document" it will display all documents. If it's "block" it will display only block-level document elements.
+    lod: Option<LevelOfDetail>, // The lowest level of node to return, `document` by default
+    depth: Option<usize>,       // How many levels below the root to return
+    root: Option<String>,       // The id or virtual path of the node to start from
+}
+
+#[derive(Deserialize)]
+struct EventParams {
+    tree: Option<String>,
+    selector: Option<String>, // Only pass node events where the node or one of its descendants match
 }
 
 #[derive(Serialize, Deserialize)]
 struct TreeDetail {
     path: String,
     name: String,
+    state: TreeState,
+    documents: usize,
+    hydrated: usize,
+    failed: usize, // Documents which couldn't be loaded, see `failures` on the tree
 }
 
+#[derive(Serialize)]
+struct NodeDetail {
+    /// The node's virtual path, see `Tree::vpath_of`
+    path: Option<String>,
+    #[serde(flatten)]
+    node: Node,
+}
+
+/// Flags take precedence over the config file's global settings, but not
+/// over a tree's own settings in it.
 #[derive(Parser, Debug)]
 struct Args {
+    /// Read settings from this file [default: ~/.config/zenkat/config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Serve the directory or document at `path` as `name`, given as `name:path`
     #[arg(short, long)]
     tree: Vec<String>,
+
+    /// How many documents to parse at once [default: the number of CPUs]
     #[arg(long, default_value = "0")]
     processes: usize,
 
+    /// `builtin` to parse in-process, or the path of a parser executable
+    /// [default: the md-parse next to zenkat, or builtin]
     #[arg(long, default_value = "")]
     parser: String,
 
+    /// Pass this argument to the parser, before its file arguments
+    #[arg(long = "parser-arg", allow_hyphen_values = true)]
+    parser_args: Vec<String>,
+
+    /// Check that the parser understands this Markdown dialect
+    #[arg(long)]
+    dialect: Option<String>,
+
+    /// Seconds a parser may spend on one document before it's killed [default: 30]
+    #[arg(long)]
+    parse_timeout: Option<u64>,
+
+    /// Where to keep parsed documents between runs [default: ~/.cache/zenkat]
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Parse every document rather than using the cache
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Restore trees from this file at startup, and save them to it on exit
+    #[arg(long)]
+    snapshot: Option<PathBuf>,
+
     #[arg(long)]
     follow_symlinks: bool,
 
-    #[arg(long, default_value = "localhost")]
-    interface: String,
+    /// Skip files and directories matching this .gitignore-style pattern
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Include hidden files and directories
+    #[arg(long)]
+    hidden: bool,
+
+    /// Parse files with this extension as documents; other files are attachments [default: md]
+    #[arg(long = "document-extension")]
+    document_extensions: Vec<String>,
+
+    /// Don't read .gitignore and .zkignore files
+    #[arg(long)]
+    no_ignore: bool,
+
+    /// [default: localhost]
+    #[arg(long)]
+    interface: Option<String>,
+
+    /// [default: 9001]
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Listen on a Unix socket at this path instead of over TCP
+    #[cfg(unix)]
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// The socket's permissions in octal, which decide who may connect [default: 600]
+    #[cfg(unix)]
+    #[arg(long, value_parser = parse_mode)]
+    socket_mode: Option<u32>,
+
+    /// Speak the Language Server Protocol over stdio instead of serving HTTP
+    #[arg(long)]
+    lsp: bool,
+}
+
+fn tree_detail(tree: &Tree) -> Option<TreeDetail> {
+    let path = match tree.get_root().data.clone() {
+        NodeData::DocumentData { path, .. } => path,
+        NodeData::DirectoryData { path, .. } => path,
+        _ => return None,
+    };
 
-    #[arg(short, long, default_value = "9001")]
-    port: String,
+    let (documents, hydrated) = tree.hydration_counts();
+    return Some(
+        TreeDetail {
+            name: tree.name.clone(),
+            path,
+            state: tree.state,
+            documents,
+            hydrated,
+            failed: tree.failures.len(),
+        },
+    );
 }
 
 async fn list_trees(State(state): State<AppState>) -> Json<Vec<TreeDetail>> {
-    let tree_guard = state.trees.lock().await;
     let mut tree_details = vec![];
-    for tree in tree_guard.iter() {
-        let path = match tree.get_root().data.clone() {
-            NodeData::DocumentData { path, loaded: _ } => path,
-            NodeData::DirectoryData { path } => path,
-            _ => continue,
-        };
+    for tree in state.trees.read_all() {
+        if let Some(detail) = tree_detail(&tree) {
+            tree_details.push(detail);
+        }
+    }
+    return Json(tree_details);
+}
 
-        tree_details.push(
-            TreeDetail {
-                name: tree.name.clone(),
-                path,
-            },
+/// Drops a tree from the store, cancelling any job hydrating it, and
+/// responds with the tree's details as they were.
+async fn unload_tree(
+    ApiPath(name): ApiPath<String>,
+    State(state): State<AppState>,
+) -> Result<Json<TreeDetail>, ApiError> {
+    if state.trees.get(&name).is_none() {
+        return Err(ApiError::tree_not_found(&name));
+    }
+    if let Some(job) = state.jobs.running(&name) {
+        job.cancel();
+        job.wait().await;
+    }
+    let cell = match state.trees.remove(&name) {
+        Some(cell) => cell,
+        // another request unloaded it while the job stopped
+        None => return Err(ApiError::tree_not_found(&name)),
+    };
+    let _ = state
+        .events
+        .send(TreeEvent::TreeUnloaded { tree: name.clone() });
+    return match tree_detail(&cell.read()) {
+        Some(detail) => Ok(Json(
+            detail,
+        )),
+        None => Err(
+            ApiError::internal(format!(
+                "{} has no root directory",
+                name
+            )),
+        ),
+    };
+}
+
+/// The tree `name`, once it has been walked.
+fn loaded_tree(state: &AppState, name: &str) -> Result<Arc<TreeCell>, ApiError> {
+    let cell = match state.trees.get(name) {
+        Some(cell) => cell,
+        None => return Err(ApiError::tree_not_found(name)),
+    };
+    let tree_state = cell.read().state;
+    if tree_state == TreeState::UNLOADED || tree_state == TreeState::LOADING_METADATA {
+        return Err(
+            ApiError::tree_not_loaded(
+                name, tree_state,
+            ),
         );
     }
-    return Json(tree_details);
+    return Ok(cell);
 }
 
 async fn get_tree(
-    Path(name): Path<String>,
-    Query(tree_params): Query<GetTreeParams>,
+    ApiPath(name): ApiPath<String>,
+    ApiQuery(tree_params): ApiQuery<GetTreeParams>,
+    State(state): State<AppState>,
+) -> Result<Json<Tree>, ApiError> {
+    let cell = loaded_tree(
+        &state, &name,
+    )?;
+    let lod = tree_params.lod.unwrap_or(LevelOfDetail::DOCUMENT);
+    let root_id = match &tree_params.root {
+        Some(root) => match cell.read().find_node(root) {
+            Some(node) => node.id.clone(),
+            None => {
+                return Err(
+                    ApiError::node_not_found(
+                        &name, root,
+                    ),
+                )
+            }
+        },
+        None => cell.read().root_node.clone(),
+    };
+    if lod >= LevelOfDetail::BLOCK {
+        // only the documents being returned, and failed ones only when
+        // asked, or every read would parse them again
+        let scope = HydrationScope {
+            root: Some(root_id.clone()),
+            max_depth: tree_params.depth,
+            retry_failed: false,
+        };
+        // wait for the documents without holding up other requests. A job
+        // that was already running may have been for other documents, so
+        // once it's done the scope is checked again.
+        for _ in 0..2 {
+            match Jobs::hydrate(
+                &state, &name, &scope,
+            )
+            .await
+            {
+                Some(job) => job.wait().await,
+                None => break,
+            };
+        }
+    }
+    let tree = cell.read();
+    if lod >= LevelOfDetail::BLOCK {
+        // the contents of a single document were asked for, but it has none
+        if let Some(failure) = tree.failures.get(&root_id) {
+            return Err(ApiError::parser_failed(failure));
+        }
+    }
+    return match tree.subtree(
+        &root_id,
+        lod,
+        tree_params.depth,
+    ) {
+        Some(subtree) => Ok(Json(
+            subtree,
+        )),
+        None => Err(
+            ApiError::node_not_found(
+                &name, &root_id,
+            ),
+        ),
+    };
+}
+
+/// Starts hydrating a tree in the background, returning the job's status
+/// to be polled at `GET /jobs/:id`. Documents which failed are retried.
+async fn hydrate_tree(
+    ApiPath(name): ApiPath<String>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    loaded_tree(
+        &state, &name,
+    )?;
+    let scope = HydrationScope {
+        retry_failed: true,
+        ..Default::default()
+    };
+    return match Jobs::hydrate(
+        &state, &name, &scope,
+    )
+    .await
+    {
+        Some(job) => Ok((
+            StatusCode::ACCEPTED,
+            Json(job.status()),
+        )
+            .into_response()),
+        // every document is hydrated already
+        None => Ok(StatusCode::NO_CONTENT.into_response()),
+    };
+}
+
+async fn list_jobs(State(state): State<AppState>) -> Json<Vec<JobStatus>> {
+    return Json(state.jobs.list());
+}
+
+async fn get_job(
+    ApiPath(id): ApiPath<String>,
+    State(state): State<AppState>,
+) -> Result<Json<JobStatus>, ApiError> {
+    return match state.jobs.get(&id) {
+        Some(job) => Ok(Json(
+            job.status(),
+        )),
+        None => Err(ApiError::job_not_found(&id)),
+    };
+}
+
+/// Cancels a job, responding once it has stopped.
+async fn cancel_job(
+    ApiPath(id): ApiPath<String>,
+    State(state): State<AppState>,
+) -> Result<Json<JobStatus>, ApiError> {
+    let job = match state.jobs.get(&id) {
+        Some(job) => job,
+        None => return Err(ApiError::job_not_found(&id)),
+    };
+    if job.status().state != JobState::RUNNING {
+        return Err(ApiError::job_finished(&id));
+    }
+    job.cancel();
+    return Ok(Json(
+        job.wait().await,
+    ));
+}
+
+/// Streams tree events to the client as Server-Sent Events.
+async fn stream_events(
+    ApiQuery(params): ApiQuery<EventParams>,
+    State(state): State<AppState>,
+) -> Result<Sse<ReceiverStream<Result<Event, Infallible>>>, ApiError> {
+    let selector = match params.selector {
+        Some(raw) => Some(Selector::parse(&raw).map_err(|e| ApiError::bad_selector(&raw, e))?),
+        None => None,
+    };
+    let mut events = state.events.subscribe();
+    let mut shutdown = state.shutdown.clone();
+    let (tx, rx) = mpsc::channel(64);
+
+    tokio::spawn(
+        async move {
+            loop {
+                let received = tokio::select! {
+                    received = events.recv() => received,
+                    // ends the stream, or the server would wait for the
+                    // client to go away before it could stop
+                    _ = shutdown.wait_for(|stopping| *stopping) => break,
+                };
+                let event = match received {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if !event_passes(
+                    &event,
+                    &params.tree,
+                    &selector,
+                    &state,
+                )
+                .await
+                {
+                    continue;
+                }
+                let sse_event = match Event::default().event(event.name()).json_data(&event) {
+                    Ok(sse_event) => sse_event,
+                    Err(_) => continue,
+                };
+                if tx
+                    .send(Ok(
+                        sse_event,
+                    ))
+                    .await
+                    .is_err()
+                {
+                    // the client disconnected
+                    break;
+                }
+            }
+        },
+    );
+
+    return Ok(Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default()));
+}
+
+async fn event_passes(
+    event: &TreeEvent,
+    tree_name: &Option<String>,
+    selector: &Option<Selector>,
+    state: &AppState,
+) -> bool {
+    if let Some(tree_name) = tree_name {
+        if event.tree() != tree_name {
+            return false;
+        }
+    }
+    let (selector, node_id) = match (
+        selector,
+        event.node(),
+    ) {
+        (Some(selector), Some(node_id)) => (
+            selector, node_id,
+        ),
+        // tree-level events aren't filtered by selector
+        _ => return true,
+    };
+    let tree = match state.trees.get(event.tree()) {
+        Some(cell) => cell.read(),
+        None => return true,
+    };
+    if !tree.nodes.contains_key(node_id) {
+        // removed nodes can't be tested, so let the client decide
+        return true;
+    }
+    return selector.matches_within(
+        &tree, node_id,
+    );
+}
+
+async fn query_tree(
+    ApiPath(name): ApiPath<String>,
     State(state): State<AppState>,
-) -> Json<Option<Tree>> {
-    let mut tree_guard = state.trees.lock().await;
-    for tree in tree_guard.iter_mut() {
-        if tree.name == name {
-            let lod = tree_params.lod.unwrap_or("document".into());
-            if lod == "block" || lod == "full" {
-                let parser = state.app_config.doc_parser.clone();
-                tree.load_all_unloaded_docs(parser).await;
+    ApiJson(operation): ApiJson<Operation>,
+) -> Result<Json<OperationResult>, ApiError> {
+    let cell = loaded_tree(
+        &state, &name,
+    )?;
+    match operation {
+        Operation::UnloadDocs { query } => {
+            let selector =
+                Selector::parse(&query).map_err(|e| ApiError::bad_selector(&query, e))?;
+            let unloaded = cell.update(|tree| tree.unload_documents(&selector)).await;
+            for node_id in unloaded.iter() {
+                let _ = state.events.send(
+                    TreeEvent::DocumentRemoved {
+                        tree: name.clone(),
+                        node: node_id.clone(),
+                    },
+                );
             }
-            return Json(Some(
-                tree.clone(),
+            return Ok(Json(
+                OperationResult::Unloaded { unloaded },
+            ));
+        }
+        Operation::Select { query, sort, limit } => {
+            let selector =
+                Selector::parse(&query).map_err(|e| ApiError::bad_selector(&query, e))?;
+            let tree = cell.read();
+            let mut selected = selector.select(&tree);
+            if let Some(sort) = sort {
+                sort_by_attribute(
+                    &tree,
+                    &mut selected,
+                    &sort,
+                );
+            }
+            if let Some(limit) = limit {
+                selected.truncate(limit);
+            }
+            let nodes = selected
+                .iter()
+                .filter_map(|node_id| tree.nodes.get(node_id).cloned())
+                .collect();
+            return Ok(Json(
+                OperationResult::Selected { nodes },
             ));
         }
     }
-    return Json(None);
 }
 
-async fn query_tree() {}
+async fn query_store(
+    State(state): State<AppState>,
+    ApiJson(operation): ApiJson<StoreOperation>,
+) -> Result<Json<OperationResult>, ApiError> {
+    match operation {
+        StoreOperation::SaveSnapshot { path } => {
+            let path = match path
+                .map(PathBuf::from)
+                .or(state.app_config.snapshot.clone())
+            {
+                Some(path) => path,
+                None => {
+                    return Err(
+                        ApiError::bad_request(
+                            "No path given and the server has no --snapshot file.".into(),
+                        ),
+                    )
+                }
+            };
+            let trees = state.trees.read_all();
+            let snapshot = Snapshot::of(trees.iter().map(|tree| tree.as_ref()));
+            let trees = snapshot.trees.len();
+            let bytes = snapshot.save(&path).map_err(ApiError::internal)?;
+            return Ok(Json(
+                OperationResult::Saved {
+                    path: path.to_string_lossy().into(),
+                    trees,
+                    bytes,
+                },
+            ));
+        }
+    }
+}
 
-async fn get_node() {}
+/// Gets one node by id or virtual path.
+async fn get_node(
+    ApiPath((name, node)): ApiPath<(
+        String,
+        String,
+    )>,
+    State(state): State<AppState>,
+) -> Result<Json<NodeDetail>, ApiError> {
+    let tree = loaded_tree(
+        &state, &name,
+    )?
+    .read();
+    let node = match tree.find_node(&node) {
+        Some(node) => node,
+        None => {
+            return Err(
+                ApiError::node_not_found(
+                    &name, &node,
+                ),
+            )
+        }
+    };
+    return Ok(Json(
+        NodeDetail {
+            path: tree.vpath_of(&node.id),
+            node: node.clone(),
+        },
+    ));
+}
+
+/// The parser for the command `command` run with `args`, and its cache.
+type ConnectedParser = (
+    Arc<dyn DocumentParser>,
+    Option<Arc<ParseCache>>,
+);
+
+/// Starts each distinct parser once, so trees configured the same way
+/// share their parser processes and cache.
+struct Parsers {
+    connected: HashMap<
+        (
+            String,
+            Vec<String>,
+        ),
+        ConnectedParser,
+    >,
+    processes: usize,
+    cache_dir: Option<PathBuf>,
+}
+
+impl Parsers {
+    async fn get(
+        &mut self,
+        command: &str,
+        args: &[String],
+        dialect: Option<&str>,
+    ) -> Result<ConnectedParser, String> {
+        let key = (
+            command.to_string(),
+            args.to_vec(),
+        );
+        let (doc_parser, cache) = match self.connected.get(&key) {
+            Some(connected) => connected.clone(),
+            None => {
+                let doc_parser = parser_from_arg(
+                    command,
+                    args,
+                    self.processes,
+                )
+                .await?;
+                let cache = self.cache_dir.as_ref().map(|dir| {
+                    Arc::new(
+                        ParseCache::new(
+                            dir.clone(),
+                            &doc_parser.describe(),
+                            args,
+                        ),
+                    )
+                });
+                self.connected.insert(
+                    key,
+                    (
+                        doc_parser.clone(),
+                        cache.clone(),
+                    ),
+                );
+                (
+                    doc_parser, cache,
+                )
+            }
+        };
+        if let Some(dialect) = dialect {
+            let description = doc_parser.describe();
+            if !description.supports_dialect(dialect) {
+                return Err(format!(
+                    "{} doesn't understand the {} dialect, only: {}",
+                    doc_parser.name(),
+                    dialect,
+                    description.dialects.join(", ")
+                ));
+            }
+        }
+        return Ok((
+            doc_parser, cache,
+        ));
+    }
+}
+
+/// The parser for trees without one of their own, and its arguments. The
+/// flags take precedence over the config, but arguments meant for the
+/// config's parser don't apply to one given as a flag.
+fn global_parser(
+    args: &Args,
+    config: &Config,
+) -> (
+    String,
+    Vec<String>,
+) {
+    return match args.parser.is_empty() {
+        false => (
+            args.parser.clone(),
+            args.parser_args.clone(),
+        ),
+        true => (
+            config.parser.clone().unwrap_or_else(default_parser),
+            match args.parser_args.is_empty() {
+                true => config.parser_args.clone(),
+                false => args.parser_args.clone(),
+            },
+        ),
+    };
+}
+
+/// The parser for a `[[tree]]` and its arguments: its own if it names one,
+/// otherwise the global parser with the global arguments. A tree's
+/// `parser_args` only go with its own `parser`, which `Config::load`
+/// checks.
+fn tree_parser(
+    tree_config: &TreeConfig,
+    parser: &str,
+    parser_args: &[String],
+) -> (
+    String,
+    Vec<String>,
+) {
+    return match &tree_config.parser {
+        Some(command) => (
+            command.clone(),
+            tree_config.parser_args.clone().unwrap_or_default(),
+        ),
+        None => (
+            parser.into(),
+            parser_args.to_vec(),
+        ),
+    };
+}
+
+/// Combines the flags with the config file into the settings for each of
+/// the trees `tree_names`.
+async fn app_config(
+    args: &Args,
+    config: &Config,
+    tree_names: &[String],
+) -> Result<AppConfig, String> {
+    let processes = [args.processes, config.processes.unwrap_or(0)]
+        .into_iter()
+        .find_map(NonZeroUsize::new)
+        .unwrap_or(thread::available_parallelism().map_err(|e| e.to_string())?);
+
+    let (parser, parser_args) = global_parser(
+        args, config,
+    );
+    let dialect = args.dialect.clone().or(config.dialect.clone());
+
+    let cache_dir = args
+        .cache_dir
+        .clone()
+        .or(config.cache_dir.clone())
+        .or(ParseCache::default_dir());
+    let mut parsers = Parsers {
+        connected: HashMap::new(),
+        processes: processes.get(),
+        cache_dir: match args.no_cache || config.no_cache {
+            true => None,
+            false => cache_dir,
+        },
+    };
+
+    let load_options = LoadOptions {
+        follow_symlinks: args.follow_symlinks || config.follow_symlinks,
+        ignore: IgnoreOptions {
+            hidden: args.hidden || config.ignore.hidden,
+            no_ignore_files: args.no_ignore || config.ignore.no_ignore_files,
+            exclude: [config.ignore.exclude.clone(), args.exclude.clone()].concat(),
+        },
+        document_extensions: match args.document_extensions.is_empty() {
+            false => args.document_extensions.clone(),
+            true => config
+                .document_extensions
+                .clone()
+                .unwrap_or(LoadOptions::default().document_extensions),
+        },
+    };
+
+    let (doc_parser, cache) = parsers
+        .get(
+            &parser,
+            &parser_args,
+            dialect.as_deref(),
+        )
+        .await?;
+    let default_settings = TreeSettings {
+        load_options: load_options.clone(),
+        doc_parser,
+        cache,
+    };
+
+    let mut trees = HashMap::new();
+    for name in tree_names {
+        let tree_config = match config.tree(name) {
+            Some(tree_config) => tree_config,
+            None => continue,
+        };
+        let (command, command_args) = tree_parser(
+            tree_config,
+            &parser,
+            &parser_args,
+        );
+        let (doc_parser, cache) = parsers
+            .get(
+                &command,
+                &command_args,
+                tree_config.dialect.as_deref().or(dialect.as_deref()),
+            )
+            .await?;
+        let mut tree_load_options = load_options.clone();
+        tree_load_options
+            .ignore
+            .exclude
+            .extend(tree_config.exclude.iter().cloned());
+        trees.insert(
+            name.clone(),
+            TreeSettings {
+                load_options: tree_load_options,
+                doc_parser,
+                cache,
+            },
+        );
+    }
+
+    return Ok(
+        AppConfig {
+            trees,
+            default_settings,
+            processes,
+            parse_timeout: Duration::from_secs(
+                args.parse_timeout.or(config.parse_timeout).unwrap_or(30),
+            ),
+            snapshot: args.snapshot.clone().or(config.snapshot.clone()),
+        },
+    );
+}
 
 #[tokio::main]
 async fn main() {
-    let mut processes = thread::available_parallelism().expect("");
-    let mut parser = String::from("target/debug/md-parse");
-
     let args = Args::parse();
+    let config = match Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    // checked before loading anything, as it may be a mistake
+    let socket_path = socket_path(
+        &args, &config,
+    );
 
-    if args.processes > 0 {
-        processes = NonZeroUsize::new(args.processes).expect("");
+    // trees given as flags replace any with the same name in the config
+    let mut tree_paths: Vec<(
+        String,
+        String,
+    )> = config
+        .trees
+        .iter()
+        .map(|tree| {
+            (
+                tree.name.clone(),
+                tree.path.to_string_lossy().into(),
+            )
+        })
+        .collect();
+    for tree_arg in args.tree.iter() {
+        let (name, path) = match tree_arg.split_once(":") {
+            Some(tree) => tree,
+            None => {
+                eprintln!(
+                    "Expected --tree name:path, got {}",
+                    tree_arg
+                );
+                process::exit(1);
+            }
+        };
+        match tree_paths.iter_mut().find(|(existing, _)| existing == name) {
+            Some(tree) => tree.1 = path.into(),
+            None => tree_paths.push((
+                name.into(),
+                path.into(),
+            )),
+        }
     }
+    let tree_names: Vec<String> = tree_paths.iter().map(|(name, _)| name.clone()).collect();
+    let trees: Vec<Tree> = tree_paths
+        .into_iter()
+        .map(|(name, path)| Tree::unloaded(name, path))
+        .collect();
 
-    if args.parser.len() > 0 {
-        parser = args.parser;
-    }
+    let app_config = match app_config(
+        &args,
+        &config,
+        &tree_names,
+    )
+    .await
+    {
+        Ok(app_config) => app_config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    let mut trees = vec![];
-    for tree_arg in args.tree {
-        let (name, path) = tree_arg.split_once(":").unwrap();
-        let cur_tree = Tree::load(
-            name.into(),
-            path.into(),
-            args.follow_symlinks,
-        )
-        .await;
-        match cur_tree {
-            Some(tree) => trees.push(tree),
-            None => {}
+    let mut snapshot = match &app_config.snapshot {
+        Some(path) if path.exists() => match Snapshot::load(path) {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                eprintln!(
+                    "Ignoring snapshot {}: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        },
+        _ => None,
+    };
+
+    if args.lsp {
+        let mut loaded = vec![];
+        for tree in trees {
+            if let Some(tree) = walk_tree(
+                &tree,
+                app_config.load_options(&tree.name),
+                &mut snapshot,
+            )
+            .await
+            {
+                loaded.push(tree);
+            }
+        }
+        let mut trees = loaded;
+        for tree in trees.iter_mut() {
+            tree.load_all_unloaded_docs(
+                &app_config.hydrate_options(&tree.name),
+                |_, _, _| {},
+            )
+            .await;
         }
+        let mut server = LanguageServer::new(
+            trees, app_config,
+        );
+        if let Err(e) = server.run().await {
+            eprintln!(
+                "Language server stopped: {}",
+                e
+            );
+            process::exit(1);
+        }
+        return;
     }
 
+    let (events, _) = broadcast::channel(1024);
+    let (stopping, shutdown) = watch::channel(false);
     let state = AppState {
-        trees: Arc::new(Mutex::new(trees.to_owned())),
-        app_config: AppConfig {
-            follow_symlinks: args.follow_symlinks,
-            doc_parser: parser,
-            processes,
-        },
+        trees: Arc::new(Store::new(trees)),
+        events,
+        jobs: Jobs::default(),
+        app_config,
+        shutdown,
     };
+    tokio::spawn(
+        load_trees(
+            state.clone(),
+            snapshot,
+        ),
+    );
+
+    let app = router(state.clone());
+
+    match &socket_path {
+        #[cfg(unix)]
+        Some(path) => {
+            let mode = args
+                .socket_mode
+                .or(config.server.socket_mode)
+                .unwrap_or(socket::DEFAULT_SOCKET_MODE);
+            let listener = match socket::bind(path, mode).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!(
+                        "Failed to listen on {}: {}",
+                        path.display(),
+                        e
+                    );
+                    process::exit(1);
+                }
+            };
+            socket::serve(
+                listener,
+                app,
+                shutdown_signal(stopping),
+            )
+            .await;
+            let _ = std::fs::remove_file(path);
+        }
+        _ => {
+            let addr = format!(
+                "{}:{}",
+                args.interface
+                    .or(config.server.interface)
+                    .unwrap_or("localhost".into()),
+                args.port.or(config.server.port).unwrap_or(9001)
+            );
+            let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+            axum::serve(
+                listener, app,
+            )
+            .with_graceful_shutdown(shutdown_signal(stopping))
+            .await
+            .unwrap();
+        }
+    }
 
-    let app = Router::new()
+    if let Some(path) = &state.app_config.snapshot {
+        let trees = state.trees.read_all();
+        match Snapshot::of(trees.iter().map(|tree| tree.as_ref())).save(path) {
+            Ok(bytes) => eprintln!(
+                "Saved snapshot to {} ({} bytes).",
+                path.display(),
+                bytes
+            ),
+            Err(e) => eprintln!(
+                "Failed to save snapshot to {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Every route of the HTTP API, see docs/api.md.
+fn router(state: AppState) -> Router {
+    return Router::new()
         .route(
             "/tree",
             get(list_trees),
         )
+        .route(
+            "/events",
+            get(stream_events),
+        )
         .route(
             "/tree/:name",
-            get(get_tree),
+            get(get_tree).delete(unload_tree),
         )
         .route(
             "/tree/:name/query",
             post(query_tree),
         )
+        .route(
+            "/tree/:name/hydrate",
+            post(hydrate_tree),
+        )
+        .route(
+            "/jobs",
+            get(list_jobs),
+        )
+        .route(
+            "/jobs/:id",
+            get(get_job).delete(cancel_job),
+        )
         .route(
             "/tree/:name/:node",
             get(get_node),
         )
+        .route(
+            "/tree/:name/path/*node",
+            get(get_node),
+        )
+        .route(
+            "/query",
+            post(query_store),
+        )
+        .fallback(|| async { ApiError::no_route() })
+        .layer(middleware::map_response(ApiError::replace_method_not_allowed))
+        .layer(CatchPanicLayer::custom(ApiError::from_panic))
         .with_state(state);
+}
 
-    let addr = vec![args.interface, ":".into(), args.port.into()].join("");
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(
-        listener, app,
-    )
+/// Walks each tree that's still unloaded in turn, so the server can answer
+/// requests, with the trees' states, while it starts up.
+async fn load_trees(state: AppState, mut snapshot: Option<Snapshot>) {
+    for cell in state.trees.cells() {
+        if cell.read().state != TreeState::UNLOADED {
+            continue;
+        }
+        let placeholder = cell
+            .update(|tree| {
+                tree.state = TreeState::LOADING_METADATA;
+                return tree.clone();
+            })
+            .await;
+        let walked = walk_tree(
+            &placeholder,
+            state.app_config.load_options(&placeholder.name),
+            &mut snapshot,
+        )
+        .await;
+        let loaded = walked.is_some();
+        let unloaded_meanwhile = !state.trees.get(&placeholder.name).is_some_and(
+            |current| {
+                Arc::ptr_eq(
+                    &current, &cell,
+                )
+            },
+        );
+        if unloaded_meanwhile {
+            continue;
+        }
+        cell.update(
+            |tree| match walked {
+                Some(walked) => *tree = walked,
+                None => tree.state = TreeState::UNLOADED,
+            },
+        )
+        .await;
+        if loaded {
+            let _ = state.events.send(
+                TreeEvent::TreeLoaded {
+                    tree: placeholder.name.clone(),
+                },
+            );
+        }
+    }
+}
+
+/// Walks the tree `placeholder` stands in for, using its snapshot instead
+/// if it's still up to date.
+async fn walk_tree(
+    placeholder: &Tree,
+    options: &LoadOptions,
+    snapshot: &mut Option<Snapshot>,
+) -> Option<Tree> {
+    let name = placeholder.name.clone();
+    let path = match &placeholder.get_root().data {
+        NodeData::DirectoryData { path, .. } => path.clone(),
+        _ => return None,
+    };
+    let options = options.clone();
+    // the walk never yields, so keep it off the runtime's worker threads
+    let handle = tokio::runtime::Handle::current();
+    let fresh = tokio::task::spawn_blocking(move || {
+        handle.block_on(
+            Tree::load(
+                name, path, &options,
+            ),
+        )
+    })
     .await
-    .unwrap();
+    .ok()
+    .flatten()?;
+    if let Some(snapshot) = snapshot {
+        if let Some(restored) = snapshot.restore(
+            &placeholder.name,
+            &fresh,
+        ) {
+            return Some(restored);
+        }
+    }
+    return Some(fresh);
+}
+
+/// The Unix socket to listen on instead of TCP, if any. A TCP address
+/// given as a flag wins over a socket in the config.
+#[cfg(unix)]
+fn socket_path(args: &Args, config: &Config) -> Option<PathBuf> {
+    let tcp_flags = args.interface.is_some() || args.port.is_some();
+    return match tcp_flags {
+        true => args.socket.clone(),
+        false => args.socket.clone().or(config.server.socket.clone()),
+    };
+}
+
+/// There are no Unix sockets to listen on, so one in the config is an
+/// error unless a TCP address is given as a flag instead.
+#[cfg(not(unix))]
+fn socket_path(args: &Args, config: &Config) -> Option<PathBuf> {
+    let tcp_flags = args.interface.is_some() || args.port.is_some();
+    if config.server.socket.is_some() && !tcp_flags {
+        eprintln!("Unix sockets aren't supported on this platform. Remove `socket` from the [server] table of the config, or pass --interface or --port.");
+        process::exit(1);
+    }
+    return None;
+}
+
+/// Parses a file mode in octal, e.g. `660`.
+#[cfg(unix)]
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    return u32::from_str_radix(
+        mode.trim_start_matches("0o"),
+        8,
+    )
+    .map_err(|e| e.to_string());
+}
+
+/// Waits for Ctrl-C or SIGTERM, then tells responses that are still
+/// streaming to finish so the server can stop.
+async fn shutdown_signal(stopping: watch::Sender<bool>) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to listen for SIGTERM: {}",
+                    e
+                );
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    eprintln!("Shutting down.");
+    let _ = stopping.send(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::node::FileState;
+    use crate::common::parser::BuiltinParser;
+    use crate::testing::{request, send, serve, GatedParser};
+    use serde_json::{json, Value};
+
+    const NOTES: [(&str, &str); 2] = [
+        (
+            "a.md",
+            "# Alpha\n",
+        ),
+        (
+            "b.md", "# Beta\n",
+        ),
+    ];
+
+    /// Starts hydrating `notes`, returning the job's id.
+    async fn start_job(state: &AppState) -> String {
+        let (status, job) = request(
+            state,
+            "POST",
+            "/tree/notes/hydrate",
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::ACCEPTED
+        );
+        assert_eq!(
+            job["state"],
+            "RUNNING"
+        );
+        return job["id"].as_str().unwrap().into();
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_job_leaves_the_rest_dehydrated() {
+        let parser = GatedParser::closed();
+        let (_root, state) = serve(
+            &NOTES,
+            parser.clone(),
+        )
+        .await;
+        let id = start_job(&state).await;
+
+        let (status, job) = request(
+            &state,
+            "DELETE",
+            &format!(
+                "/jobs/{}",
+                id
+            ),
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            job["state"],
+            "CANCELLED"
+        );
+        assert_eq!(
+            job["done"],
+            0
+        );
+        let tree = state.trees.get("notes").unwrap().read();
+        assert!(tree.has_dehydrated(&HydrationScope::default()));
+        assert_eq!(
+            tree.state,
+            TreeState::LOADED_METADATA_ONLY
+        );
+
+        // the cancelled job can still be polled
+        let (status, job) = request(
+            &state,
+            "GET",
+            &format!(
+                "/jobs/{}",
+                id
+            ),
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            job["state"],
+            "CANCELLED"
+        );
+    }
+
+    #[tokio::test]
+    async fn jobs_which_are_over_cant_be_cancelled() {
+        let parser = GatedParser::closed();
+        let (_root, state) = serve(
+            &NOTES,
+            parser.clone(),
+        )
+        .await;
+        let id = start_job(&state).await;
+        parser.allow(NOTES.len());
+        let finished = state.jobs.get(&id).unwrap().wait().await;
+        assert_eq!(
+            finished.state,
+            JobState::FINISHED
+        );
+
+        let (status, error) = request(
+            &state,
+            "DELETE",
+            &format!(
+                "/jobs/{}",
+                id
+            ),
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            error["code"],
+            "job_finished"
+        );
+        let (status, error) = request(
+            &state,
+            "DELETE",
+            "/jobs/no-such-job",
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            error["code"],
+            "job_not_found"
+        );
+        // nothing is left to hydrate
+        assert_eq!(
+            request(
+                &state,
+                "POST",
+                "/tree/notes/hydrate",
+                None,
+            )
+            .await,
+            (
+                StatusCode::NO_CONTENT,
+                Value::String(String::new())
+            )
+        );
+    }
+
+    /// Whether the document at `path` in `notes` has been parsed.
+    fn is_hydrated(state: &AppState, path: &str) -> bool {
+        let tree = state.trees.get("notes").unwrap().read();
+        return match &tree.find_node(path).unwrap().data {
+            NodeData::DocumentData { state, .. } => *state == FileState::HYDRATED,
+            _ => panic!(
+                "{} isn't a document",
+                path
+            ),
+        };
+    }
+
+    #[tokio::test]
+    async fn get_tree_only_hydrates_the_documents_it_returns() {
+        let (_root, state) = serve(
+            &[
+                (
+                    "top.md", "# Top\n",
+                ),
+                (
+                    "notes/todo.md",
+                    "# Todo\n",
+                ),
+                (
+                    "notes/deeper/old.md",
+                    "# Old\n",
+                ),
+            ],
+            Arc::new(BuiltinParser),
+        )
+        .await;
+        let hydrated = |state: &AppState| {
+            return ["top.md", "notes/todo.md", "notes/deeper/old.md"]
+                .into_iter()
+                .filter(|path| {
+                    is_hydrated(
+                        state, path,
+                    )
+                })
+                .collect::<Vec<_>>();
+        };
+
+        // documents have no blocks to return
+        let (status, tree) = request(
+            &state,
+            "GET",
+            "/tree/notes",
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            tree["nodes"].as_object().unwrap().len(),
+            6
+        );
+        assert!(hydrated(&state).is_empty());
+
+        let (status, tree) = request(
+            &state,
+            "GET",
+            "/tree/notes?lod=block&root=notes&depth=2",
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::OK
+        );
+        // notes, todo.md and its header, and deeper with old.md, whose
+        // header would be too deep
+        assert_eq!(
+            tree["nodes"].as_object().unwrap().len(),
+            5
+        );
+        assert_eq!(
+            hydrated(&state),
+            ["notes/todo.md"]
+        );
+
+        // top.md is returned, but its header would be too deep
+        let (status, _) = request(
+            &state,
+            "GET",
+            "/tree/notes?lod=block&depth=1",
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            hydrated(&state),
+            ["notes/todo.md"]
+        );
+
+        let (status, _) = request(
+            &state,
+            "GET",
+            "/tree/notes?lod=block&depth=2",
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            hydrated(&state),
+            ["top.md", "notes/todo.md"]
+        );
+
+        let (status, error) = request(
+            &state,
+            "GET",
+            "/tree/notes?lod=block&root=missing.md",
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            error["code"],
+            "node_not_found"
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_have_the_documented_status_and_code() {
+        let (_root, state) = serve(
+            &NOTES,
+            Arc::new(BuiltinParser),
+        )
+        .await;
+        let cases = [
+            (
+                "GET",
+                "/no/such/route",
+                None,
+                StatusCode::NOT_FOUND,
+                "no_route",
+            ),
+            (
+                "GET",
+                "/tree/missing",
+                None,
+                StatusCode::NOT_FOUND,
+                "tree_not_found",
+            ),
+            (
+                "GET",
+                "/tree/notes/missing.md",
+                None,
+                StatusCode::NOT_FOUND,
+                "node_not_found",
+            ),
+            (
+                "GET",
+                "/jobs/missing",
+                None,
+                StatusCode::NOT_FOUND,
+                "job_not_found",
+            ),
+            (
+                "GET",
+                "/tree/notes?lod=everything",
+                None,
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+            ),
+            (
+                "POST",
+                "/tree/notes/query",
+                Some(json!({"operation": "unload_docs", "query": "[[["})),
+                StatusCode::BAD_REQUEST,
+                "bad_selector",
+            ),
+            (
+                "POST",
+                "/query",
+                Some(json!({"operation": "save_snapshot"})),
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+            ),
+            (
+                "POST",
+                "/query",
+                Some(json!({"operation": "no_such_operation"})),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "bad_request",
+            ),
+            (
+                "PUT",
+                "/tree/notes",
+                None,
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method_not_allowed",
+            ),
+        ];
+        for (method, uri, body, status, code) in cases {
+            let (actual_status, error) = request(
+                &state, method, uri, body,
+            )
+            .await;
+            assert_eq!(
+                (
+                    actual_status,
+                    &error["code"]
+                ),
+                (
+                    status,
+                    &json!(code)
+                ),
+                "{} {}",
+                method,
+                uri
+            );
+            assert!(error["message"].is_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_which_arent_json_are_bad_requests() {
+        let (_root, state) = serve(
+            &NOTES,
+            Arc::new(BuiltinParser),
+        )
+        .await;
+        let post = |content_type: Option<&str>| {
+            let request = axum::http::Request::post("/query");
+            let request = match content_type {
+                Some(content_type) => request.header(
+                    "content-type",
+                    content_type,
+                ),
+                None => request,
+            };
+            return request.body(axum::body::Body::from("{not json")).unwrap();
+        };
+        let (status, _, error) = send(
+            &state,
+            post(Some(
+                "application/json",
+            )),
+        )
+        .await;
+        assert_eq!(
+            (
+                status,
+                &error["code"]
+            ),
+            (
+                StatusCode::BAD_REQUEST,
+                &json!("bad_request")
+            )
+        );
+        let (status, _, error) = send(
+            &state,
+            post(None),
+        )
+        .await;
+        assert_eq!(
+            (
+                status,
+                &error["code"]
+            ),
+            (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                &json!("bad_request")
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_methods_list_the_allowed_ones() {
+        let (_root, state) = serve(
+            &NOTES,
+            Arc::new(BuiltinParser),
+        )
+        .await;
+        let (status, headers, error) = send(
+            &state,
+            axum::http::Request::post("/jobs/some-job")
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            headers["allow"],
+            "GET,HEAD,DELETE"
+        );
+        assert_eq!(
+            error["code"],
+            "method_not_allowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn trees_still_loading_are_conflicts() {
+        let (_root, state) = serve(
+            &NOTES,
+            Arc::new(BuiltinParser),
+        )
+        .await;
+        let state = AppState {
+            trees: Arc::new(
+                Store::new(vec![
+                    Tree::unloaded(
+                        "notes".into(),
+                        "/notes".into(),
+                    ),
+                ]),
+            ),
+            ..state
+        };
+        let (status, error) = request(
+            &state,
+            "GET",
+            "/tree/notes",
+            None,
+        )
+        .await;
+        assert_eq!(
+            status,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            error,
+            json!({
+                "code": "tree_not_loaded",
+                "message": "notes isn't loaded yet",
+                "details": {"state": "UNLOADED"},
+            })
+        );
+    }
+
+    fn args(flags: &[&str]) -> Args {
+        return Args::try_parse_from(std::iter::once("zenkat").chain(flags.iter().copied()))
+            .unwrap();
+    }
+
+    fn config(raw: &str) -> Config {
+        return toml::from_str(raw).unwrap();
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        return values.iter().map(|value| value.to_string()).collect();
+    }
+
+    #[test]
+    fn parser_flags_take_precedence_over_the_config() {
+        let config = config("parser = \"config-parse\"\nparser_args = [\"--config\"]\n");
+        assert_eq!(
+            global_parser(
+                &args(&[]),
+                &config
+            ),
+            (
+                "config-parse".to_string(),
+                strings(&["--config"])
+            )
+        );
+        // the config's arguments were meant for the config's parser
+        assert_eq!(
+            global_parser(
+                &args(&["--parser", "flag-parse"]),
+                &config
+            ),
+            (
+                "flag-parse".to_string(),
+                vec![]
+            )
+        );
+        assert_eq!(
+            global_parser(
+                &args(&["--parser-arg", "--flag"]),
+                &config
+            ),
+            (
+                "config-parse".to_string(),
+                strings(&["--flag"])
+            )
+        );
+        assert_eq!(
+            global_parser(
+                &args(&[]),
+                &Config::default()
+            ),
+            (
+                default_parser(),
+                vec![]
+            )
+        );
+    }
+
+    #[test]
+    fn trees_use_their_own_parser_or_the_global_one() {
+        let config = config(
+            "[[tree]]\nname = \"wiki\"\npath = \"/wiki\"\nparser = \"gfm-parse\"\nparser_args = [\"--tables\"]\n\n[[tree]]\nname = \"bare\"\npath = \"/bare\"\nparser = \"gfm-parse\"\n\n[[tree]]\nname = \"notes\"\npath = \"/notes\"\n",
+        );
+        let global = strings(&["--global"]);
+        let parser = |name: &str| {
+            return tree_parser(
+                config.tree(name).unwrap(),
+                "md-parse",
+                &global,
+            );
+        };
+        assert_eq!(
+            parser("wiki"),
+            (
+                "gfm-parse".to_string(),
+                strings(&["--tables"])
+            )
+        );
+        // the global arguments were meant for the global parser
+        assert_eq!(
+            parser("bare"),
+            (
+                "gfm-parse".to_string(),
+                vec![]
+            )
+        );
+        assert_eq!(
+            parser("notes"),
+            (
+                "md-parse".to_string(),
+                global.clone()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn app_config_merges_flags_the_config_and_trees() {
+        let config = config(
+            "parser = \"builtin\"\nprocesses = 2\nparse_timeout = 5\nsnapshot = \"/config/snapshot\"\ndocument_extensions = [\"md\", \"txt\"]\nno_cache = true\n\n[ignore]\nexclude = [\"from-config\"]\n\n[[tree]]\nname = \"wiki\"\npath = \"/wiki\"\nexclude = [\"archive/\"]\n",
+        );
+        let merged = app_config(
+            &args(&[
+                "--processes",
+                "3",
+                "--snapshot",
+                "/flag/snapshot",
+                "--exclude",
+                "from-flag",
+                "--hidden",
+            ]),
+            &config,
+            &strings(&["wiki", "notes"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            merged.processes.get(),
+            3
+        );
+        assert_eq!(
+            merged.parse_timeout,
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            merged.snapshot,
+            Some(PathBuf::from("/flag/snapshot"))
+        );
+
+        // notes has no [[tree]], so it gets the global settings
+        assert!(!merged.trees.contains_key("notes"));
+        let notes = merged.load_options("notes");
+        assert_eq!(
+            notes.ignore.exclude,
+            ["from-config", "from-flag"]
+        );
+        assert!(notes.ignore.hidden);
+        assert_eq!(
+            notes.document_extensions,
+            ["md", "txt"]
+        );
+        assert!(merged.settings("notes").cache.is_none());
+
+        let wiki = merged.load_options("wiki");
+        assert_eq!(
+            wiki.ignore.exclude,
+            ["from-config", "from-flag", "archive/"]
+        );
+        assert_eq!(
+            merged.settings("wiki").doc_parser.name(),
+            merged.settings("notes").doc_parser.name()
+        );
+
+        // without flags, the config's settings apply
+        let merged = app_config(
+            &args(&[]),
+            &config,
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            merged.processes.get(),
+            2
+        );
+        assert_eq!(
+            merged.snapshot,
+            Some(PathBuf::from("/config/snapshot"))
+        );
+        assert!(!merged.load_options("notes").ignore.hidden);
+    }
 }
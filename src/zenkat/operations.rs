@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common::node::Node;
+
+/// Operations accepted by `POST /tree/:name/query`, see docs/api.md.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Operation {
+    UnloadDocs {
+        query: String,
+    },
+    /// Returns the nodes matching `query`, optionally sorted by an
+    /// attribute (`-` first for descending) and cut to `limit` nodes.
+    Select {
+        query: String,
+        sort: Option<String>,
+        limit: Option<usize>,
+    },
+}
+
+/// Operations on the whole store, accepted by `POST /query`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum StoreOperation {
+    /// Saves every tree to `path`, or the server's `--snapshot` file.
+    SaveSnapshot { path: Option<String> },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum OperationResult {
+    Unloaded {
+        unloaded: Vec<String>,
+    },
+    Selected {
+        nodes: Vec<Node>,
+    },
+    Saved {
+        path: String,
+        trees: usize,
+        bytes: usize,
+    },
+}
@@ -0,0 +1,326 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::common::metadata::FileMetadata;
+use crate::common::node::{FileState, NodeData};
+use crate::common::tree::{Tree, TreeState};
+
+/// Bumped whenever the snapshot format changes. Older snapshots are
+/// ignored rather than migrated.
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+/// Every tree in the store, written as MessagePack by `save`.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub trees: Vec<TreeSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TreeSnapshot {
+    pub tree: Tree,
+    /// The size and mtime of each loaded document when it was parsed, by
+    /// node id.
+    pub stamps: HashMap<String, FileStamp>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub modified: Option<u64>,
+    pub size: u64,
+}
+
+impl FileStamp {
+    fn of(metadata: &FileMetadata) -> FileStamp {
+        return FileStamp {
+            modified: metadata.modified,
+            size: metadata.size,
+        };
+    }
+}
+
+/// Tells apart the temporary files of saves running at the same time.
+static SAVES: AtomicUsize = AtomicUsize::new(0);
+
+impl Snapshot {
+    /// Trees which haven't been walked yet are left out, so a server
+    /// stopped while starting up doesn't save empty trees.
+    /// Documents are stamped with the metadata they had when parsed, so a
+    /// file edited since then is parsed again after a restore.
+    pub fn of<'a>(trees: impl IntoIterator<Item = &'a Tree>) -> Snapshot {
+        let trees = trees
+            .into_iter()
+            .filter(|tree| {
+                tree.state != TreeState::UNLOADED && tree.state != TreeState::LOADING_METADATA
+            })
+            .map(|tree| {
+                let mut stamps = HashMap::new();
+                for node in tree.nodes.values() {
+                    if let NodeData::DocumentData {
+                        metadata,
+                        state: FileState::HYDRATED,
+                        ..
+                    } = &node.data
+                    {
+                        stamps.insert(
+                            node.id.clone(),
+                            FileStamp::of(metadata),
+                        );
+                    }
+                }
+                return TreeSnapshot {
+                    tree: tree.clone(),
+                    stamps,
+                };
+            })
+            .collect();
+        return Snapshot {
+            version: SNAPSHOT_VERSION,
+            trees,
+        };
+    }
+
+    /// Writes the snapshot to `path`, returning its size in bytes.
+    pub fn save(&self, path: &Path) -> Result<usize, String> {
+        let bytes = rmp_serde::to_vec_named(self).map_err(|e| e.to_string())?;
+        // write then rename, so a crash never leaves half a snapshot behind,
+        // to a file of our own so saves at the same time can't mix
+        let temp_path = path.with_extension(format!(
+            "{}.{}.tmp",
+            process::id(),
+            SAVES.fetch_add(
+                1,
+                Ordering::Relaxed
+            )
+        ));
+        fs::write(
+            &temp_path, &bytes,
+        )
+        .map_err(|e| e.to_string())?;
+        if let Err(e) = fs::rename(
+            &temp_path, path,
+        ) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e.to_string());
+        }
+        return Ok(bytes.len());
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot, String> {
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        let snapshot: Snapshot = rmp_serde::from_slice(&bytes).map_err(|e| e.to_string())?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Snapshot version {} isn't supported, expected {}.",
+                snapshot.version, SNAPSHOT_VERSION
+            ));
+        }
+        return Ok(snapshot);
+    }
+
+    /// Takes the snapshot of the tree `name` if it was loaded from the same
+    /// path as `fresh`, a tree just loaded from the filesystem, and merges
+    /// it into `fresh`.
+    /// The walk decides which files exist and their metadata. Snapshot
+    /// documents which were removed are dropped, and those which changed
+    /// since the snapshot was saved are left to be parsed again when next
+    /// hydrated. Every other loaded document keeps its parsed contents.
+    pub fn restore(&mut self, name: &str, fresh: &Tree) -> Option<Tree> {
+        let index = self.trees.iter().position(
+            |snapshot| snapshot.tree.name == name && root_path(&snapshot.tree) == root_path(fresh),
+        )?;
+        let TreeSnapshot {
+            tree: saved,
+            stamps,
+        } = self.trees.swap_remove(index);
+
+        // ids are derived from paths, so the same files give the same ids
+        let mut tree = fresh.clone();
+        let mut stale = 0;
+        for (document_id, stamp) in stamps.iter() {
+            let unchanged = match tree.nodes.get(document_id).map(|node| &node.data) {
+                Some(NodeData::DocumentData { metadata, .. }) => &FileStamp::of(metadata) == stamp,
+                _ => false,
+            };
+            if !unchanged {
+                stale += 1;
+                continue;
+            }
+            if tree
+                .copy_document_from(
+                    &saved,
+                    document_id,
+                )
+                .is_err()
+            {
+                stale += 1;
+            }
+        }
+        tree.failures = saved
+            .failures
+            .into_iter()
+            .filter(|(document_id, _)| tree.nodes.contains_key(document_id))
+            .collect();
+        tree.refresh_state();
+        eprintln!(
+            "Restored {} from snapshot ({} documents changed or removed).",
+            name, stale
+        );
+        return Some(tree);
+    }
+}
+
+fn root_path(tree: &Tree) -> Option<&str> {
+    return match &tree.nodes.get(&tree.root_node)?.data {
+        NodeData::DirectoryData { path, .. } => Some(path),
+        NodeData::DocumentData { path, .. } => Some(path),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::parser::BuiltinParser;
+    use crate::common::tree::{HydrateOptions, LoadOptions};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    async fn load(root: &TempDir) -> Tree {
+        return Tree::load(
+            "notes".into(),
+            root.path().to_str().unwrap().into(),
+            &LoadOptions::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn hydrated(root: &TempDir) -> Tree {
+        let mut tree = load(root).await;
+        tree.load_all_unloaded_docs(
+            &HydrateOptions {
+                parser: Arc::new(BuiltinParser),
+                processes: 1,
+                timeout: Duration::from_secs(30),
+                cache: None,
+            },
+            |_, _, _| {},
+        )
+        .await;
+        return tree;
+    }
+
+    fn document<'a>(tree: &'a Tree, name: &str) -> &'a NodeData {
+        let id = tree.nodes[&tree.root_node].children.iter().find(|id| {
+            matches!(
+                &tree.nodes[*id].data,
+                NodeData::DocumentData { path, .. } if path.ends_with(name)
+            )
+        });
+        return &tree.nodes[id.unwrap()].data;
+    }
+
+    fn state(tree: &Tree, name: &str) -> FileState {
+        return match document(tree, name) {
+            NodeData::DocumentData { state, .. } => *state,
+            _ => unreachable!(),
+        };
+    }
+
+    fn notes() -> TempDir {
+        let root = TempDir::new().unwrap();
+        fs::write(
+            root.path().join("a.md"),
+            "# Alpha\n\nText.\n",
+        )
+        .unwrap();
+        fs::write(
+            root.path().join("b.md"),
+            "# Beta\n",
+        )
+        .unwrap();
+        return root;
+    }
+
+    #[tokio::test]
+    async fn saved_snapshots_restore_parsed_documents() {
+        let root = notes();
+        let tree = hydrated(&root).await;
+        let saves = TempDir::new().unwrap();
+        let path = saves.path().join("zenkat.snapshot");
+        Snapshot::of([&tree]).save(&path).unwrap();
+        // nothing is left behind next to the snapshot
+        assert_eq!(
+            fs::read_dir(saves.path()).unwrap().count(),
+            1
+        );
+
+        let mut snapshot = Snapshot::load(&path).unwrap();
+        let restored = snapshot
+            .restore(
+                "notes",
+                &load(&root).await,
+            )
+            .unwrap();
+        assert_eq!(
+            restored.nodes,
+            tree.nodes
+        );
+        assert_eq!(
+            restored.state,
+            tree.state
+        );
+    }
+
+    #[tokio::test]
+    async fn documents_changed_since_parsing_are_left_to_parse_again() {
+        let root = notes();
+        let tree = hydrated(&root).await;
+        // edited after parsing but before saving, which a stamp taken at
+        // save time would miss
+        fs::write(
+            root.path().join("a.md"),
+            "# Alpha\n\nMore text.\n",
+        )
+        .unwrap();
+        let mut snapshot = Snapshot::of([&tree]);
+
+        let restored = snapshot
+            .restore(
+                "notes",
+                &load(&root).await,
+            )
+            .unwrap();
+        assert_eq!(
+            state(&restored, "a.md"),
+            FileState::DEHYDRATED
+        );
+        assert_eq!(
+            state(&restored, "b.md"),
+            FileState::HYDRATED
+        );
+        assert_eq!(
+            restored.state,
+            TreeState::LOADED_PARTIAL
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshots_of_another_path_arent_restored() {
+        let root = notes();
+        let mut snapshot = Snapshot::of([&hydrated(&root).await]);
+        let other = notes();
+        assert!(snapshot
+            .restore(
+                "notes",
+                &load(&other).await,
+            )
+            .is_none());
+    }
+}
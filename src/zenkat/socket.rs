@@ -0,0 +1,104 @@
+use axum::Router;
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::fs::{self, DirBuilder, Permissions};
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Who may connect when `--socket-mode` isn't given: only the user
+/// running the server.
+pub const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+/// Binds a Unix socket at `path` with the permissions `mode`. Connecting
+/// needs write permission on the socket, so `mode` decides who can use the
+/// server. A socket left behind by a server which didn't shut down cleanly
+/// is replaced, but one that's still being served is an error.
+pub async fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(
+                io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "it exists and isn't a socket",
+                ),
+            );
+        }
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(
+                io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another server is listening on it",
+                ),
+            );
+        }
+        fs::remove_file(path)?;
+    }
+    // bound in a directory only we can enter and moved into place once
+    // its permissions are set, so nobody can connect in between
+    let private_dir = path.with_file_name(format!(
+        ".zenkat-{}",
+        process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let private_path = private_dir.join("socket");
+    let bound = UnixListener::bind(&private_path).and_then(
+        |listener| {
+            fs::set_permissions(
+                &private_path,
+                Permissions::from_mode(mode),
+            )?;
+            fs::rename(
+                &private_path,
+                path,
+            )?;
+            return Ok(listener);
+        },
+    );
+    let _ = fs::remove_file(&private_path);
+    fs::remove_dir(&private_dir)?;
+    return bound;
+}
+
+/// Serves `app` on `listener` until `shutdown` completes, then waits for
+/// open connections to finish, as `axum::serve` does over TCP.
+pub async fn serve(listener: UnixListener, app: Router, shutdown: impl Future<Output = ()>) {
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // e.g. out of file descriptors, which takes a while to clear
+                eprintln!(
+                    "Failed to accept a connection: {}",
+                    e
+                );
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let connection = http1::Builder::new().serve_connection(
+            TokioIo::new(stream),
+            TowerToHyperService::new(app.clone()),
+        );
+        let connection = graceful.watch(connection);
+        tokio::spawn(
+            async move {
+                // errors are clients going away, which isn't our problem
+                let _ = connection.await;
+            },
+        );
+    }
+    graceful.shutdown().await;
+}
@@ -0,0 +1,199 @@
+use arc_swap::ArcSwap;
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+
+use crate::Tree;
+
+/// One tree in the store, kept as an immutable snapshot so readers never
+/// wait. Writers work on a copy and swap it in when they're done, as
+/// described in docs/state-problem.md.
+pub struct TreeCell {
+    current: ArcSwap<Tree>,
+    /// Held while a copy is being changed, so writes to one tree can't
+    /// overwrite each other.
+    writer: Mutex<()>,
+}
+
+impl TreeCell {
+    pub fn new(tree: Tree) -> TreeCell {
+        return TreeCell {
+            current: ArcSwap::from_pointee(tree),
+            writer: Mutex::new(()),
+        };
+    }
+
+    /// The latest version of the tree, which stays the same however long
+    /// it's held for.
+    pub fn read(&self) -> Arc<Tree> {
+        return self.current.load_full();
+    }
+
+    /// Changes a copy of the tree and makes it the latest version. Other
+    /// writers wait, but readers carry on with the previous version.
+    /// Every call copies the whole tree, so a writer making many changes,
+    /// like a hydration job, should batch them into one call.
+    pub async fn update<R>(&self, change: impl FnOnce(&mut Tree) -> R) -> R {
+        let _writing = self.writer.lock().await;
+        let mut tree = Tree::clone(&self.current.load());
+        let result = change(&mut tree);
+        self.current.store(Arc::new(
+            tree,
+        ));
+        return result;
+    }
+}
+
+/// Every tree the server knows about.
+#[derive(Default)]
+pub struct Store {
+    trees: RwLock<Vec<Arc<TreeCell>>>,
+}
+
+impl Store {
+    pub fn new(trees: Vec<Tree>) -> Store {
+        return Store {
+            trees: RwLock::new(
+                trees
+                    .into_iter()
+                    .map(|tree| Arc::new(TreeCell::new(tree)))
+                    .collect(),
+            ),
+        };
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<TreeCell>> {
+        return self
+            .trees
+            .read()
+            .unwrap()
+            .iter()
+            .find(|cell| cell.read().name == name)
+            .cloned();
+    }
+
+    /// Takes the tree `name` out of the store. Anyone still holding its
+    /// cell keeps the last version.
+    pub fn remove(&self, name: &str) -> Option<Arc<TreeCell>> {
+        let mut trees = self.trees.write().unwrap();
+        let index = trees.iter().position(|cell| cell.read().name == name)?;
+        return Some(trees.remove(index));
+    }
+
+    pub fn cells(&self) -> Vec<Arc<TreeCell>> {
+        return self.trees.read().unwrap().clone();
+    }
+
+    /// The latest version of every tree.
+    pub fn read_all(&self) -> Vec<Arc<Tree>> {
+        return self
+            .trees
+            .read()
+            .unwrap()
+            .iter()
+            .map(|cell| cell.read())
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::node::{Node, NodeType};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn readers_keep_the_version_they_read() {
+        let cell = TreeCell::new(
+            Tree::unloaded(
+                "notes".into(),
+                "/notes".into(),
+            ),
+        );
+        let before = cell.read();
+        tokio::runtime::Runtime::new().unwrap().block_on(
+            cell.update(|tree| {
+                let node = Node::new(NodeType::PARAGRAPH);
+                tree.nodes.insert(
+                    node.id.clone(),
+                    node,
+                );
+                // the change isn't visible until it's done
+                assert_eq!(
+                    cell.read().nodes.len(),
+                    1
+                );
+            }),
+        );
+        assert_eq!(
+            before.nodes.len(),
+            1
+        );
+        assert_eq!(
+            cell.read().nodes.len(),
+            2
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn readers_never_see_half_a_batch() {
+        let cell = Arc::new(
+            TreeCell::new(
+                Tree::unloaded(
+                    "notes".into(),
+                    "/notes".into(),
+                ),
+            ),
+        );
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let cell = cell.clone();
+                return tokio::spawn(
+                    async move {
+                        for _ in 0..50 {
+                            // a batch of two nodes, after the root
+                            cell.update(|tree| {
+                                for _ in 0..2 {
+                                    let node = Node::new(NodeType::PARAGRAPH);
+                                    tree.nodes.insert(
+                                        node.id.clone(),
+                                        node,
+                                    );
+                                }
+                            })
+                            .await;
+                        }
+                    },
+                );
+            })
+            .collect();
+        let writing = Arc::new(AtomicBool::new(true));
+        let reader = {
+            let cell = cell.clone();
+            let writing = writing.clone();
+            tokio::spawn(
+                async move {
+                    while writing.load(Ordering::Relaxed) {
+                        assert_eq!(
+                            cell.read().nodes.len() % 2,
+                            1
+                        );
+                        tokio::task::yield_now().await;
+                    }
+                },
+            )
+        };
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        writing.store(
+            false,
+            Ordering::Relaxed,
+        );
+        reader.await.unwrap();
+        // and writers never overwrite each other
+        assert_eq!(
+            cell.read().nodes.len(),
+            1 + 4 * 50 * 2
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use axum::body::Body;
+use axum::http::{HeaderMap, Request, StatusCode};
+use http_body_util::BodyExt;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::sync::{broadcast, watch, Semaphore};
+use tower::ServiceExt;
+
+use crate::app_state::{AppConfig, AppState, TreeSettings};
+use crate::common::parser::{BuiltinParser, DocumentParser, ParseFuture, ParserDescription};
+use crate::common::tree::{LoadOptions, Tree};
+use crate::jobs::Jobs;
+use crate::store::Store;
+
+/// Parses documents as `BuiltinParser` does, but only as many as it's
+/// been told to, so tests can catch a job while it's running.
+pub struct GatedParser {
+    gate: Arc<Semaphore>,
+}
+
+impl GatedParser {
+    /// A parser which parses nothing until `allow` is called.
+    pub fn closed() -> Arc<GatedParser> {
+        return Arc::new(
+            GatedParser {
+                gate: Arc::new(Semaphore::new(0)),
+            },
+        );
+    }
+
+    /// Lets `documents` more documents be parsed.
+    pub fn allow(&self, documents: usize) {
+        self.gate.add_permits(documents);
+    }
+}
+
+impl DocumentParser for GatedParser {
+    fn parse(&self, path: String) -> ParseFuture {
+        let gate = self.gate.clone();
+        return Box::pin(
+            async move {
+                gate.acquire().await.unwrap().forget();
+                return BuiltinParser.parse(path).await;
+            },
+        );
+    }
+
+    fn name(&self) -> String {
+        return "gated".into();
+    }
+
+    fn describe(&self) -> ParserDescription {
+        return ParserDescription::builtin();
+    }
+}
+
+/// Writes `files`, given by path relative to the root and contents, to a
+/// new directory, and serves it as the tree `notes` parsed by `parser`.
+/// The tree is walked but nothing in it is parsed yet.
+pub async fn serve(
+    files: &[(&str, &str)],
+    parser: Arc<dyn DocumentParser>,
+) -> (
+    TempDir,
+    AppState,
+) {
+    let root = TempDir::new().unwrap();
+    for (path, contents) in files {
+        let path = root.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            path, contents,
+        )
+        .unwrap();
+    }
+    let app_config = AppConfig {
+        trees: HashMap::new(),
+        default_settings: TreeSettings {
+            load_options: LoadOptions::default(),
+            doc_parser: parser,
+            cache: None,
+        },
+        processes: NonZeroUsize::new(4).unwrap(),
+        parse_timeout: Duration::from_secs(30),
+        snapshot: None,
+    };
+    let tree = Tree::load(
+        "notes".into(),
+        root.path().to_str().unwrap().into(),
+        app_config.load_options("notes"),
+    )
+    .await
+    .unwrap();
+    let (events, _) = broadcast::channel(1024);
+    let (_, shutdown) = watch::channel(false);
+    let state = AppState {
+        app_config,
+        trees: Arc::new(Store::new(vec![tree])),
+        events,
+        jobs: Jobs::default(),
+        shutdown,
+    };
+    return (
+        root, state,
+    );
+}
+
+/// Sends a request to the API, with `body` as JSON, returning the status
+/// and the body, as JSON if it is any.
+pub async fn request(
+    state: &AppState,
+    method: &str,
+    uri: &str,
+    body: Option<Value>,
+) -> (
+    StatusCode,
+    Value,
+) {
+    let request = Request::builder().method(method).uri(uri);
+    let request = match body {
+        Some(body) => request
+            .header(
+                "content-type",
+                "application/json",
+            )
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    };
+    let (status, _, body) = send(
+        state,
+        request.unwrap(),
+    )
+    .await;
+    return (
+        status, body,
+    );
+}
+
+/// Sends `request` to the API, returning the status, headers and the body,
+/// as JSON if it is any.
+pub async fn send(
+    state: &AppState,
+    request: Request<Body>,
+) -> (
+    StatusCode,
+    HeaderMap,
+    Value,
+) {
+    let response = crate::router(state.clone()).oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    let text = String::from_utf8(bytes.to_vec()).unwrap();
+    let body = serde_json::from_str(&text).unwrap_or(Value::String(text));
+    return (
+        status, headers, body,
+    );
+}
@@ -0,0 +1,90 @@
+use hyper::body::Bytes;
+use hyper::StatusCode;
+#[cfg(unix)]
+use {
+    http_body_util::{BodyExt, Empty},
+    hyper::client::conn::http1,
+    hyper::{header, Request},
+    hyper_util::rt::TokioIo,
+    std::path::PathBuf,
+    tokio::net::UnixStream,
+};
+
+/// Where the server is listening.
+pub enum Server {
+    /// The base URI, e.g. `http://localhost:9001`.
+    Tcp(String),
+    /// The path of the socket given to the server's `--socket`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Server {
+    /// Sends a GET request for `path`, e.g. `/tree/notes?lod=full`, and
+    /// returns the response's status and body.
+    pub async fn get(
+        &self,
+        path: &str,
+    ) -> Result<
+        (
+            StatusCode,
+            Bytes,
+        ),
+        String,
+    > {
+        match self {
+            Server::Tcp(base_uri) => {
+                let response = reqwest::get(format!(
+                    "{}{}",
+                    base_uri, path
+                ))
+                .await
+                .map_err(|e| e.to_string())?;
+                let status = response.status();
+                let body = response.bytes().await.map_err(|e| e.to_string())?;
+                return Ok((
+                    status, body,
+                ));
+            }
+            #[cfg(unix)]
+            Server::Unix(socket) => {
+                let stream = UnixStream::connect(socket).await.map_err(|e| {
+                    format!(
+                        "Failed to connect to {}: {}",
+                        socket.display(),
+                        e
+                    )
+                })?;
+                let (mut sender, connection) = http1::handshake(TokioIo::new(stream))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                // drives the connection while the request is in flight
+                tokio::spawn(connection);
+                let request = Request::get(path)
+                    .header(
+                        header::HOST,
+                        "localhost",
+                    )
+                    .body(Empty::<
+                        Bytes,
+                    >::new(
+                    ))
+                    .map_err(|e| e.to_string())?;
+                let response = sender
+                    .send_request(request)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let status = response.status();
+                let body = response
+                    .into_body()
+                    .collect()
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .to_bytes();
+                return Ok((
+                    status, body,
+                ));
+            }
+        }
+    }
+}
@@ -1,29 +1,52 @@
-use std::path::Path;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use axum::handler::Handler;
 use clap::{Parser, Subcommand};
-use reqwest;
-use tokio;
 
+// compiled into every binary, each of which only uses part of it
+#[allow(dead_code)]
 #[path = "../common.rs"]
 mod common;
-use common::tree::{self, Tree};
+use common::config::Config;
+use common::node::{Node, NodeData, NodeType};
+use common::parser::{default_parser, parser_from_arg, BUILTIN_PARSER};
+use common::traverse::{Visitor, Walk};
+use common::tree::{HydrateOptions, LoadOptions, Tree};
 
-use crate::common::node::NodeData;
+mod client;
+use client::Server;
 
+/// Flags take precedence over the config file, which is shared with the
+/// server so both agree on where it's listening and which parser to use.
 #[derive(Parser, Debug)]
 struct Args {
     #[clap(subcommand)]
     cmd: Command,
 
+    /// Read settings from this file [default: ~/.config/zenkat/config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     #[arg(long, default_value = "http")]
     protocol: String,
 
-    #[arg(long, default_value = "9001")]
-    port: String,
+    /// [default: the server's port in the config, or 9001]
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// [default: the server's interface in the config, or localhost]
+    #[arg(long)]
+    host: Option<String>,
 
-    #[arg(long, default_value = "localhost")]
-    host: String,
+    /// Connect to the server's Unix socket at this path instead of over TCP
+    #[cfg(unix)]
+    #[arg(long)]
+    socket: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -33,118 +56,186 @@ enum Command {
     },
     Html {
         path: String,
-        #[arg(long, default_value = "target/debug/md-parse")]
-        parser: String,
+        /// `builtin` to parse in-process, or the path of a parser executable
+        /// [default: the parser in the config, the md-parse next to zk-cmd, or builtin]
+        #[arg(long)]
+        parser: Option<String>,
+    },
+    /// Times hydrating many copies of a directory with each parser
+    Bench {
+        #[arg(default_value = "sample")]
+        path: String,
+        #[arg(long, default_value = "1000")]
+        copies: usize,
+        /// [default: builtin and the parser in the config or next to zk-cmd]
+        #[arg(long)]
+        parser: Vec<String>,
+        #[arg(long, default_value = "0")]
+        processes: usize,
     },
 }
 
-fn visualise_tree(tree: &Tree, cur_node_id: String, cur_depth: usize) {
-    let cur_node = tree.nodes.get(&cur_node_id).unwrap();
-    let indent = "  ".repeat(cur_depth);
+fn visualise_tree(tree: &Tree, start_id: &str) {
+    for (depth, node) in tree.pre_order(start_id) {
+        let indent = "  ".repeat(depth);
 
-    let mut content: String = String::new();
+        let mut content: String = String::new();
 
-    match cur_node.data.clone() {
-        NodeData::DirectoryData { path } => {
-            content = path.clone();
-        }
-        NodeData::DocumentData { path, loaded: _ } => {
-            content = path.clone();
-        }
-        NodeData::HeaderData { text, level } => {
-            content = format!(
-                "<h{}> {}",
-                level, text
-            )
+        match node.data.clone() {
+            NodeData::DirectoryData { path, .. } => {
+                content = path.clone();
+            }
+            NodeData::DocumentData { path, .. } => {
+                content = path.clone();
+            }
+            NodeData::AttachmentData { path, size, mime } => {
+                content = format!(
+                    "{} ({}, {} bytes)",
+                    path, mime, size
+                );
+            }
+            NodeData::HeaderData { text, level } => {
+                content = format!(
+                    "<h{}> {}",
+                    level, text
+                )
+            }
+            NodeData::ParagraphData { text: _ } => content = "<p>".into(),
+            _ => {}
         }
-        NodeData::ParagraphData { text: _ } => content = "<p>".into(),
-        _ => {}
-    }
 
-    println!(
-        "{}{}",
-        indent, content
-    );
-
-    for child in cur_node.children.iter() {
-        visualise_tree(
-            tree,
-            child.clone(),
-            cur_depth + 1,
+        println!(
+            "{}{}",
+            indent, content
         );
     }
 }
 
-fn render_tree_html(tree: &Tree, cur_node_id: String, cur_depth: usize) {
-    let cur_node = tree.nodes.get(&cur_node_id).unwrap();
-    let indent = "  ".repeat(cur_depth);
+/// Prints a document as a standalone HTML page.
+struct HtmlRenderer;
+
+impl<'a> Visitor<'a> for HtmlRenderer {
+    fn enter(&mut self, _tree: &'a Tree, node: &'a Node, depth: usize) -> Walk {
+        let indent = "  ".repeat(depth);
 
-    match cur_node.data.clone() {
-        NodeData::DocumentData { path: _, loaded: _ } => {
-            println!(
-                "<html><head>
+        match node.data.clone() {
+            NodeData::DocumentData { .. } => {
+                println!(
+                    "<html><head>
                 <link rel=\"stylesheet\" href=\"https://unpkg.com/mvp.css\">
                 </head><body><main>"
-            );
-            for child in cur_node.children.iter() {
-                render_tree_html(
-                    tree,
-                    child.clone(),
-                    cur_depth + 1,
+                );
+                return Walk::Continue;
+            }
+            NodeData::HeaderData { text, level } => {
+                println!(
+                    "{}<h{}>{}</h{}>",
+                    indent, level, text, level
+                );
+            }
+            NodeData::ParagraphData { text } => {
+                println!(
+                    "{}<p>{}</p>",
+                    indent, text
                 )
             }
-            println!("</main></body></html>");
-        }
-        NodeData::HeaderData { text, level } => {
-            println!(
-                "{}<h{}>{}</h{}>",
-                indent, level, text, level
-            );
-        }
-        NodeData::ParagraphData { text } => {
-            println!(
-                "{}<p>{}</p>",
-                indent, text
-            )
+            NodeData::ThematicBreakData {} => {
+                println!("<hr>")
+            }
+            _ => {}
         }
-        NodeData::ThematicBreakData {} => {
-            println!("<hr>")
+        // only documents have children we know how to render
+        return Walk::SkipChildren;
+    }
+
+    fn leave(&mut self, _tree: &'a Tree, node: &'a Node, _depth: usize) {
+        if node.node_type == NodeType::DOCUMENT {
+            println!("</main></body></html>");
         }
-        _ => {}
     }
 }
 
+/// The server's Unix socket to connect to instead of TCP, if any. A host
+/// or port given as a flag wins over a socket in the config.
+#[cfg(unix)]
+fn socket_path(args: &Args, config: &Config) -> Option<PathBuf> {
+    let tcp_flags = args.host.is_some() || args.port.is_some();
+    return match tcp_flags {
+        true => args.socket.clone(),
+        false => args.socket.clone().or(config.server.socket.clone()),
+    };
+}
+
+/// There are no Unix sockets to connect to, so one in the config is an
+/// error unless a host or port is given as a flag instead.
+#[cfg(not(unix))]
+fn socket_path(args: &Args, config: &Config) -> Option<PathBuf> {
+    let tcp_flags = args.host.is_some() || args.port.is_some();
+    if config.server.socket.is_some() && !tcp_flags {
+        eprintln!("Unix sockets aren't supported on this platform. Remove `socket` from the [server] table of the config, or pass --host or --port.");
+        process::exit(1);
+    }
+    return None;
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let client = reqwest::Client::new();
-
-    let server_uri = vec![
-        args.protocol,
-        "://".into(),
-        args.host,
-        ":".into(),
-        args.port,
-    ]
-    .join("");
+    let config = match Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let host = match (
+        &args.host,
+        &config.server.interface,
+    ) {
+        (Some(host), _) => host.clone(),
+        // a server listening everywhere is also listening locally
+        (None, Some(interface)) if interface != "0.0.0.0" && interface != "::" => interface.clone(),
+        _ => "localhost".into(),
+    };
+    let server = match socket_path(
+        &args, &config,
+    ) {
+        #[cfg(unix)]
+        Some(socket) => Server::Unix(socket),
+        _ => Server::Tcp(format!(
+            "{}://{}:{}",
+            args.protocol,
+            host,
+            args.port.or(config.server.port).unwrap_or(9001)
+        )),
+    };
 
     match &args.cmd {
         Command::Tree { name } => {
-            let uri = vec![server_uri, "tree".into(), name.into()].join("/");
-            let uri_params = vec![uri.clone(), "lod=full".into()].join("?");
-
-            let tree: Tree = client
-                .get(uri_params)
-                .send()
-                .await
-                .unwrap()
-                .json()
-                .await
-                .unwrap();
+            let path = format!(
+                "/tree/{}?lod=full",
+                name
+            );
+            let (status, body) = match server.get(&path).await {
+                Ok(response) => response,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            if !status.is_success() {
+                // the server explains itself in an ApiError body
+                let error: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+                eprintln!(
+                    "{}",
+                    error["message"].as_str().unwrap_or("Request failed")
+                );
+                process::exit(1);
+            }
+            let tree: Tree = serde_json::from_slice(&body).unwrap();
             visualise_tree(
                 &tree,
-                tree.root_node.clone(),
-                0,
+                &tree.root_node,
             );
         }
         Command::Html {
@@ -153,17 +244,175 @@ async fn main() {
         } => {
             let path = Path::new(path_str);
             if path.is_file() && path.extension().unwrap() == "md" {
-                let tree = Tree::load_document(
-                    path_str.clone(),
-                    parser.clone(),
+                let (parser, parser_args) = configured_parser(
+                    parser, &config,
+                );
+                let parser = match parser_from_arg(
+                    &parser,
+                    &parser_args,
+                    1,
                 )
-                .await;
-                render_tree_html(
-                    &tree,
-                    tree.root_node.clone(),
-                    0,
+                .await
+                {
+                    Ok(parser) => parser,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    }
+                };
+                let tree = match parser.parse(path_str.clone()).await {
+                    Ok(tree) => tree,
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to load {}: {}",
+                            path_str, e
+                        );
+                        process::exit(1);
+                    }
+                };
+                tree.walk(
+                    &tree.root_node,
+                    &mut HtmlRenderer,
                 );
             }
         }
+        Command::Bench {
+            path,
+            copies,
+            parser,
+            processes,
+        } => {
+            let parsers = match parser.is_empty() {
+                true => vec![
+                    BUILTIN_PARSER.into(),
+                    configured_parser(
+                        &None, &config,
+                    )
+                    .0,
+                ],
+                false => parser.clone(),
+            };
+            bench(
+                path, *copies, &parsers, *processes,
+            )
+            .await;
+        }
     };
 }
+
+/// The parser to use and its arguments: `parser` if one was given, else
+/// the config's global parser, else the default one.
+fn configured_parser(
+    parser: &Option<String>,
+    config: &Config,
+) -> (
+    String,
+    Vec<String>,
+) {
+    return match (
+        parser,
+        &config.parser,
+    ) {
+        (Some(parser), _) => (
+            parser.clone(),
+            vec![],
+        ),
+        (None, Some(parser)) => (
+            parser.clone(),
+            config.parser_args.clone(),
+        ),
+        (None, None) => (
+            default_parser(),
+            config.parser_args.clone(),
+        ),
+    };
+}
+
+/// Copies the markdown files under `from` into `to`, keeping the layout.
+fn copy_documents(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry_path = entry?.path();
+        let target = to.join(entry_path.file_name().unwrap());
+        if entry_path.is_dir() {
+            copy_documents(
+                &entry_path,
+                &target,
+            )?;
+        } else if entry_path.extension().is_some_and(|ext| ext == "md") {
+            fs::copy(
+                &entry_path,
+                &target,
+            )?;
+        }
+    }
+    return Ok(());
+}
+
+async fn bench(path: &str, copies: usize, parsers: &[String], processes: usize) {
+    let bench_dir = env::temp_dir().join(format!(
+        "zenkat-bench-{}",
+        process::id()
+    ));
+    for copy in 0..copies {
+        copy_documents(
+            Path::new(path),
+            &bench_dir.join(copy.to_string()),
+        )
+        .unwrap();
+    }
+    let processes = match processes {
+        0 => thread::available_parallelism().map_or(1, |n| {
+            n.get()
+        }),
+        n => n,
+    };
+
+    for parser in parsers {
+        let options = HydrateOptions {
+            parser: match parser_from_arg(
+                parser,
+                &[],
+                processes,
+            )
+            .await
+            {
+                Ok(parser) => parser,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            },
+            processes,
+            timeout: Duration::from_secs(30),
+            // the point is to time the parsers
+            cache: None,
+        };
+        let tree = Tree::load(
+            "bench".into(),
+            bench_dir.to_string_lossy().into(),
+            &LoadOptions::default(),
+        )
+        .await;
+        let mut tree = match tree {
+            Some(tree) => tree,
+            None => continue,
+        };
+        let mut documents = 0;
+        let before = Instant::now();
+        tree.load_all_unloaded_docs(
+            &options,
+            |_, done, _| documents = done,
+        )
+        .await;
+        println!(
+            "{}: {} documents in {:.2?} with {} processes",
+            parser,
+            documents,
+            before.elapsed(),
+            processes
+        );
+    }
+
+    fs::remove_dir_all(bench_dir).unwrap();
+}